@@ -6,11 +6,32 @@ use flate2::Compression;
 use sha2::{Sha256, Digest};
 use walkdir::WalkDir;
 
+// The server's calendar math, for build/site.rs.
+#[path = "src/date.rs"]
+#[allow(dead_code)]
+mod date;
+#[path = "build/site.rs"]
+mod site;
+use site::{
+    add_subresource_integrity, alias_target, collect_git_times, collect_integrity, collect_languages,
+    inline_attribute_hashes, inline_block_hashes, inline_critical_css, lastmod, page_csp, parse_redirects,
+    preload_links, theme_variant, THEME_HERO_CLASSES,
+};
+
 const PUBLIC_DIR: &str = "../public";
 const OUTPUT: &str = "src/assets.rs";
+// Netlify-style redirect rules; Hugo copies it over from static/.
+const REDIRECTS_FILE: &str = "_redirects";
+// Page CSP before inline hashes are added. Override with CSP_BASE at build
+// time; hashes are appended to its script-src and style-src directives.
+const DEFAULT_CSP_BASE: &str = "default-src 'self'; script-src 'self'; style-src 'self'; \
+    img-src 'self' data:; font-src 'self'; connect-src 'self'; frame-ancestors 'none'; \
+    base-uri 'self'; form-action 'self'";
 
 fn main() {
     println!("cargo:rerun-if-changed={}", PUBLIC_DIR);
+    println!("cargo:rerun-if-env-changed=CSP_BASE");
+    let csp_base = std::env::var("CSP_BASE").unwrap_or_else(|_| DEFAULT_CSP_BASE.to_string());
 
     // Check if public directory exists
     if !Path::new(PUBLIC_DIR).exists() {
@@ -27,24 +48,18 @@ fn main() {
     writeln!(output, "#![allow(dead_code)]").unwrap();
     writeln!(output, "use std::collections::HashMap;\n").unwrap();
 
-    // Write Asset struct (for HTTP/HTTPS)
-    writeln!(output, "#[derive(Clone)]").unwrap();
-    writeln!(output, "pub struct Asset {{").unwrap();
-    writeln!(output, "    pub content_raw: &'static [u8],").unwrap();
-    writeln!(output, "    pub content_gzip: &'static [u8],").unwrap();
-    writeln!(output, "    pub content_brotli: &'static [u8],").unwrap();
-    writeln!(output, "    pub content_type: &'static str,").unwrap();
-    writeln!(output, "    pub etag: &'static str,").unwrap();
-    writeln!(output, "    pub is_compressible: bool,").unwrap();
-    writeln!(output, "}}\n").unwrap();
-
-    // Write GeminiAsset struct (simpler - no compression needed)
-    writeln!(output, "#[derive(Clone)]").unwrap();
-    writeln!(output, "pub struct GeminiAsset {{").unwrap();
-    writeln!(output, "    pub content: &'static [u8],").unwrap();
-    writeln!(output, "    pub content_type: &'static str,").unwrap();
-    writeln!(output, "    pub etag: &'static str,").unwrap();
-    writeln!(output, "}}\n").unwrap();
+    write_asset_types(&mut output);
+
+    let integrity = collect_integrity(Path::new(PUBLIC_DIR));
+    let languages = collect_languages(Path::new(PUBLIC_DIR));
+    let git_times = collect_git_times(Path::new(PUBLIC_DIR));
+    let redirects_path = Path::new(PUBLIC_DIR).join(REDIRECTS_FILE);
+    println!("cargo:rerun-if-changed={}", redirects_path.display());
+    let mut redirects = match fs::read_to_string(&redirects_path) {
+        Ok(source) => parse_redirects(&source)
+            .unwrap_or_else(|e| panic!("{}: {}", redirects_path.display(), e)),
+        Err(_) => Vec::new(),
+    };
 
     let mut http_routes = Vec::new();
     let mut gemini_routes = Vec::new();
@@ -67,6 +82,11 @@ fn main() {
         let relative_path = path.strip_prefix(PUBLIC_DIR)
             .expect("Failed to strip prefix");
 
+        // Compiled into the redirect table below, not served.
+        if relative_path == Path::new(REDIRECTS_FILE) {
+            continue;
+        }
+
         // Check if this is a Gemini file
         let is_gemini = path.extension().map(|e| e == "gmi").unwrap_or(false);
 
@@ -79,6 +99,35 @@ fn main() {
             }
         };
 
+        let is_html = path.extension().is_some_and(|e| e == "html" || e == "htm");
+
+        // Hugo aliases are meta-refresh stubs; turn them into real 301s
+        // (and Gemini 31s) for both the file and its directory route.
+        if is_html {
+            if let Some(target) = alias_target(&String::from_utf8_lossy(&content)) {
+                let file_route = format!("/{}", relative_path.to_string_lossy()).replace('\\', "/");
+                let dir_route = file_route.strip_suffix("/index.html").filter(|r| !r.is_empty());
+                for from in [Some(file_route.as_str()), dir_route].into_iter().flatten() {
+                    redirects.push((from.to_string(), target.clone(), 301));
+                }
+                continue;
+            }
+        }
+
+        // Pin every embedded script and stylesheet a page references, then
+        // inline its critical CSS. This runs before hashing so the ETag and
+        // compressed variants match the bytes actually served.
+        let content = if is_html {
+            let page_route = format!("/{}", relative_path.to_string_lossy()).replace('\\', "/");
+            let html = String::from_utf8_lossy(&content);
+            match add_subresource_integrity(&html, &page_route, &integrity) {
+                Ok(html) => inline_critical_css(&html, &page_route, Path::new(PUBLIC_DIR)).into_bytes(),
+                Err(e) => panic!("{}: {}", path.display(), e),
+            }
+        } else {
+            content
+        };
+
         // Generate ETag (SHA256 hash of content)
         let mut hasher = Sha256::new();
         hasher.update(&content);
@@ -125,39 +174,45 @@ fn main() {
                 .as_ref()
                 .to_string();
 
-            // Determine if content should be compressed
-            let is_compressible = is_compressible_type(&content_type);
-
-            // Compress if beneficial
-            let (gzip_content, brotli_content) = if is_compressible {
-                (compress_gzip(&content), compress_brotli(&content))
-            } else {
-                (content.clone(), content.clone())
-            };
-
             let ident = sanitize_ident(&route);
 
-            // Write raw content
-            writeln!(output, "const CONTENT_RAW_{}: &[u8] = &{:?};", ident, content).unwrap();
+            // Content-Language and the hreflang siblings of this page.
+            let (content_language, alternates) = match languages.get(&route) {
+                Some(page) => (format!("Some({:?})", page.lang), format!("&{:?}", page.alternates)),
+                None => ("None".to_string(), "&[]".to_string()),
+            };
+            // Hugo's lastmod for pages, else the last commit touching the
+            // static/ source, else the file's own mtime.
+            let last_modified = is_html
+                .then(|| lastmod(&String::from_utf8_lossy(&content)))
+                .flatten()
+                .or_else(|| git_times.get(&route).copied())
+                .or_else(|| file_mtime(path));
+            let mut meta = AssetMeta {
+                content_type: &content_type,
+                csp_base: &csp_base,
+                content_language,
+                alternates,
+                theme_variants: "&[]".to_string(),
+                last_modified: format!("{:?}", last_modified),
+            };
 
-            // Only write different compressed versions if actually compressible
-            if is_compressible {
-                writeln!(output, "const CONTENT_GZIP_{}: &[u8] = &{:?};", ident, gzip_content).unwrap();
-                writeln!(output, "const CONTENT_BROTLI_{}: &[u8] = &{:?};", ident, brotli_content).unwrap();
-            } else {
-                writeln!(output, "const CONTENT_GZIP_{}: &[u8] = CONTENT_RAW_{};", ident, ident).unwrap();
-                writeln!(output, "const CONTENT_BROTLI_{}: &[u8] = CONTENT_RAW_{};", ident, ident).unwrap();
+            // Pages with a per-theme hero also get one body per theme that
+            // only loads that theme's image; the router picks by cookie.
+            if is_html {
+                let html = String::from_utf8_lossy(&content);
+                let mut themes = Vec::new();
+                for (theme, _) in THEME_HERO_CLASSES {
+                    if let Some(variant) = theme_variant(&html, theme) {
+                        let variant_ident = format!("{}_THEME_{}", ident, theme.to_ascii_uppercase());
+                        write_http_asset(&mut output, &variant_ident, variant.as_bytes(), &meta);
+                        themes.push(format!("({:?}, &ASSET_{})", theme, variant_ident));
+                    }
+                }
+                meta.theme_variants = format!("&[{}]", themes.join(", "));
             }
 
-            // Write Asset const
-            writeln!(output, "const ASSET_{}: Asset = Asset {{", ident).unwrap();
-            writeln!(output, "    content_raw: CONTENT_RAW_{},", ident).unwrap();
-            writeln!(output, "    content_gzip: CONTENT_GZIP_{},", ident).unwrap();
-            writeln!(output, "    content_brotli: CONTENT_BROTLI_{},", ident).unwrap();
-            writeln!(output, "    content_type: \"{}\",", content_type).unwrap();
-            writeln!(output, "    etag: \"{}\",", etag).unwrap();
-            writeln!(output, "    is_compressible: {},", is_compressible).unwrap();
-            writeln!(output, "}};\n").unwrap();
+            write_http_asset(&mut output, &ident, &content, &meta);
 
             http_routes.push((route.clone(), ident.clone()));
 
@@ -192,10 +247,128 @@ fn main() {
         writeln!(output, "    m.insert(\"{}\", &ASSET_{});", route, ident).unwrap();
     }
     writeln!(output, "    m").unwrap();
+    writeln!(output, "}}\n").unwrap();
+
+    // Generate get_redirects function, rules in file order
+    writeln!(output, "pub fn get_redirects() -> Vec<Redirect> {{").unwrap();
+    writeln!(output, "    vec![").unwrap();
+    for (from, to, status) in &redirects {
+        writeln!(output, "        Redirect {{ from: {:?}, to: {:?}, status: {} }},", from, to, status).unwrap();
+    }
+    writeln!(output, "    ]").unwrap();
     writeln!(output, "}}").unwrap();
 
     println!("cargo:warning=Generated {} HTTP routes", http_routes.len());
     println!("cargo:warning=Generated {} Gemini routes", gemini_routes.len());
+    println!("cargo:warning=Generated {} redirects", redirects.len());
+}
+
+/// Everything that goes into an `Asset` besides its bytes. String fields
+/// are Rust expressions.
+struct AssetMeta<'a> {
+    content_type: &'a str,
+    csp_base: &'a str,
+    content_language: String,
+    alternates: String,
+    theme_variants: String,
+    last_modified: String,
+}
+
+/// Write `CONTENT_*_{ident}` and `ASSET_{ident}` for one HTTP body.
+fn write_http_asset(output: &mut File, ident: &str, content: &[u8], meta: &AssetMeta) {
+    let etag = format!("{:x}", Sha256::digest(content));
+    let is_compressible = is_compressible_type(meta.content_type);
+
+    // Per-page CSP that allows exactly this page's inline blocks,
+    // so script-src never needs 'unsafe-inline'.
+    let csp = if meta.content_type.starts_with("text/html") {
+        let html = String::from_utf8_lossy(content);
+        let mut script_hashes = inline_block_hashes(&html, "script");
+        let mut style_hashes = inline_block_hashes(&html, "style");
+        // Event handlers and style attributes need 'unsafe-hashes'
+        // on top of their hash; blocks alone don't.
+        let (handler_hashes, style_attr_hashes) = inline_attribute_hashes(&html);
+        for (hashes, extra) in [
+            (&mut script_hashes, handler_hashes),
+            (&mut style_hashes, style_attr_hashes),
+        ] {
+            if !extra.is_empty() {
+                hashes.push("'unsafe-hashes'".to_string());
+            }
+            for hash in extra {
+                if !hashes.contains(&hash) {
+                    hashes.push(hash);
+                }
+            }
+        }
+        format!("Some({:?})", page_csp(meta.csp_base, &script_hashes, &style_hashes))
+    } else {
+        "None".to_string()
+    };
+
+    // Link: rel=preload for the LCP image, stylesheets and fonts, sent as
+    // 103 Early Hints and again on the response.
+    let preload = if meta.content_type.starts_with("text/html") {
+        let links = preload_links(&String::from_utf8_lossy(content));
+        if links.is_empty() {
+            "None".to_string()
+        } else {
+            format!("Some({:?})", links.join(", "))
+        }
+    } else {
+        "None".to_string()
+    };
+
+    // Write raw content
+    writeln!(output, "const CONTENT_RAW_{}: &[u8] = &{:?};", ident, content).unwrap();
+
+    // Precompressed variants. A variant that isn't smaller than the
+    // raw bytes is pure overhead, so it's left out and never offered.
+    // Each gets its own strong ETag, the hash of its own bytes.
+    let mut variants = Vec::new();
+    if is_compressible {
+        for (name, compressed) in [
+            ("GZIP", compress_gzip(content)),
+            ("BROTLI", compress_brotli(content)),
+            ("ZSTD", compress_zstd(content)),
+        ] {
+            if compressed.len() < content.len() {
+                writeln!(output, "const CONTENT_{}_{}: &[u8] = &{:?};", name, ident, compressed).unwrap();
+                variants.push((name, format!("{:x}", Sha256::digest(&compressed))));
+            }
+        }
+    }
+    let variant = |name: &str| {
+        if variants.iter().any(|(n, _)| *n == name) {
+            format!("Some(CONTENT_{}_{})", name, ident)
+        } else {
+            "None".to_string()
+        }
+    };
+    let variant_etag = |name: &str| match variants.iter().find(|(n, _)| *n == name) {
+        Some((_, etag)) => format!("Some({:?})", etag),
+        None => "None".to_string(),
+    };
+
+    // Write Asset const
+    writeln!(output, "const ASSET_{}: Asset = Asset {{", ident).unwrap();
+    writeln!(output, "    content_raw: CONTENT_RAW_{},", ident).unwrap();
+    writeln!(output, "    content_gzip: {},", variant("GZIP")).unwrap();
+    writeln!(output, "    content_brotli: {},", variant("BROTLI")).unwrap();
+    writeln!(output, "    content_zstd: {},", variant("ZSTD")).unwrap();
+    writeln!(output, "    content_type: \"{}\",", meta.content_type).unwrap();
+    writeln!(output, "    etag: \"{}\",", etag).unwrap();
+    writeln!(output, "    etag_gzip: {},", variant_etag("GZIP")).unwrap();
+    writeln!(output, "    etag_brotli: {},", variant_etag("BROTLI")).unwrap();
+    writeln!(output, "    etag_zstd: {},", variant_etag("ZSTD")).unwrap();
+    writeln!(output, "    is_compressible: {},", is_compressible).unwrap();
+    writeln!(output, "    csp: {},", csp).unwrap();
+    writeln!(output, "    content_language: {},", meta.content_language).unwrap();
+    writeln!(output, "    alternates: {},", meta.alternates).unwrap();
+    writeln!(output, "    theme_variants: {},", meta.theme_variants).unwrap();
+    writeln!(output, "    preload: {},", preload).unwrap();
+    writeln!(output, "    last_modified: {},", meta.last_modified).unwrap();
+    writeln!(output, "}};\n").unwrap();
 }
 
 fn is_compressible_type(mime: &str) -> bool {
@@ -218,40 +391,89 @@ fn compress_brotli(data: &[u8]) -> Vec<u8> {
         quality: 11,
         ..Default::default()
     };
-    let mut input = &data[..];
+    let mut input = data;
     brotli::BrotliCompress(&mut input, &mut output, &params)
         .expect("Failed to compress with brotli");
     output
 }
 
+fn compress_zstd(data: &[u8]) -> Vec<u8> {
+    zstd::encode_all(data, 19).expect("Failed to compress with zstd")
+}
+
+fn file_mtime(path: &Path) -> Option<u64> {
+    let modified = fs::metadata(path).and_then(|m| m.modified()).ok()?;
+    Some(modified.duration_since(std::time::UNIX_EPOCH).ok()?.as_secs())
+}
+
 fn sanitize_ident(path: &str) -> String {
     path.chars()
         .map(|c| if c.is_alphanumeric() { c.to_ascii_uppercase() } else { '_' })
         .collect()
 }
 
-fn create_empty_assets() {
-    let mut output = File::create(OUTPUT).expect("Failed to create assets.rs");
-    writeln!(output, "// Empty assets - run 'hugo --minify' first").unwrap();
-    writeln!(output, "use std::collections::HashMap;\n").unwrap();
+/// Struct definitions shared by the real and the empty `assets.rs`.
+fn write_asset_types(output: &mut File) {
+    // Write Asset struct (for HTTP/HTTPS). Compressed variants are `None`
+    // when they wouldn't beat the raw bytes.
     writeln!(output, "#[derive(Clone)]").unwrap();
     writeln!(output, "pub struct Asset {{").unwrap();
     writeln!(output, "    pub content_raw: &'static [u8],").unwrap();
-    writeln!(output, "    pub content_gzip: &'static [u8],").unwrap();
-    writeln!(output, "    pub content_brotli: &'static [u8],").unwrap();
+    writeln!(output, "    pub content_gzip: Option<&'static [u8]>,").unwrap();
+    writeln!(output, "    pub content_brotli: Option<&'static [u8]>,").unwrap();
+    writeln!(output, "    pub content_zstd: Option<&'static [u8]>,").unwrap();
     writeln!(output, "    pub content_type: &'static str,").unwrap();
     writeln!(output, "    pub etag: &'static str,").unwrap();
+    writeln!(output, "    /// ETags of the compressed bodies, set with the matching `content_*`").unwrap();
+    writeln!(output, "    pub etag_gzip: Option<&'static str>,").unwrap();
+    writeln!(output, "    pub etag_brotli: Option<&'static str>,").unwrap();
+    writeln!(output, "    pub etag_zstd: Option<&'static str>,").unwrap();
     writeln!(output, "    pub is_compressible: bool,").unwrap();
+    writeln!(output, "    /// Hash-based Content-Security-Policy for HTML pages").unwrap();
+    writeln!(output, "    pub csp: Option<&'static str>,").unwrap();
+    writeln!(output, "    /// `<html lang>` of HTML pages").unwrap();
+    writeln!(output, "    pub content_language: Option<&'static str>,").unwrap();
+    writeln!(output, "    /// (hreflang, path) of every language version of this page,").unwrap();
+    writeln!(output, "    /// default language first; empty if it exists in one language").unwrap();
+    writeln!(output, "    pub alternates: &'static [(&'static str, &'static str)],").unwrap();
+    writeln!(output, "    /// Per-theme bodies of this page, by `theme` cookie value").unwrap();
+    writeln!(output, "    pub theme_variants: &'static [(&'static str, &'static Asset)],").unwrap();
+    writeln!(output, "    /// `Link` preload header for HTML pages (also sent as 103 Early Hints)").unwrap();
+    writeln!(output, "    pub preload: Option<&'static str>,").unwrap();
+    writeln!(output, "    /// Modification time, Unix seconds (Hugo lastmod, git or mtime)").unwrap();
+    writeln!(output, "    pub last_modified: Option<u64>,").unwrap();
     writeln!(output, "}}\n").unwrap();
+
+    // Write GeminiAsset struct (simpler - no compression needed)
     writeln!(output, "#[derive(Clone)]").unwrap();
     writeln!(output, "pub struct GeminiAsset {{").unwrap();
     writeln!(output, "    pub content: &'static [u8],").unwrap();
+    writeln!(output, "    pub content_type: &'static str,").unwrap();
     writeln!(output, "    pub etag: &'static str,").unwrap();
     writeln!(output, "}}\n").unwrap();
+
+    // Redirect rule from _redirects. `to` is empty for 410 Gone.
+    writeln!(output, "#[derive(Clone, Copy)]").unwrap();
+    writeln!(output, "pub struct Redirect {{").unwrap();
+    writeln!(output, "    pub from: &'static str,").unwrap();
+    writeln!(output, "    pub to: &'static str,").unwrap();
+    writeln!(output, "    pub status: u16,").unwrap();
+    writeln!(output, "}}\n").unwrap();
+}
+
+fn create_empty_assets() {
+    let mut output = File::create(OUTPUT).expect("Failed to create assets.rs");
+    writeln!(output, "// Empty assets - run 'hugo --minify' first").unwrap();
+    writeln!(output, "#![allow(dead_code)]").unwrap();
+    writeln!(output, "use std::collections::HashMap;\n").unwrap();
+    write_asset_types(&mut output);
     writeln!(output, "pub fn get_routes() -> HashMap<&'static str, &'static Asset> {{").unwrap();
     writeln!(output, "    HashMap::new()").unwrap();
     writeln!(output, "}}").unwrap();
     writeln!(output, "pub fn get_gemini_routes() -> HashMap<&'static str, &'static GeminiAsset> {{").unwrap();
     writeln!(output, "    HashMap::new()").unwrap();
     writeln!(output, "}}").unwrap();
+    writeln!(output, "pub fn get_redirects() -> Vec<Redirect> {{").unwrap();
+    writeln!(output, "    Vec::new()").unwrap();
+    writeln!(output, "}}").unwrap();
 }
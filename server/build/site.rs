@@ -0,0 +1,1506 @@
+//! Page and stylesheet processing for build.rs: redirects, aliases,
+//! languages, Subresource Integrity, critical CSS, CSP hashes and preloads.
+//!
+//! Kept apart from build.rs, which only walks public/ and writes assets.rs,
+//! so the server crate can include it in its unit tests.
+
+use base64::Engine as _;
+use sha2::{Digest, Sha256, Sha384};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use walkdir::WalkDir;
+
+use crate::date;
+
+// Statuses a `_redirects` rule may use.
+const REDIRECT_STATUSES: [u16; 5] = [301, 302, 307, 308, 410];
+
+// Theme name (the `theme` cookie value) and the class of the hero image
+// shown only in that theme, as in the theme's index.html.
+pub const THEME_HERO_CLASSES: [(&str, &str); 2] = [("light", "hero-bg-light"), ("dark", "hero-bg-dark")];
+
+// How much of a page's <body> markup counts as above the fold when picking
+// critical CSS: the navigation and the start of <main> on this theme.
+const CRITICAL_FOLD_BYTES: usize = 6 * 1024;
+
+/// Parse `_redirects`: one `FROM TO [STATUS]` or `FROM 410` rule per line,
+/// `#` comments. FROM may use `:name` segments and a trailing `*`, which TO
+/// can refer to as `:name` and `:splat`. Status defaults to 301. The error
+/// names the first malformed line.
+pub fn parse_redirects(source: &str) -> Result<Vec<(String, String, u16)>, String> {
+    let mut rules = Vec::new();
+    for (i, line) in source.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        let fail = |msg: &str| Err(format!("line {}: {}", i + 1, msg));
+        let fields: Vec<&str> = line.split_whitespace().collect();
+
+        let (from, to, status) = match fields.as_slice() {
+            [from, "410"] => (*from, "", "410"),
+            [from, to] => (*from, *to, "301"),
+            [from, to, status] => (*from, *to, *status),
+            _ => return fail("expected `FROM TO [STATUS]`"),
+        };
+        let Ok(status) = status.parse::<u16>() else {
+            return fail("status is not a number");
+        };
+        if !REDIRECT_STATUSES.contains(&status) {
+            return fail("status must be one of 301, 302, 307, 308, 410");
+        }
+        if !from.starts_with('/') {
+            return fail("FROM must be a path starting with /");
+        }
+        if from.find('*').is_some_and(|i| i != from.len() - 1) {
+            return fail("`*` is only allowed at the end of FROM");
+        }
+        if status != 410 && to.is_empty() {
+            return fail("missing TO");
+        }
+
+        // Every placeholder in TO must be captured by FROM.
+        let captures: Vec<&str> = from
+            .split('/')
+            .filter_map(|s| s.strip_prefix(':'))
+            .chain(from.ends_with('*').then_some("splat"))
+            .collect();
+        let placeholders = to.split(':').skip(1).map(|s| {
+            let end = s.find(|c: char| !c.is_ascii_alphanumeric() && c != '_').unwrap_or(s.len());
+            &s[..end]
+        });
+        // `https://` yields an empty name, which isn't a placeholder.
+        for placeholder in placeholders.filter(|p| !p.is_empty()) {
+            if !captures.contains(&placeholder) {
+                return fail(&format!("TO uses :{} but FROM doesn't capture it", placeholder));
+            }
+        }
+
+        rules.push((from.to_string(), to.to_string(), status));
+    }
+    Ok(rules)
+}
+
+/// `Link` header entries preloading what a page needs first: its existing
+/// `<link rel=preload>`s (fonts, in this theme), its render-blocking
+/// stylesheets, and the LCP candidate image, i.e. the first `<img>` with
+/// `fetchpriority=high`, else the first one that isn't lazy-loaded. A
+/// preload carries the element's `integrity` and `crossorigin`; without
+/// them the browser wouldn't reuse the preloaded response for it.
+pub fn preload_links(html: &str) -> Vec<String> {
+    let mut links: Vec<String> = Vec::new();
+    let mut preloaded: Vec<String> = Vec::new();
+    let mut lcp_image: Option<Vec<(String, String)>> = None;
+    let mut first_eager_image: Option<Vec<(String, String)>> = None;
+
+    let mut push = |href: &str, params: &[(&str, Option<&str>)]| {
+        if href.is_empty() || href.starts_with("data:") || preloaded.iter().any(|p| p == href) {
+            return;
+        }
+        let mut link = format!("<{}>; rel=preload", href);
+        for (name, value) in params {
+            match value {
+                Some(v) if v.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-') => {
+                    link.push_str(&format!("; {}={}", name, v))
+                }
+                Some(v) => link.push_str(&format!("; {}=\"{}\"", name, v)),
+                None => link.push_str(&format!("; {}", name)),
+            }
+        }
+        // Header values must be visible ASCII.
+        if link.bytes().all(|b| (0x20..0x7f).contains(&b)) {
+            preloaded.push(href.to_string());
+            links.push(link);
+        }
+    };
+
+    let lower = html.to_ascii_lowercase();
+    let mut pos = 0;
+    while let Some(start) = lower[pos..].find('<').map(|i| pos + i) {
+        let Some(end) = lower[start..].find('>').map(|i| start + i) else {
+            break;
+        };
+        pos = end + 1;
+        let tag = &html[start + 1..end];
+        let name_len = tag.find(|c: char| c.is_ascii_whitespace()).unwrap_or(tag.len());
+        let attrs = parse_attributes(&tag[name_len..]);
+        let attr = |key: &str| attrs.iter().find(|(k, _)| k == key).map(|(_, v)| v.as_str());
+        let has_rel = |rel: &str| {
+            attr("rel").is_some_and(|r| r.split_ascii_whitespace().any(|r| r.eq_ignore_ascii_case(rel)))
+        };
+        let crossorigin = attr("crossorigin").map(crossorigin_param);
+
+        match tag[..name_len].to_ascii_lowercase().as_str() {
+            "link" if has_rel("preload") => {
+                let params: Vec<(&str, Option<&str>)> =
+                    ["as", "type", "imagesrcset", "imagesizes", "fetchpriority", "integrity"]
+                        .into_iter()
+                        .filter_map(|name| attr(name).map(|v| (name, Some(v))))
+                        .chain(crossorigin)
+                        .collect();
+                push(attr("href").unwrap_or(""), &params);
+            }
+            "link" if has_rel("stylesheet") && attr("media").is_none_or(|m| m != "print") => {
+                let params: Vec<(&str, Option<&str>)> = [("as", Some("style"))]
+                    .into_iter()
+                    .chain(attr("integrity").map(|v| ("integrity", Some(v))))
+                    .chain(crossorigin)
+                    .collect();
+                push(attr("href").unwrap_or(""), &params);
+            }
+            "img" if attr("src").is_some() => {
+                if lcp_image.is_none() && attr("fetchpriority") == Some("high") {
+                    lcp_image = Some(attrs.clone());
+                }
+                if first_eager_image.is_none() && attr("loading") != Some("lazy") {
+                    first_eager_image = Some(attrs.clone());
+                }
+            }
+            _ => {}
+        }
+    }
+
+    if let Some(attrs) = lcp_image.or(first_eager_image) {
+        let attr = |key: &str| attrs.iter().find(|(k, _)| k == key).map(|(_, v)| v.as_str());
+        let mut params = vec![("as", Some("image")), ("fetchpriority", Some("high"))];
+        if let Some(srcset) = attr("srcset") {
+            params.push(("imagesrcset", Some(srcset)));
+        }
+        if let Some(sizes) = attr("sizes") {
+            params.push(("imagesizes", Some(sizes)));
+        }
+        params.extend(attr("crossorigin").map(crossorigin_param));
+        push(attr("src").unwrap_or(""), &params);
+    }
+    links
+}
+
+/// A preload's `crossorigin` parameter for an element's attribute value:
+/// bare for anonymous, spelled out otherwise.
+fn crossorigin_param(value: &str) -> (&'static str, Option<&str>) {
+    match value {
+        "" | "anonymous" => ("crossorigin", None),
+        value => ("crossorigin", Some(value)),
+    }
+}
+
+/// `html` rendered for one theme: that theme's hero loads eagerly at high
+/// priority, the other heroes keep their URLs in `data-src`/`data-srcset`
+/// (restored by light_dark.js if the visitor toggles) so the browser never
+/// fetches them, and `<html>` gets the matching `data-theme`. `None` if the
+/// page has no hero for every theme.
+pub fn theme_variant(html: &str, theme: &str) -> Option<String> {
+    let has_all = THEME_HERO_CLASSES.iter().all(|(_, class)| html.contains(class));
+    if !has_all {
+        return None;
+    }
+
+    let lower = html.to_ascii_lowercase();
+    let mut output = String::with_capacity(html.len());
+    let mut copied = 0;
+    let mut pos = 0;
+    while let Some(start) = lower[pos..].find('<').map(|i| pos + i) {
+        let end = start + lower[start..].find('>')?;
+        pos = end + 1;
+        let tag = &html[start + 1..end];
+        let name_len = tag.find(|c: char| c.is_ascii_whitespace()).unwrap_or(tag.len());
+        let name = tag[..name_len].to_ascii_lowercase();
+        let mut attrs = parse_attributes(&tag[name_len..]);
+
+        match name.as_str() {
+            "html" => {
+                attrs.retain(|(k, _)| k != "data-theme");
+                attrs.push(("data-theme".to_string(), theme.to_string()));
+            }
+            "img" => {
+                let class = attrs.iter().find(|(k, _)| k == "class").map(|(_, v)| v.clone());
+                let Some((hero_theme, _)) = THEME_HERO_CLASSES.iter().find(|(_, hero)| {
+                    class.as_deref().is_some_and(|c| c.split_ascii_whitespace().any(|c| c == *hero))
+                }) else {
+                    continue;
+                };
+                attrs.retain(|(k, _)| k != "loading" && k != "fetchpriority");
+                if *hero_theme == theme {
+                    attrs.push(("fetchpriority".to_string(), "high".to_string()));
+                } else {
+                    for (key, _) in attrs.iter_mut() {
+                        if key == "src" || key == "srcset" {
+                            key.insert_str(0, "data-");
+                        }
+                    }
+                }
+            }
+            _ => continue,
+        }
+
+        output.push_str(&html[copied..start]);
+        output.push('<');
+        output.push_str(&tag[..name_len]);
+        for (key, value) in &attrs {
+            output.push_str(&format!(" {}=\"{}\"", key, value.replace('"', "&quot;")));
+        }
+        output.push('>');
+        copied = end + 1;
+    }
+    output.push_str(&html[copied..]);
+    Some(output)
+}
+
+/// Target path of a Hugo alias page: an immediate `<meta http-equiv=refresh>`
+/// with no `<body>`. Hugo writes the absolute permalink; only the path is
+/// kept so the redirect also works on localhost and over Gemini.
+pub fn alias_target(html: &str) -> Option<String> {
+    let lower = html.to_ascii_lowercase();
+    if lower.contains("<body") {
+        return None;
+    }
+
+    let mut pos = 0;
+    while let Some(start) = lower[pos..].find("<meta").map(|i| pos + i) {
+        let end = lower[start..].find('>').map(|i| start + i)?;
+        pos = end + 1;
+        let attrs = parse_attributes(&html[start + "<meta".len()..end]);
+        let attr = |key: &str| attrs.iter().find(|(k, _)| k == key).map(|(_, v)| v.as_str());
+        if !attr("http-equiv").is_some_and(|v| v.eq_ignore_ascii_case("refresh")) {
+            continue;
+        }
+
+        // content="0; url=https://example.org/posts/hello/"
+        let (delay, url) = attr("content")?.split_once(';')?;
+        if delay.trim() != "0" {
+            return None;
+        }
+        let url = url.trim();
+        let url = url
+            .get(..4)
+            .filter(|p| p.eq_ignore_ascii_case("url="))
+            .map_or(url, |_| &url[4..])
+            .trim_matches(|c| c == '\'' || c == '"');
+        let path = match url.split_once("://") {
+            Some((_, rest)) => rest.find('/').map_or("/", |i| &rest[i..]),
+            None => url,
+        };
+        return path.starts_with('/').then(|| path.to_string());
+    }
+    None
+}
+
+pub struct PageLanguage {
+    pub lang: String,
+    pub alternates: Vec<(String, String)>,
+}
+
+/// Language and translations of every HTML page, by route. A page's
+/// language is its `<html lang>`; pages under `/<primary subtag>/` (e.g.
+/// `/de/posts/` for `lang="de-de"`) are translations of the same path
+/// without that prefix, which is how Hugo lays out non-default languages.
+pub fn collect_languages(public: &Path) -> HashMap<String, PageLanguage> {
+    let mut pages = Vec::new();
+    for entry in WalkDir::new(public).follow_links(true).into_iter().flatten() {
+        let path = entry.path();
+        if !entry.file_type().is_file() || !path.extension().is_some_and(|e| e == "html" || e == "htm") {
+            continue;
+        }
+        let Ok(content) = fs::read(path) else {
+            continue;
+        };
+        let html = String::from_utf8_lossy(&content);
+        if alias_target(&html).is_some() {
+            continue;
+        }
+        let Some(lang) = html_lang(&html) else {
+            continue;
+        };
+        let relative = path.strip_prefix(public).expect("Failed to strip prefix");
+        let route = format!("/{}", relative.to_string_lossy()).replace('\\', "/");
+
+        let primary = lang.split('-').next().unwrap_or("").to_ascii_lowercase();
+        let prefix = format!("/{}/", primary);
+        let (base, is_default) = if route.starts_with(&prefix) {
+            (route[prefix.len() - 1..].to_string(), false)
+        } else {
+            (route.clone(), true)
+        };
+        pages.push((route, lang, base, is_default));
+    }
+
+    let mut groups: HashMap<&str, Vec<(&str, &str, bool)>> = HashMap::new();
+    for (route, lang, base, is_default) in &pages {
+        groups.entry(base).or_default().push((route, lang, *is_default));
+    }
+
+    let mut languages = HashMap::new();
+    for (route, lang, base, _) in &pages {
+        let mut siblings = groups[base.as_str()].clone();
+        let alternates = if siblings.len() > 1 {
+            siblings.sort_by_key(|(route, _, is_default)| (!is_default, *route));
+            siblings
+                .iter()
+                .map(|(route, lang, _)| {
+                    let href = route.strip_suffix("index.html").unwrap_or(route);
+                    (lang.to_string(), href.to_string())
+                })
+                .collect()
+        } else {
+            Vec::new()
+        };
+        languages.insert(route.clone(), PageLanguage { lang: lang.clone(), alternates });
+    }
+    languages
+}
+
+/// The `lang` attribute of the `<html>` element.
+fn html_lang(html: &str) -> Option<String> {
+    let lower = html.to_ascii_lowercase();
+    let start = lower.find("<html")? + "<html".len();
+    let end = start + lower[start..].find('>')?;
+    parse_attributes(&html[start..end])
+        .into_iter()
+        .find(|(name, _)| name == "lang")
+        .map(|(_, value)| value)
+        .filter(|value| !value.is_empty())
+}
+
+/// Commit time of the latest change to each file under the project's and
+/// themes' static/ directories, by the route Hugo copies it to. The project
+/// wins where both have a file. Empty if git isn't available.
+pub fn collect_git_times(public: &Path) -> HashMap<String, u64> {
+    let mut times = HashMap::new();
+    let mut dirs = vec![public.with_file_name("static")];
+    if let Ok(themes) = fs::read_dir(public.with_file_name("themes")) {
+        dirs.extend(themes.flatten().map(|theme| theme.path().join("static")));
+    }
+
+    for dir in dirs.iter().filter(|d| d.is_dir()) {
+        let Ok(log) = std::process::Command::new("git")
+            .arg("-C")
+            .arg(dir)
+            .args(["log", "--format=@%ct", "--name-only", "--relative", "--", "."])
+            .output()
+        else {
+            continue;
+        };
+        // Newest commit first: `@<timestamp>`, then the files it touched.
+        let mut commit_time = None;
+        for line in String::from_utf8_lossy(&log.stdout).lines().filter(|l| !l.is_empty()) {
+            match line.strip_prefix('@') {
+                Some(time) => commit_time = time.parse::<u64>().ok(),
+                None => {
+                    if let Some(time) = commit_time {
+                        times.entry(format!("/{}", line)).or_insert(time);
+                    }
+                }
+            }
+        }
+    }
+    times
+}
+
+/// A page's `<meta property="article:modified_time">`, which the theme
+/// fills from Hugo's `.Lastmod`.
+pub fn lastmod(html: &str) -> Option<u64> {
+    let lower = html.to_ascii_lowercase();
+    let mut pos = 0;
+    while let Some(start) = lower[pos..].find("<meta").map(|i| pos + i) {
+        let end = lower[start..].find('>').map(|i| start + i)?;
+        pos = end + 1;
+        let attrs = parse_attributes(&html[start + "<meta".len()..end]);
+        let attr = |key: &str| attrs.iter().find(|(k, _)| k == key).map(|(_, v)| v.as_str());
+        if attr("property") == Some("article:modified_time") {
+            return attr("content").and_then(parse_rfc3339);
+        }
+    }
+    None
+}
+
+/// `2024-05-01T10:20:30+02:00` (or `Z`) as Unix seconds.
+fn parse_rfc3339(s: &str) -> Option<u64> {
+    let field = |range: std::ops::Range<usize>| s.get(range)?.parse::<i64>().ok();
+    let (year, month, day) = (field(0..4)?, field(5..7)?, field(8..10)?);
+    let (hour, min, sec) = (field(11..13)?, field(14..16)?, field(17..19)?);
+    // Fractional seconds don't matter at HTTP-date resolution.
+    let zone = s.get(19..)?.trim_start_matches(|c: char| c == '.' || c.is_ascii_digit());
+    let offset = match zone {
+        "Z" | "z" => 0,
+        _ => {
+            let sign = match zone.get(..1)? {
+                "+" => 1,
+                "-" => -1,
+                _ => return None,
+            };
+            let hours = zone.get(1..3)?.parse::<i64>().ok()?;
+            let mins = zone.get(4..6)?.parse::<i64>().ok()?;
+            sign * (hours * 3600 + mins * 60)
+        }
+    };
+
+    let days = date::days_from_civil(year, month, day);
+    u64::try_from(days * 86400 + hour * 3600 + min * 60 + sec - offset).ok()
+}
+
+/// `sha384-…` SRI values for every non-HTML file under public/, by route.
+pub fn collect_integrity(public: &Path) -> HashMap<String, String> {
+    let mut integrity = HashMap::new();
+    for entry in WalkDir::new(public).follow_links(true).into_iter().flatten() {
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let path = entry.path();
+        if path.extension().is_some_and(|e| e == "html" || e == "htm" || e == "gmi") {
+            continue;
+        }
+        let Ok(content) = fs::read(path) else {
+            continue;
+        };
+        let relative = path.strip_prefix(public).expect("Failed to strip prefix");
+        let route = format!("/{}", relative.to_string_lossy()).replace('\\', "/");
+        let digest = Sha384::digest(&content);
+        integrity.insert(
+            route,
+            format!("sha384-{}", base64::engine::general_purpose::STANDARD.encode(digest)),
+        );
+    }
+    integrity
+}
+
+/// Add `integrity` to every `<script src>` and `<link rel=stylesheet>` in
+/// `html` that points at an embedded asset and doesn't carry one already.
+/// External URLs are left alone; a local reference to a file that isn't in
+/// public/ is an error, since the page would be broken anyway.
+pub fn add_subresource_integrity(
+    html: &str,
+    page_route: &str,
+    integrity: &HashMap<String, String>,
+) -> Result<String, String> {
+    let lower = html.to_ascii_lowercase();
+    let mut output = String::with_capacity(html.len());
+    let mut copied = 0;
+    let mut pos = 0;
+
+    while let Some(start) = lower[pos..].find('<').map(|i| pos + i) {
+        let Some(end) = lower[start..].find('>').map(|i| start + i) else {
+            break;
+        };
+        pos = end + 1;
+
+        let tag = &html[start + 1..end];
+        let name_len = tag.find(|c: char| c.is_ascii_whitespace()).unwrap_or(tag.len());
+        let name = tag[..name_len].to_ascii_lowercase();
+        let attrs = parse_attributes(&tag[name_len..]);
+        let attr = |key: &str| attrs.iter().find(|(k, _)| k == key).map(|(_, v)| v.as_str());
+
+        let reference = match name.as_str() {
+            "script" => attr("src"),
+            "link" if attr("rel").is_some_and(|rel| {
+                rel.split_ascii_whitespace().any(|r| r.eq_ignore_ascii_case("stylesheet"))
+            }) => attr("href"),
+            _ => None,
+        };
+        let Some(reference) = reference else {
+            continue;
+        };
+        if attr("integrity").is_some() {
+            continue;
+        }
+        let Some(target) = resolve_reference(page_route, reference) else {
+            continue;
+        };
+        let hash = integrity
+            .get(&target)
+            .ok_or_else(|| format!("references {} ({}), which is not in public/", reference, target))?;
+
+        // Insert before `>` or a self-closing `/>`.
+        let insert_at = if tag.ends_with('/') { end - 1 } else { end };
+        output.push_str(&html[copied..insert_at]);
+        output.push_str(&format!(" integrity=\"{}\"", hash));
+        copied = insert_at;
+    }
+    output.push_str(&html[copied..]);
+    Ok(output)
+}
+
+/// Inline the rules of a page's fingerprinted stylesheets that apply above
+/// the fold, and load the full sheets without blocking render: each
+/// `<link rel=stylesheet>` becomes a preload that turns itself into a
+/// stylesheet on load, with the original link in `<noscript>`. Pages with
+/// nothing critical are returned unchanged.
+pub fn inline_critical_css(html: &str, page_route: &str, public: &Path) -> String {
+    let lower = html.to_ascii_lowercase();
+    let Some(body_start) = lower.find("<body") else {
+        return html.to_string();
+    };
+    let used = above_the_fold(html, body_start);
+
+    let mut links = Vec::new();
+    let mut critical = String::new();
+    let mut pos = 0;
+    while let Some(start) = lower[pos..].find("<link").map(|i| pos + i) {
+        let Some(end) = lower[start..].find('>').map(|i| start + i) else {
+            break;
+        };
+        pos = end + 1;
+        let attrs = parse_attributes(&html[start + "<link".len()..end]);
+        let attr = |key: &str| attrs.iter().find(|(k, _)| k == key).map(|(_, v)| v.as_str());
+        let is_stylesheet = attr("rel").is_some_and(|rel| {
+            rel.split_ascii_whitespace().any(|r| r.eq_ignore_ascii_case("stylesheet"))
+        });
+        if !is_stylesheet || attr("media").is_some() {
+            continue;
+        }
+        let Some(target) = attr("href").and_then(|href| resolve_reference(page_route, href)) else {
+            continue;
+        };
+        if !is_fingerprinted_css(&target) {
+            continue;
+        }
+        let Ok(css) = fs::read_to_string(public.join(&target[1..])) else {
+            continue;
+        };
+        critical.push_str(&critical_rules(&css, &used));
+        links.push((start, end + 1, attrs));
+    }
+    if critical.is_empty() {
+        return html.to_string();
+    }
+
+    let mut output = String::with_capacity(html.len() + critical.len());
+    let mut copied = 0;
+    for (i, (start, end, attrs)) in links.iter().enumerate() {
+        output.push_str(&html[copied..*start]);
+        if i == 0 {
+            output.push_str(&format!("<style>{}</style>", critical));
+        }
+        output.push_str("<link");
+        for (name, value) in attrs {
+            match name.as_str() {
+                "rel" => output.push_str(" rel=\"preload\" as=\"style\""),
+                _ if value.is_empty() => output.push_str(&format!(" {}", name)),
+                // Values may come single-quoted and contain `"`.
+                _ => output.push_str(&format!(" {}=\"{}\"", name, value.replace('"', "&quot;"))),
+            }
+        }
+        output.push_str(" onload=\"this.onload=null;this.rel='stylesheet'\">");
+        output.push_str(&format!("<noscript>{}</noscript>", &html[*start..*end]));
+        copied = *end;
+    }
+    output.push_str(&html[copied..]);
+    output
+}
+
+/// Hugo's `resources.Fingerprint` names: `name.min.<hex>.css`.
+fn is_fingerprinted_css(route: &str) -> bool {
+    route
+        .strip_suffix(".css")
+        .and_then(|stem| stem.rsplit_once('.'))
+        .is_some_and(|(_, hash)| hash.len() >= 16 && hash.bytes().all(|b| b.is_ascii_hexdigit()))
+}
+
+/// Element names, classes and ids that occur in the first
+/// `CRITICAL_FOLD_BYTES` of the body, plus the `<html>` element's.
+struct UsedSelectors {
+    tags: Vec<String>,
+    classes: Vec<String>,
+    ids: Vec<String>,
+}
+
+fn above_the_fold(html: &str, body_start: usize) -> UsedSelectors {
+    let mut used = UsedSelectors {
+        tags: vec!["html".to_string(), "body".to_string()],
+        classes: Vec::new(),
+        ids: Vec::new(),
+    };
+    let fold = html.len().min(body_start + CRITICAL_FOLD_BYTES);
+    let html_start = html.to_ascii_lowercase().find("<html").unwrap_or(body_start);
+    let starts = std::iter::once(html_start).chain(
+        html[body_start..fold].match_indices('<').map(|(i, _)| body_start + i),
+    );
+
+    for start in starts {
+        let Some(end) = html[start..].find('>').map(|i| start + i) else {
+            break;
+        };
+        let tag = &html[start + 1..end];
+        if tag.starts_with(['/', '!']) {
+            continue;
+        }
+        let name_len = tag.find(|c: char| c.is_ascii_whitespace()).unwrap_or(tag.len());
+        let add = |list: &mut Vec<String>, value: &str| {
+            if !list.iter().any(|v| v == value) {
+                list.push(value.to_string());
+            }
+        };
+        add(&mut used.tags, &tag[..name_len].to_ascii_lowercase());
+        for (name, value) in parse_attributes(&tag[name_len..]) {
+            match name.as_str() {
+                "class" => value.split_ascii_whitespace().for_each(|c| add(&mut used.classes, c)),
+                "id" => add(&mut used.ids, &value),
+                _ => {}
+            }
+        }
+    }
+    used
+}
+
+/// The rules of `css` whose selectors can match `used`, keeping the
+/// `@media`/`@supports` blocks around them, every `@font-face`, and the
+/// `@keyframes` that kept rules refer to.
+fn critical_rules(css: &str, used: &UsedSelectors) -> String {
+    let mut output = String::new();
+    let mut keyframes = Vec::new();
+    collect_critical_rules(&strip_css_comments(css), used, &mut output, &mut keyframes);
+    for (name, rule) in keyframes {
+        if output.contains(&name) {
+            output.push_str(&rule);
+        }
+    }
+    output
+}
+
+fn collect_critical_rules(
+    css: &str,
+    used: &UsedSelectors,
+    output: &mut String,
+    keyframes: &mut Vec<(String, String)>,
+) {
+    for (prelude, block) in css_blocks(css) {
+        let prelude = prelude.trim();
+        let Some(at_rule) = prelude.strip_prefix('@') else {
+            let selectors: Vec<&str> = split_selectors(prelude)
+                .into_iter()
+                .filter(|s| selector_can_match(s, used))
+                .collect();
+            if !selectors.is_empty() {
+                output.push_str(&format!("{}{{{}}}", selectors.join(","), block));
+            }
+            continue;
+        };
+        let name_len = at_rule.find(|c: char| !c.is_ascii_alphanumeric() && c != '-').unwrap_or(at_rule.len());
+        match at_rule[..name_len].to_ascii_lowercase().as_str() {
+            "media" | "supports" | "layer" | "container" => {
+                let mut inner = String::new();
+                collect_critical_rules(block, used, &mut inner, keyframes);
+                if !inner.is_empty() {
+                    output.push_str(&format!("{}{{{}}}", prelude, inner));
+                }
+            }
+            "font-face" => output.push_str(&format!("{}{{{}}}", prelude, block)),
+            "keyframes" | "-webkit-keyframes" => {
+                let name = at_rule[name_len..].trim().to_string();
+                keyframes.push((name, format!("{}{{{}}}", prelude, block)));
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Top-level `(prelude, block)` pairs of a stylesheet. Block-less
+/// statements (`@import`, `@charset`) are dropped.
+fn css_blocks(css: &str) -> Vec<(&str, &str)> {
+    let bytes = css.as_bytes();
+    let mut blocks = Vec::new();
+    let mut prelude_start = 0;
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'"' | b'\'' => i = skip_css_string(bytes, i),
+            b';' => {
+                prelude_start = i + 1;
+                i += 1;
+            }
+            b'{' => {
+                let mut depth = 1;
+                let mut j = i + 1;
+                while j < bytes.len() && depth > 0 {
+                    match bytes[j] {
+                        b'"' | b'\'' => {
+                            j = skip_css_string(bytes, j);
+                            continue;
+                        }
+                        b'{' => depth += 1,
+                        b'}' => depth -= 1,
+                        _ => {}
+                    }
+                    j += 1;
+                }
+                let block_end = if depth == 0 { j - 1 } else { j };
+                blocks.push((&css[prelude_start..i], &css[i + 1..block_end]));
+                prelude_start = j;
+                i = j;
+            }
+            _ => i += 1,
+        }
+    }
+    blocks
+}
+
+/// Index just past the string literal starting at `start`.
+fn skip_css_string(bytes: &[u8], start: usize) -> usize {
+    let quote = bytes[start];
+    let mut i = start + 1;
+    while i < bytes.len() && bytes[i] != quote {
+        i += if bytes[i] == b'\\' { 2 } else { 1 };
+    }
+    (i + 1).min(bytes.len())
+}
+
+fn strip_css_comments(css: &str) -> String {
+    let bytes = css.as_bytes();
+    let mut output = String::with_capacity(css.len());
+    let (mut copied, mut i) = (0, 0);
+    while i < bytes.len() {
+        match bytes[i] {
+            b'"' | b'\'' => i = skip_css_string(bytes, i),
+            b'/' if bytes.get(i + 1) == Some(&b'*') => {
+                output.push_str(&css[copied..i]);
+                i = css[i + 2..].find("*/").map_or(bytes.len(), |end| i + 2 + end + 2);
+                copied = i;
+            }
+            _ => i += 1,
+        }
+    }
+    output.push_str(&css[copied..]);
+    output
+}
+
+/// Split a selector list on the commas that aren't inside `:is(…)` and
+/// the like.
+fn split_selectors(prelude: &str) -> Vec<&str> {
+    let mut selectors = Vec::new();
+    let (mut depth, mut start) = (0, 0);
+    for (i, c) in prelude.char_indices() {
+        match c {
+            '(' | '[' => depth += 1,
+            ')' | ']' => depth -= 1,
+            ',' if depth == 0 => {
+                selectors.push(prelude[start..i].trim());
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    selectors.push(prelude[start..].trim());
+    selectors
+}
+
+/// Whether every element name, class and id in `selector` occurs above
+/// the fold. Attribute selectors and pseudo-classes can't be judged from
+/// markup alone (`[data-theme]` is set by a script), so they're assumed
+/// to match.
+fn selector_can_match(selector: &str, used: &UsedSelectors) -> bool {
+    let chars: Vec<char> = selector.chars().collect();
+    let ident = |start: usize| {
+        let mut end = start;
+        let mut name = String::new();
+        while end < chars.len() {
+            match chars[end] {
+                '\\' if end + 1 < chars.len() => {
+                    name.push(chars[end + 1]);
+                    end += 2;
+                }
+                c if c.is_alphanumeric() || c == '-' || c == '_' || !c.is_ascii() => {
+                    name.push(c);
+                    end += 1;
+                }
+                _ => break,
+            }
+        }
+        (name, end)
+    };
+
+    let mut i = 0;
+    while i < chars.len() {
+        match chars[i] {
+            '.' | '#' => {
+                let (name, end) = ident(i + 1);
+                let list = if chars[i] == '.' { &used.classes } else { &used.ids };
+                if !list.contains(&name) {
+                    return false;
+                }
+                i = end;
+            }
+            '[' => {
+                let mut depth = 0;
+                while i < chars.len() {
+                    match chars[i] {
+                        '[' => depth += 1,
+                        ']' => depth -= 1,
+                        _ => {}
+                    }
+                    i += 1;
+                    if depth == 0 {
+                        break;
+                    }
+                }
+            }
+            ':' => {
+                let colons = if chars.get(i + 1) == Some(&':') { 2 } else { 1 };
+                let (_, mut end) = ident(i + colons);
+                if chars.get(end) == Some(&'(') {
+                    let mut depth = 0;
+                    while end < chars.len() {
+                        match chars[end] {
+                            '(' => depth += 1,
+                            ')' => depth -= 1,
+                            _ => {}
+                        }
+                        end += 1;
+                        if depth == 0 {
+                            break;
+                        }
+                    }
+                }
+                i = end;
+            }
+            c if c.is_alphabetic() => {
+                let (name, end) = ident(i);
+                if !used.tags.contains(&name.to_ascii_lowercase()) {
+                    return false;
+                }
+                i = end;
+            }
+            _ => i += 1,
+        }
+    }
+    true
+}
+
+/// Lowercased attribute names with their (unquoted) values. Handles the
+/// double-, single- and unquoted forms `hugo --minify` produces.
+fn parse_attributes(s: &str) -> Vec<(String, String)> {
+    let mut attrs = Vec::new();
+    let mut rest = s.trim_start();
+    while !rest.is_empty() && !rest.starts_with('/') {
+        let name_end = rest
+            .find(|c: char| c == '=' || c == '/' || c.is_ascii_whitespace())
+            .unwrap_or(rest.len());
+        let name = rest[..name_end].to_ascii_lowercase();
+        rest = rest[name_end..].trim_start();
+
+        let mut value = String::new();
+        if let Some(after_eq) = rest.strip_prefix('=') {
+            let after_eq = after_eq.trim_start();
+            let (v, remaining) = match after_eq.chars().next() {
+                Some(q @ ('"' | '\'')) => {
+                    let body = &after_eq[1..];
+                    let close = body.find(q).unwrap_or(body.len());
+                    (&body[..close], body.get(close + 1..).unwrap_or(""))
+                }
+                _ => {
+                    let close = after_eq.find(|c: char| c.is_ascii_whitespace()).unwrap_or(after_eq.len());
+                    (&after_eq[..close], &after_eq[close..])
+                }
+            };
+            value = v.to_string();
+            rest = remaining.trim_start();
+        }
+        if name.is_empty() {
+            // Stray character; skip it rather than loop forever.
+            rest = rest.get(1..).unwrap_or("").trim_start();
+            continue;
+        }
+        attrs.push((name, value));
+    }
+    attrs
+}
+
+/// The route a same-origin reference from `page_route` points at, or `None`
+/// for anything external (`https:`, `//host`, `data:`, ...).
+fn resolve_reference(page_route: &str, reference: &str) -> Option<String> {
+    let reference = reference.split(['?', '#']).next().unwrap_or("");
+    if reference.is_empty() || reference.starts_with("//") || reference.contains(':') {
+        return None;
+    }
+    let joined = if reference.starts_with('/') {
+        reference.to_string()
+    } else {
+        let dir = &page_route[..page_route.rfind('/').map_or(0, |i| i + 1)];
+        format!("{}{}", dir, reference)
+    };
+
+    let mut segments: Vec<&str> = Vec::new();
+    for segment in joined.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop();
+            }
+            s => segments.push(s),
+        }
+    }
+    Some(format!("/{}", segments.join("/")))
+}
+
+/// `'sha256-…'` CSP sources for every inline `<script>` or `<style>` block
+/// (`tag`), hashed over the exact bytes between the tags. Scripts with a
+/// `src` attribute have no inline body to allow and are skipped.
+pub fn inline_block_hashes(html: &str, tag: &str) -> Vec<String> {
+    let lower = html.to_ascii_lowercase();
+    let open = format!("<{}", tag);
+    let close = format!("</{}", tag);
+    let mut hashes = Vec::new();
+    let mut pos = 0;
+
+    while let Some(start) = lower[pos..].find(&open).map(|i| pos + i) {
+        let after_name = start + open.len();
+        // `<scripts>` or `<style-guide>` aren't the element we want.
+        if !lower[after_name..].starts_with(|c: char| c == '>' || c.is_ascii_whitespace()) {
+            pos = after_name;
+            continue;
+        }
+        let Some(tag_end) = lower[after_name..].find('>').map(|i| after_name + i) else {
+            break;
+        };
+        let Some(body_end) = lower[tag_end..].find(&close).map(|i| tag_end + i) else {
+            break;
+        };
+        let attrs = &lower[after_name..tag_end];
+        let has_src = attrs
+            .split(|c: char| c.is_ascii_whitespace())
+            .any(|a| a == "src" || a.starts_with("src="));
+        let body = &html[tag_end + 1..body_end];
+        if !has_src && !body.is_empty() {
+            let source = csp_hash_source(body);
+            if !hashes.contains(&source) {
+                hashes.push(source);
+            }
+        }
+        pos = body_end + close.len();
+    }
+    hashes
+}
+
+/// CSP hash sources for inline event handlers (`onclick=…`) and `style`
+/// attributes, in that order, hashed over the attribute values.
+pub fn inline_attribute_hashes(html: &str) -> (Vec<String>, Vec<String>) {
+    let (mut handlers, mut styles) = (Vec::new(), Vec::new());
+    let mut pos = 0;
+    while let Some(start) = html[pos..].find('<').map(|i| pos + i) {
+        let Some(end) = html[start..].find('>').map(|i| start + i) else {
+            break;
+        };
+        pos = end + 1;
+        let tag = &html[start + 1..end];
+        if tag.starts_with(['/', '!']) {
+            continue;
+        }
+        let name_len = tag.find(|c: char| c.is_ascii_whitespace()).unwrap_or(tag.len());
+        for (name, value) in parse_attributes(&tag[name_len..]) {
+            let list = if name.starts_with("on") && name.len() > 2 {
+                &mut handlers
+            } else if name == "style" {
+                &mut styles
+            } else {
+                continue;
+            };
+            let source = csp_hash_source(&decode_entities(&value));
+            if !value.is_empty() && !list.contains(&source) {
+                list.push(source);
+            }
+        }
+    }
+    (handlers, styles)
+}
+
+/// The handful of character references minifiers leave in attribute
+/// values; the browser hashes the decoded text.
+fn decode_entities(value: &str) -> String {
+    value
+        .replace("&quot;", "\"")
+        .replace("&#34;", "\"")
+        .replace("&#39;", "'")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&amp;", "&")
+}
+
+fn csp_hash_source(text: &str) -> String {
+    let digest = Sha256::digest(text.as_bytes());
+    format!("'sha256-{}'", base64::engine::general_purpose::STANDARD.encode(digest))
+}
+
+/// `base` with `script_hashes`/`style_hashes` appended to the matching
+/// directives. A directive missing from `base` is added as `'self'` plus
+/// the hashes.
+pub fn page_csp(base: &str, script_hashes: &[String], style_hashes: &[String]) -> String {
+    let mut directives: Vec<String> = base
+        .split(';')
+        .map(|d| d.split_whitespace().collect::<Vec<_>>().join(" "))
+        .filter(|d| !d.is_empty())
+        .collect();
+
+    for (name, hashes) in [("script-src", script_hashes), ("style-src", style_hashes)] {
+        if hashes.is_empty() {
+            continue;
+        }
+        let extra = hashes.join(" ");
+        match directives
+            .iter_mut()
+            .find(|d| d.split(' ').next().is_some_and(|n| n.eq_ignore_ascii_case(name)))
+        {
+            Some(directive) => {
+                directive.push(' ');
+                directive.push_str(&extra);
+            }
+            None => directives.push(format!("{} 'self' {}", name, extra)),
+        }
+    }
+    directives.join("; ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    /// A fresh `<tmp>/<name>/public` holding `files` (route, content).
+    fn public_dir(name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("site-test-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&root);
+        let public = root.join("public");
+        for (route, content) in files {
+            let path = public.join(route.trim_start_matches('/'));
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, content).unwrap();
+        }
+        public
+    }
+
+    const ALERT: &str = "'sha256-bhHHL3z2vDgxUt0W3dWQOrprscmda2Y5pLsLg4GF+pI='";
+    const RED_BODY: &str = "'sha256-FcQqt3aNlV7AZnGV4zkQRVeCeJOxbMPnQSx258L803E='";
+    const RED: &str = "'sha256-8f935d27GvUutRyY9yWScUMiFUk4WTdZURISiYfPOeQ='";
+
+    #[test]
+    fn hashes_the_exact_block_bytes() {
+        assert_eq!(csp_hash_source("alert(1)"), ALERT);
+        assert_ne!(csp_hash_source("alert(1) "), ALERT);
+    }
+
+    #[test]
+    fn hashes_inline_blocks_once_and_skips_external_scripts() {
+        let html = "<script>alert(1)</script><script src=/app.js></script>\
+            <SCRIPT type=module>alert(1)</SCRIPT><scripts>x</scripts><script></script>\
+            <style>body{color:red}</style>";
+        assert_eq!(inline_block_hashes(html, "script"), [ALERT]);
+        assert_eq!(inline_block_hashes(html, "style"), [RED_BODY]);
+        assert!(inline_block_hashes("<script>alert(1)", "script").is_empty());
+    }
+
+    #[test]
+    fn hashes_decoded_handler_and_style_attributes() {
+        let html = "<a onclick=\"alert(1)\" style='color:red'>x</a><b onmouseover=alert(1) on=x>\
+            <link onload=\"this.rel=&#39;stylesheet&#39;\"><!-- style=\"x\" --></a>";
+        let (handlers, styles) = inline_attribute_hashes(html);
+        assert_eq!(handlers, [ALERT, "'sha256-F1noxsLOnJhyRSgc0zu5JgzoLjG2BBMaXaSG24k2mRM='"]);
+        assert_eq!(styles, [RED]);
+    }
+
+    #[test]
+    fn appends_hashes_to_matching_directives() {
+        let hashes = [ALERT.to_string()];
+        assert_eq!(
+            page_csp("default-src 'self';  Script-Src 'self' ; img-src *", &hashes, &[]),
+            format!("default-src 'self'; Script-Src 'self' {}; img-src *", ALERT),
+        );
+        assert_eq!(
+            page_csp("default-src 'none'", &hashes, &[RED.to_string()]),
+            format!("default-src 'none'; script-src 'self' {}; style-src 'self' {}", ALERT, RED),
+        );
+        assert_eq!(page_csp("default-src 'self';", &[], &[]), "default-src 'self'");
+    }
+
+    const APP_JS: &str = "sha384-vuz+yO71bcb30P4dMUNzy6/D2y+6d/n0KcOnt5clJtTBxEDoKAqGay0stFlC8Dpr";
+    const MAIN_CSS: &str = "sha384-myyg/hQ74aSgjBBvVME/QXAXEkT4Y9dHbVQ5C0lIyGpldvNLJV2IWc5ElXbqLi06";
+
+    fn integrity() -> HashMap<String, String> {
+        HashMap::from([
+            ("/js/app.js".to_string(), APP_JS.to_string()),
+            ("/css/main.css".to_string(), MAIN_CSS.to_string()),
+        ])
+    }
+
+    #[test]
+    fn collects_sha384_of_everything_but_pages() {
+        let public = public_dir(
+            "integrity",
+            &[
+                ("/js/app.js", "console.log(1)"),
+                ("/css/main.css", "body{}"),
+                ("/index.html", "<html></html>"),
+                ("/index.gmi", "# Home"),
+            ],
+        );
+        assert_eq!(collect_integrity(&public), integrity());
+        fs::remove_dir_all(public.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn pins_local_scripts_and_stylesheets() {
+        let html = "<link rel=\"preload stylesheet\" href=../css/main.css?v=2/>\
+            <script src=\"/js/app.js\"></script><link rel=icon href=/favicon.ico>";
+        assert_eq!(
+            add_subresource_integrity(html, "/posts/index.html", &integrity()).unwrap(),
+            format!(
+                "<link rel=\"preload stylesheet\" href=../css/main.css?v=2 integrity=\"{}\"/>\
+                <script src=\"/js/app.js\" integrity=\"{}\"></script><link rel=icon href=/favicon.ico>",
+                MAIN_CSS, APP_JS,
+            ),
+        );
+    }
+
+    #[test]
+    fn leaves_external_and_pinned_references_alone() {
+        let html = "<script src=https://cdn.example/x.js></script><script src=//cdn.example/y.js></script>\
+            <script src=/js/app.js integrity=sha384-other></script><script>inline()</script>";
+        assert_eq!(add_subresource_integrity(html, "/index.html", &integrity()).unwrap(), html);
+    }
+
+    #[test]
+    fn missing_local_reference_is_an_error() {
+        let error = add_subresource_integrity("<script src=gone.js></script>", "/a/index.html", &integrity())
+            .unwrap_err();
+        assert_eq!(error, "references gone.js (/a/gone.js), which is not in public/");
+    }
+
+    fn rule(from: &str, to: &str, status: u16) -> (String, String, u16) {
+        (from.to_string(), to.to_string(), status)
+    }
+
+    #[test]
+    fn parses_redirect_rules_in_order() {
+        let source = "# moved posts\n\
+            /old/:slug/*  /posts/:slug/:splat  302\n\
+            \n\
+            /feed.xml /index.xml # renamed\n\
+            /gone 410\n\
+            /ext/* https://example.org/:splat 308\n";
+        assert_eq!(
+            parse_redirects(source).unwrap(),
+            [
+                rule("/old/:slug/*", "/posts/:slug/:splat", 302),
+                rule("/feed.xml", "/index.xml", 301),
+                rule("/gone", "", 410),
+                rule("/ext/*", "https://example.org/:splat", 308),
+            ],
+        );
+        assert_eq!(parse_redirects("").unwrap(), []);
+    }
+
+    #[test]
+    fn malformed_redirect_rules_name_the_line() {
+        for (line, error) in [
+            ("/a", "expected `FROM TO [STATUS]`"),
+            ("/a /b 301 x", "expected `FROM TO [STATUS]`"),
+            ("/a /b moved", "status is not a number"),
+            ("/a /b 404", "status must be one of 301, 302, 307, 308, 410"),
+            ("a /b", "FROM must be a path starting with /"),
+            ("/*/a /b", "`*` is only allowed at the end of FROM"),
+            ("/a/:id /b/:slug", "TO uses :slug but FROM doesn't capture it"),
+            ("/a /b/:splat", "TO uses :splat but FROM doesn't capture it"),
+        ] {
+            let source = format!("/ok /fine\n{}\n", line);
+            assert_eq!(parse_redirects(&source), Err(format!("line 2: {}", error)), "{}", line);
+        }
+    }
+
+    #[test]
+    fn alias_pages_redirect_to_the_target_path() {
+        let alias = |content: &str| {
+            let html = format!(
+                "<!doctype html><html lang=en><head><title>x</title>\
+                <link rel=canonical href=https://example.org/posts/hello/>\
+                <meta name=robots content=noindex><meta charset=utf-8>\
+                <meta http-equiv={}></head></html>",
+                content,
+            );
+            alias_target(&html)
+        };
+        for (meta, target) in [
+            ("refresh content=\"0; url=https://example.org/posts/hello/\"", Some("/posts/hello/")),
+            ("\"Refresh\" content='0;URL=\"/de/posts/\"'", Some("/de/posts/")),
+            ("refresh content=\"0; url=https://example.org\"", Some("/")),
+            ("refresh content=\"5; url=/posts/\"", None),
+            ("refresh content=\"0; url=posts/\"", None),
+            ("refresh content=\"0\"", None),
+            ("content-type content=\"0; url=/posts/\"", None),
+        ] {
+            assert_eq!(alias(meta).as_deref(), target, "{}", meta);
+        }
+    }
+
+    #[test]
+    fn pages_with_a_body_are_not_aliases() {
+        let html = "<html><head><meta http-equiv=refresh content=\"0; url=/live/\"></head>\
+            <body>Live</body></html>";
+        assert_eq!(alias_target(html), None);
+    }
+
+    #[test]
+    fn html_lang_reads_the_root_element() {
+        let html = "<!doctype html><HTML class=x LANG='de-DE'><body lang=en>";
+        assert_eq!(html_lang(html).as_deref(), Some("de-DE"));
+        assert_eq!(html_lang("<html lang=\"\"><body lang=en>"), None);
+        assert_eq!(html_lang("<body lang=en>"), None);
+    }
+
+    #[test]
+    fn groups_translations_by_path_without_the_language_prefix() {
+        let page = |lang: &str| format!("<html lang={}><body>x</body></html>", lang);
+        let public = public_dir(
+            "languages",
+            &[
+                ("/index.html", &page("en")),
+                ("/de/index.html", &page("de-DE")),
+                ("/posts/a/index.html", &page("en")),
+                ("/de/posts/a/index.html", &page("de")),
+                ("/only/index.html", &page("en")),
+                ("/de/only-de.html", &page("de")),
+                ("/unmarked/index.html", "<html><body>x</body></html>"),
+                ("/old/index.html", "<html lang=en><meta http-equiv=refresh content=\"0; url=/posts/a/\">"),
+                ("/de/style.css", "body{}"),
+            ],
+        );
+        let languages = collect_languages(&public);
+        fs::remove_dir_all(public.parent().unwrap()).unwrap();
+
+        let mut routes: Vec<&str> = languages.keys().map(|r| r.as_str()).collect();
+        routes.sort();
+        assert_eq!(
+            routes,
+            [
+                "/de/index.html",
+                "/de/only-de.html",
+                "/de/posts/a/index.html",
+                "/index.html",
+                "/only/index.html",
+                "/posts/a/index.html",
+            ],
+        );
+        let alternates = |route: &str| {
+            let page = &languages[route];
+            let alternates: Vec<(&str, &str)> =
+                page.alternates.iter().map(|(lang, href)| (lang.as_str(), href.as_str())).collect();
+            (page.lang.as_str(), alternates)
+        };
+        let home = vec![("en", "/"), ("de-DE", "/de/")];
+        assert_eq!(alternates("/index.html"), ("en", home.clone()));
+        assert_eq!(alternates("/de/index.html"), ("de-DE", home));
+        let post = vec![("en", "/posts/a/"), ("de", "/de/posts/a/")];
+        assert_eq!(alternates("/de/posts/a/index.html"), ("de", post));
+        assert_eq!(alternates("/only/index.html"), ("en", vec![]));
+        assert_eq!(alternates("/de/only-de.html"), ("de", vec![]));
+    }
+
+    #[test]
+    fn theme_variant_loads_only_that_themes_hero() {
+        let html = "<html lang=en data-theme=dark><body>\
+            <img class=\"hero hero-bg-light\" src=/l.webp srcset=\"/l.webp 1x\" loading=lazy>\
+            <IMG class=hero-bg-dark src=/d.webp fetchpriority=high>\
+            <img src=/x.png loading=lazy></body></html>";
+        assert_eq!(
+            theme_variant(html, "light").unwrap(),
+            "<html lang=\"en\" data-theme=\"light\"><body>\
+            <img class=\"hero hero-bg-light\" src=\"/l.webp\" srcset=\"/l.webp 1x\" fetchpriority=\"high\">\
+            <IMG class=\"hero-bg-dark\" data-src=\"/d.webp\">\
+            <img src=/x.png loading=lazy></body></html>",
+        );
+        assert_eq!(
+            theme_variant(html, "dark").unwrap(),
+            "<html lang=\"en\" data-theme=\"dark\"><body>\
+            <img class=\"hero hero-bg-light\" data-src=\"/l.webp\" data-srcset=\"/l.webp 1x\">\
+            <IMG class=\"hero-bg-dark\" src=\"/d.webp\" fetchpriority=\"high\">\
+            <img src=/x.png loading=lazy></body></html>",
+        );
+    }
+
+    #[test]
+    fn pages_without_every_hero_have_no_theme_variants() {
+        let html = "<html><body><img class=hero-bg-light src=/l.webp></body></html>";
+        assert_eq!(theme_variant(html, "light"), None);
+        assert_eq!(theme_variant(html, "dark"), None);
+    }
+
+    #[test]
+    fn preloads_fonts_stylesheets_and_the_lcp_image() {
+        let html = "<html><head>\
+            <link rel=preload href=/fonts/a.woff2 as=font type=font/woff2 crossorigin>\
+            <link rel=stylesheet href=/css/main.css integrity=\"sha384-AbC+/=\">\
+            <link rel=\"preload\" as=\"style\" href=\"/css/site.css\" integrity=\"sha384-x\" onload=\"f()\">\
+            <noscript><link rel=stylesheet href=/css/site.css integrity=sha384-x></noscript>\
+            <link rel=stylesheet href=/print.css media=print><link rel=stylesheet href=\"data:text/css,a\">\
+            </head><body><img src=/logo.png loading=lazy><img src=/first.png>\
+            <img src=/hero.webp srcset=\"/hero-2x.webp 2x\" sizes=100vw fetchpriority=high \
+            crossorigin=use-credentials>";
+        assert_eq!(
+            preload_links(html),
+            [
+                "</fonts/a.woff2>; rel=preload; as=font; type=\"font/woff2\"; crossorigin",
+                "</css/main.css>; rel=preload; as=style; integrity=\"sha384-AbC+/=\"",
+                "</css/site.css>; rel=preload; as=style; integrity=sha384-x",
+                "</hero.webp>; rel=preload; as=image; fetchpriority=high; imagesrcset=\"/hero-2x.webp 2x\"; \
+                imagesizes=100vw; crossorigin=use-credentials",
+            ],
+        );
+    }
+
+    #[test]
+    fn lcp_image_falls_back_to_the_first_eager_one() {
+        let html = "<body><img src=/logo.png loading=lazy><img src=/first.png><img src=/second.png>";
+        assert_eq!(preload_links(html), ["</first.png>; rel=preload; as=image; fetchpriority=high"]);
+        assert!(preload_links("<body><img src=/a.png loading=lazy><p>text</p>").is_empty());
+    }
+
+    #[test]
+    fn css_blocks_split_top_level_rules() {
+        let css = "@charset \"utf-8\";a{color:red}@media (min-width:1px){.b{x:y}}.c{content:\"}{\"}.d{x:y";
+        assert_eq!(
+            css_blocks(css),
+            [
+                ("a", "color:red"),
+                ("@media (min-width:1px)", ".b{x:y}"),
+                (".c", "content:\"}{\""),
+                (".d", "x:y"),
+            ],
+        );
+    }
+
+    fn used() -> UsedSelectors {
+        let list = |items: &[&str]| items.iter().map(|s| s.to_string()).collect();
+        UsedSelectors {
+            tags: list(&["html", "body", "nav", "a"]),
+            classes: list(&["menu", "hero-bg-light", "sm:flex"]),
+            ids: list(&["top"]),
+        }
+    }
+
+    #[test]
+    fn selectors_match_only_what_is_above_the_fold() {
+        for (selector, expected) in [
+            ("nav .menu > a:hover", true),
+            ("NAV#top", true),
+            ("[data-theme=dark] .hero-bg-light", true),
+            (":is(.x, footer) a::before", true),
+            (".sm\\:flex", true),
+            ("*", true),
+            (".menu.other", false),
+            ("#bottom", false),
+            ("footer a", false),
+            ("a + .hero-bg-dark", false),
+        ] {
+            assert_eq!(selector_can_match(selector, &used()), expected, "{}", selector);
+        }
+    }
+
+    #[test]
+    fn fold_cuts_off_after_critical_fold_bytes_of_body() {
+        let html = format!(
+            "<html class=js><head><link class=head></head><body><nav id=top class=\"menu wide\"></nav>\
+            <p>{}</p><footer class=late></footer></body></html>",
+            "x".repeat(CRITICAL_FOLD_BYTES),
+        );
+        let used = above_the_fold(&html, html.find("<body").unwrap());
+        assert_eq!(used.tags, ["html", "body", "nav", "p"]);
+        assert_eq!(used.classes, ["js", "menu", "wide"]);
+        assert_eq!(used.ids, ["top"]);
+    }
+
+    #[test]
+    fn critical_rules_keep_wrappers_fonts_and_used_keyframes() {
+        let css = "/* reset */nav,footer{margin:0}.late{color:red}\
+            @media (min-width:40em){.menu{display:flex}.late{x:y}}@media print{footer{x:y}}\
+            @font-face{font-family:f;src:url(/f.woff2)}\
+            @keyframes spin{to{rotate:1turn}}@keyframes fade{to{opacity:0}}a{animation:spin 1s}";
+        assert_eq!(
+            critical_rules(css, &used()),
+            "nav{margin:0}@media (min-width:40em){.menu{display:flex}}\
+            @font-face{font-family:f;src:url(/f.woff2)}a{animation:spin 1s}@keyframes spin{to{rotate:1turn}}",
+        );
+    }
+
+    #[test]
+    fn inlines_critical_css_and_defers_fingerprinted_sheets() {
+        let public = public_dir(
+            "critical",
+            &[("/css/main.min.0123456789abcdef.css", "nav{margin:0}footer{margin:1em}")],
+        );
+        let html = "<html><head><link rel=stylesheet href=../css/main.min.0123456789abcdef.css \
+            integrity=\"sha384-x\"><link rel=stylesheet href=/other.css></head>\
+            <body><nav></nav></body></html>";
+        let inlined = inline_critical_css(html, "/posts/index.html", &public);
+        fs::remove_dir_all(public.parent().unwrap()).unwrap();
+        assert_eq!(
+            inlined,
+            "<html><head><style>nav{margin:0}</style><link rel=\"preload\" as=\"style\" \
+            href=\"../css/main.min.0123456789abcdef.css\" integrity=\"sha384-x\" \
+            onload=\"this.onload=null;this.rel='stylesheet'\"><noscript><link rel=stylesheet \
+            href=../css/main.min.0123456789abcdef.css integrity=\"sha384-x\"></noscript>\
+            <link rel=stylesheet href=/other.css></head><body><nav></nav></body></html>",
+        );
+    }
+
+    #[test]
+    fn parses_rfc3339_timestamps() {
+        assert_eq!(parse_rfc3339("2024-05-01T10:20:30+02:00"), Some(1714551630));
+        assert_eq!(parse_rfc3339("2024-05-01T08:20:30Z"), Some(1714551630));
+        assert_eq!(parse_rfc3339("2000-02-29T23:59:59.123-05:30"), Some(951888599));
+        assert_eq!(parse_rfc3339("1970-01-01T00:00:00Z"), Some(0));
+        let bad = ["1969-12-31T23:59:59Z", "2024-05-01T10:20:30", "2024-05-01T10:20:30 02:00", "2024-05-01"];
+        for bad in bad {
+            assert_eq!(parse_rfc3339(bad), None, "{}", bad);
+        }
+    }
+
+    #[test]
+    fn lastmod_reads_the_modified_time_meta() {
+        let html = "<head><meta property=article:published_time content=2020-01-01T00:00:00Z>\
+            <meta property=\"article:modified_time\" content=\"2024-05-01T10:20:30+02:00\"></head>";
+        assert_eq!(lastmod(html), Some(1714551630));
+        assert_eq!(lastmod("<meta property=article:modified_time content=yesterday>"), None);
+        assert_eq!(lastmod("<meta name=description content=x>"), None);
+    }
+
+    #[test]
+    fn git_times_prefer_the_project_over_themes() {
+        let public = public_dir("git", &[]);
+        let root = public.parent().unwrap();
+        fs::create_dir_all(root).unwrap();
+        let git = |args: &[&str], time: &str| {
+            let status = std::process::Command::new("git")
+                .arg("-C")
+                .arg(root)
+                .args(["-c", "user.name=test", "-c", "user.email=test@example.org"])
+                .args(args)
+                .envs([("GIT_AUTHOR_DATE", time), ("GIT_COMMITTER_DATE", time)])
+                .status()
+                .unwrap();
+            assert!(status.success(), "git {:?}", args);
+        };
+        let write = |path: &str, content: &str| {
+            let path = root.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, content).unwrap();
+        };
+
+        let first = "@1672531200 +0000";
+        git(&["init", "-q"], first);
+        for path in ["static/a.css", "static/sub/c.txt", "themes/t/static/a.css", "themes/t/static/b.js"] {
+            write(path, "1");
+        }
+        git(&["add", "."], first);
+        git(&["commit", "-qm", "first"], first);
+        write("static/sub/c.txt", "2");
+        write("themes/t/static/a.css", "2");
+        git(&["commit", "-qam", "second"], "@1709294400 +0000");
+
+        let times = collect_git_times(&public);
+        fs::remove_dir_all(root).unwrap();
+        assert_eq!(
+            times,
+            HashMap::from([
+                ("/a.css".to_string(), 1672531200),
+                ("/sub/c.txt".to_string(), 1709294400),
+                ("/b.js".to_string(), 1672531200),
+            ]),
+        );
+    }
+}
@@ -50,11 +50,27 @@ deploy() {
     ssh "root@${VPS_HOST}" bash -s <<'REMOTE'
 set -e
 
-systemctl stop homepage || true
+chown homepage:homepage /tmp/static-server.new
+chmod +x /tmp/static-server.new
 mv /tmp/static-server.new /opt/homepage/static-server
-chown homepage:homepage /opt/homepage/static-server
-chmod +x /opt/homepage/static-server
-systemctl start homepage
+
+# A running server execs the new binary itself and hands over its sockets
+# (SIGUSR2); it only exits once the new one is serving.
+old_pid=$(systemctl show -p MainPID --value homepage)
+if [ "$old_pid" != "0" ]; then
+    systemctl kill --kill-whom=main -s USR2 homepage
+    for i in $(seq 1 35); do
+        new_pid=$(systemctl show -p MainPID --value homepage)
+        [ "$new_pid" != "$old_pid" ] && break
+        sleep 1
+    done
+    if [ "$new_pid" = "$old_pid" ]; then
+        echo "Upgrade didn't take over, restarting instead"
+        systemctl restart homepage
+    fi
+else
+    systemctl start homepage
+fi
 
 # Wait for it to come up
 echo "Waiting for server to start..."
@@ -0,0 +1,604 @@
+//! Access log for HTTP, WebSocket upgrades and Gemini
+//!
+//! One line per request in Common Log Format, Combined Log Format or JSON
+//! lines. Combined appends the chosen Content-Encoding and the latency in
+//! milliseconds after the user agent, which log analyzers that expect the
+//! plain format ignore.
+//!
+//! Lines are formatted on the request path and handed to a writer thread,
+//! so a slow disk never stalls a response. The queue is bounded: once the
+//! writer is `QUEUE_LINES` behind, new lines are dropped and counted, and
+//! the writer reports the count on stderr. It flushes whenever it runs out
+//! of queued lines. File logs rotate by size: `access.log` is renamed to
+//! `access.log.1`, older files shift up and the oldest is removed.
+
+use hyper::{header, Body, Request, Version};
+use parking_lot::RwLock;
+use serde_json::json;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, SystemTime};
+
+use crate::config::Config;
+use crate::date;
+
+// Lines waiting for the writer thread before new ones are dropped.
+const QUEUE_LINES: usize = 8192;
+
+lazy_static::lazy_static! {
+    static ref LOGGER: RwLock<Option<Logger>> = RwLock::new(None);
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Common,
+    Combined,
+    Json,
+}
+
+impl Format {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "common" => Some(Self::Common),
+            "combined" => Some(Self::Combined),
+            "json" => Some(Self::Json),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Common => "common",
+            Self::Combined => "combined",
+            Self::Json => "json",
+        }
+    }
+}
+
+pub enum Destination {
+    Stdout,
+    /// Rotate once the file would exceed `max_bytes`, keeping `keep` old
+    /// files.
+    File { path: PathBuf, max_bytes: u64, keep: usize },
+}
+
+struct Logger {
+    format: Format,
+    anonymize: bool,
+    sender: SyncSender<String>,
+    // Lines dropped because the queue was full, since the writer last
+    // reported them.
+    dropped: Arc<AtomicU64>,
+    writer: JoinHandle<()>,
+}
+
+/// Peer address of the connection a request arrived on, inserted into the
+/// request extensions by the listeners.
+#[derive(Clone, Copy)]
+pub struct PeerAddr(pub SocketAddr);
+
+/// One request, filled in as it's handled.
+pub struct Entry {
+    /// `http`, `websocket` or `gemini`
+    pub protocol: &'static str,
+    pub client: Option<IpAddr>,
+    /// `None` for Gemini, which has no methods
+    pub method: Option<String>,
+    /// Path and query for HTTP, the full URL for Gemini
+    pub target: String,
+    pub version: &'static str,
+    pub status: u16,
+    pub bytes: u64,
+    pub encoding: Option<String>,
+    pub latency: Duration,
+    pub referer: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+impl Entry {
+    /// The request side of an HTTP entry. Behind a local reverse proxy the
+    /// client is the last `X-Forwarded-For` hop, which the proxy appended.
+    pub fn http(req: &Request<Body>, protocol: &'static str) -> Self {
+        let header = |name| {
+            req.headers()
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_string)
+        };
+        let peer = req.extensions().get::<PeerAddr>().map(|PeerAddr(addr)| addr.ip());
+        let forwarded = req
+            .headers()
+            .get("x-forwarded-for")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.rsplit(',').next())
+            .and_then(|hop| hop.trim().parse::<IpAddr>().ok());
+        let client = match (peer, forwarded) {
+            (Some(peer), Some(forwarded)) if peer.is_loopback() => Some(forwarded),
+            (peer, _) => peer,
+        };
+
+        Self {
+            protocol,
+            client,
+            method: Some(req.method().to_string()),
+            target: req
+                .uri()
+                .path_and_query()
+                .map_or_else(|| req.uri().path().to_string(), |pq| pq.as_str().to_string()),
+            version: match req.version() {
+                Version::HTTP_09 => "HTTP/0.9",
+                Version::HTTP_10 => "HTTP/1.0",
+                Version::HTTP_2 => "HTTP/2.0",
+                Version::HTTP_3 => "HTTP/3.0",
+                _ => "HTTP/1.1",
+            },
+            status: 0,
+            bytes: 0,
+            encoding: None,
+            latency: Duration::ZERO,
+            referer: header(header::REFERER),
+            user_agent: header(header::USER_AGENT),
+        }
+    }
+
+    pub fn gemini(peer: SocketAddr, url: &str) -> Self {
+        Self {
+            protocol: "gemini",
+            client: Some(peer.ip()),
+            method: None,
+            target: url.to_string(),
+            version: "gemini",
+            status: 0,
+            bytes: 0,
+            encoding: None,
+            latency: Duration::ZERO,
+            referer: None,
+            user_agent: None,
+        }
+    }
+}
+
+/// Start logging to `destination`, replacing (and closing) any previous
+/// log. `None` turns logging off.
+pub fn configure(config: Option<(Format, bool, Destination)>) -> io::Result<()> {
+    let logger = match config {
+        Some((format, anonymize, destination)) => {
+            let dropped = Arc::new(AtomicU64::new(0));
+            let (sender, writer) = spawn_writer(destination, Arc::clone(&dropped))?;
+            Some(Logger { format, anonymize, sender, dropped, writer })
+        }
+        None => None,
+    };
+    *LOGGER.write() = logger;
+    Ok(())
+}
+
+/// `configure` with the `[access_log]` settings: without a format nothing
+/// is logged; the log goes to stdout unless `file` names a file in the
+/// state directory.
+pub fn configure_from(config: &Config) -> io::Result<()> {
+    let Some(format) = config.access_log.format else {
+        return configure(None);
+    };
+    let destination = match (&config.access_log.file, &config.state_directory) {
+        (Some(file), Some(state_dir)) => Destination::File {
+            path: state_dir.join(file),
+            max_bytes: config.access_log.max_bytes,
+            keep: config.access_log.keep,
+        },
+        // Config validation rejects this; never fall back to stdout quietly.
+        (Some(file), None) => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("access_log.file = {:?} needs state_directory", file),
+            ))
+        }
+        (None, _) => Destination::Stdout,
+    };
+    configure(Some((format, config.access_log.anonymize, destination)))
+}
+
+/// Stop logging and wait for the writer to flush what's queued, so lines
+/// logged during shutdown aren't lost when the process exits.
+pub fn close() {
+    let Some(logger) = LOGGER.write().take() else {
+        return;
+    };
+    drop(logger.sender);
+    let _ = logger.writer.join();
+}
+
+/// Whether requests are being logged, so callers can skip building entries.
+pub fn enabled() -> bool {
+    LOGGER.read().is_some()
+}
+
+pub fn log(entry: Entry) {
+    let logger = LOGGER.read();
+    let Some(logger) = logger.as_ref() else {
+        return;
+    };
+    let client = entry
+        .client
+        .map(|ip| if logger.anonymize { anonymize(ip) } else { ip });
+    let line = format_entry(&entry, client, logger.format, SystemTime::now());
+    enqueue(&logger.sender, &logger.dropped, line);
+}
+
+/// Queue `line` for the writer without blocking, counting it in `dropped`
+/// if the queue is full.
+fn enqueue(sender: &SyncSender<String>, dropped: &AtomicU64, line: String) {
+    match sender.try_send(line) {
+        Err(TrySendError::Full(_)) => {
+            dropped.fetch_add(1, Ordering::Relaxed);
+        }
+        // Disconnected only once the writer has exited after an I/O error
+        // it reported.
+        Ok(()) | Err(TrySendError::Disconnected(_)) => {}
+    }
+}
+
+/// Zero the host part: the last octet of IPv4, everything after the /48
+/// of IPv6.
+fn anonymize(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(v4) => {
+            let [a, b, c, _] = v4.octets();
+            IpAddr::from([a, b, c, 0])
+        }
+        IpAddr::V6(v6) => {
+            let mut octets = v6.octets();
+            octets[6..].fill(0);
+            IpAddr::from(octets)
+        }
+    }
+}
+
+fn format_entry(entry: &Entry, client: Option<IpAddr>, format: Format, now: SystemTime) -> String {
+    let secs = now
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    let days = (secs / 86400) as i64;
+    let (year, month, day) = date::civil_from_days(days);
+    let (hour, min, sec) = (secs % 86400 / 3600, secs % 3600 / 60, secs % 60);
+    let latency_ms = entry.latency.as_secs_f64() * 1000.0;
+
+    if format == Format::Json {
+        let line = json!({
+            "time": format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z", year, month, day, hour, min, sec),
+            "protocol": entry.protocol,
+            "client": client.map(|ip| ip.to_string()),
+            "method": entry.method,
+            "target": entry.target,
+            "version": entry.version,
+            "status": entry.status,
+            "bytes": entry.bytes,
+            "encoding": entry.encoding,
+            "latency_ms": (latency_ms * 1000.0).round() / 1000.0,
+            "referer": entry.referer,
+            "user_agent": entry.user_agent,
+        });
+        return line.to_string();
+    }
+
+    let request_line = match &entry.method {
+        Some(method) => format!("{} {} {}", method, entry.target, entry.version),
+        None => entry.target.clone(),
+    };
+    let mut line = format!(
+        "{} - - [{:02}/{}/{:04}:{:02}:{:02}:{:02} +0000] \"{}\" {} {}",
+        client.map_or_else(|| "-".to_string(), |ip| ip.to_string()),
+        day,
+        date::MONTHS[(month - 1) as usize],
+        year,
+        hour,
+        min,
+        sec,
+        escape(&request_line),
+        entry.status,
+        if entry.bytes == 0 { "-".to_string() } else { entry.bytes.to_string() },
+    );
+    if format == Format::Combined {
+        let quoted = |value: &Option<String>| value.as_deref().map_or("-".to_string(), escape);
+        line.push_str(&format!(
+            " \"{}\" \"{}\" \"{}\" {:.3}",
+            quoted(&entry.referer),
+            quoted(&entry.user_agent),
+            entry.encoding.as_deref().unwrap_or("-"),
+            latency_ms
+        ));
+    }
+    line
+}
+
+/// Quotes, backslashes and control characters as `\xHH`, so a request
+/// can't forge log lines.
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            c if c == '"' || c == '\\' || c.is_control() => {
+                escaped.push_str(&format!("\\x{:02x}", c as u32))
+            }
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn spawn_writer(
+    destination: Destination,
+    dropped: Arc<AtomicU64>,
+) -> io::Result<(SyncSender<String>, JoinHandle<()>)> {
+    let mut sink = Sink::open(destination)?;
+    let (sender, receiver) = mpsc::sync_channel::<String>(QUEUE_LINES);
+    let report_dropped = move || {
+        let count = dropped.swap(0, Ordering::Relaxed);
+        if count > 0 {
+            eprintln!("Access log queue full, dropped {} line(s)", count);
+        }
+    };
+    let writer = std::thread::Builder::new()
+        .name("access-log".to_string())
+        .spawn(move || {
+            // Ends once every sender is gone, i.e. the log was replaced.
+            while let Ok(line) = receiver.recv() {
+                let mut result = sink.write_line(&line);
+                while let (Ok(()), Ok(line)) = (&result, receiver.try_recv()) {
+                    result = sink.write_line(&line);
+                }
+                report_dropped();
+                if let Err(e) = result.and_then(|()| sink.flush()) {
+                    eprintln!("Access log write failed, logging stopped: {}", e);
+                    return;
+                }
+            }
+            report_dropped();
+            let _ = sink.flush();
+        })?;
+    Ok((sender, writer))
+}
+
+enum Sink {
+    Stdout(io::Stdout),
+    File {
+        writer: BufWriter<File>,
+        path: PathBuf,
+        size: u64,
+        max_bytes: u64,
+        keep: usize,
+    },
+}
+
+impl Sink {
+    fn open(destination: Destination) -> io::Result<Self> {
+        Ok(match destination {
+            Destination::Stdout => Self::Stdout(io::stdout()),
+            Destination::File { path, max_bytes, keep } => {
+                let file = OpenOptions::new().create(true).append(true).open(&path)?;
+                let size = file.metadata()?.len();
+                Self::File { writer: BufWriter::new(file), path, size, max_bytes, keep }
+            }
+        })
+    }
+
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        match self {
+            Self::Stdout(stdout) => writeln!(stdout.lock(), "{}", line),
+            Self::File { writer, path, size, max_bytes, keep } => {
+                let len = line.len() as u64 + 1;
+                if *size > 0 && *size + len > *max_bytes {
+                    writer.flush()?;
+                    rotate(path, *keep)?;
+                    let file = OpenOptions::new().create(true).append(true).open(&*path)?;
+                    *writer = BufWriter::new(file);
+                    *size = 0;
+                }
+                writeln!(writer, "{}", line)?;
+                *size += len;
+                Ok(())
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Stdout(stdout) => stdout.flush(),
+            Self::File { writer, .. } => writer.flush(),
+        }
+    }
+}
+
+/// `log` → `log.1` → … → `log.{keep}`, dropping the oldest. With
+/// `keep == 0` the log just starts over.
+fn rotate(path: &PathBuf, keep: usize) -> io::Result<()> {
+    let numbered = |n: usize| {
+        let mut name = path.clone().into_os_string();
+        name.push(format!(".{}", n));
+        PathBuf::from(name)
+    };
+    if keep == 0 {
+        return fs::remove_file(path);
+    }
+    let _ = fs::remove_file(numbered(keep));
+    for n in (1..keep).rev() {
+        let from = numbered(n);
+        if from.exists() {
+            fs::rename(&from, numbered(n + 1))?;
+        }
+    }
+    fs::rename(path, numbered(1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+
+    // 2024-05-01T08:20:30Z
+    const NOW: u64 = 1714551630;
+
+    fn entry() -> Entry {
+        Entry {
+            protocol: "http",
+            client: Some(IpAddr::from([192, 0, 2, 77])),
+            method: Some("GET".to_string()),
+            target: "/posts/?page=2".to_string(),
+            version: "HTTP/1.1",
+            status: 200,
+            bytes: 1234,
+            encoding: Some("br".to_string()),
+            latency: Duration::from_micros(123_456),
+            referer: Some("https://example.org/".to_string()),
+            user_agent: Some("curl/8.0 \"test\"".to_string()),
+        }
+    }
+
+    fn format(entry: &Entry, format: Format) -> String {
+        format_entry(entry, entry.client, format, SystemTime::UNIX_EPOCH + Duration::from_secs(NOW))
+    }
+
+    /// A fresh, empty directory for rotation tests.
+    fn log_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("access-log-test-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn common_log_format() {
+        assert_eq!(
+            format(&entry(), Format::Common),
+            "192.0.2.77 - - [01/May/2024:08:20:30 +0000] \"GET /posts/?page=2 HTTP/1.1\" 200 1234",
+        );
+        let peer = "[2001:db8::1]:1965".parse().unwrap();
+        let gemini = Entry { status: 51, ..Entry::gemini(peer, "gemini://x/") };
+        assert_eq!(
+            format(&gemini, Format::Common),
+            "2001:db8::1 - - [01/May/2024:08:20:30 +0000] \"gemini://x/\" 51 -",
+        );
+    }
+
+    #[test]
+    fn combined_log_format() {
+        assert_eq!(
+            format(&entry(), Format::Combined),
+            "192.0.2.77 - - [01/May/2024:08:20:30 +0000] \"GET /posts/?page=2 HTTP/1.1\" 200 1234 \
+            \"https://example.org/\" \"curl/8.0 \\x22test\\x22\" \"br\" 123.456",
+        );
+        let bare = Entry { client: None, referer: None, user_agent: None, encoding: None, ..entry() };
+        assert!(format(&bare, Format::Combined).starts_with("- - - ["));
+        assert!(format(&bare, Format::Combined).ends_with(" 1234 \"-\" \"-\" \"-\" 123.456"));
+    }
+
+    #[test]
+    fn json_lines() {
+        let line = format(&entry(), Format::Json);
+        assert!(!line.contains('\n'));
+        assert_eq!(
+            serde_json::from_str::<Value>(&line).unwrap(),
+            json!({
+                "time": "2024-05-01T08:20:30Z",
+                "protocol": "http",
+                "client": "192.0.2.77",
+                "method": "GET",
+                "target": "/posts/?page=2",
+                "version": "HTTP/1.1",
+                "status": 200,
+                "bytes": 1234,
+                "encoding": "br",
+                "latency_ms": 123.456,
+                "referer": "https://example.org/",
+                "user_agent": "curl/8.0 \"test\"",
+            }),
+        );
+    }
+
+    #[test]
+    fn requests_cannot_forge_lines() {
+        assert_eq!(escape("a\"b\\c\r\nd\u{7f}é"), "a\\x22b\\x5cc\\x0d\\x0ad\\x7fé");
+
+        let forged = "/ HTTP/1.1\" 200 1\n10.0.0.1 - - [01/Jan/2024:00:00:00 +0000] \"GET /admin";
+        let entry = Entry { target: forged.to_string(), ..entry() };
+        for format_ in [Format::Common, Format::Combined, Format::Json] {
+            let line = format(&entry, format_);
+            assert_eq!(line.lines().count(), 1, "{}", line);
+        }
+        assert!(format(&entry, Format::Common).contains("\"GET / HTTP/1.1\\x22 200 1\\x0a10.0.0.1"));
+    }
+
+    #[test]
+    fn anonymize_keeps_the_network() {
+        assert_eq!(anonymize(IpAddr::from([192, 0, 2, 77])), IpAddr::from([192, 0, 2, 0]));
+        let v6: IpAddr = "2001:db8:abcd:1234:5678::1".parse().unwrap();
+        assert_eq!(anonymize(v6), "2001:db8:abcd::".parse::<IpAddr>().unwrap());
+    }
+
+    #[test]
+    fn full_queue_drops_and_counts_lines() {
+        let (sender, receiver) = mpsc::sync_channel(1);
+        let dropped = AtomicU64::new(0);
+        for line in ["a", "b", "c"] {
+            enqueue(&sender, &dropped, line.to_string());
+        }
+        assert_eq!(dropped.load(Ordering::Relaxed), 2);
+        assert_eq!(receiver.try_recv().as_deref(), Ok("a"));
+
+        drop(receiver);
+        enqueue(&sender, &dropped, "d".to_string());
+        assert_eq!(dropped.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn rotate_shifts_and_drops_the_oldest() {
+        let dir = log_dir("rotate");
+        let path = dir.join("access.log");
+        let read = |name: &str| fs::read_to_string(dir.join(name)).ok();
+        let write = |name: &str, content: &str| fs::write(dir.join(name), content).unwrap();
+
+        // keep = 0: the log starts over.
+        write("access.log", "0");
+        rotate(&path, 0).unwrap();
+        assert_eq!((read("access.log"), read("access.log.1")), (None, None));
+
+        // keep = 1: the previous .1 is replaced.
+        write("access.log", "new");
+        write("access.log.1", "old");
+        rotate(&path, 1).unwrap();
+        assert_eq!((read("access.log"), read("access.log.1")), (None, Some("new".to_string())));
+        assert_eq!(read("access.log.2"), None);
+
+        // keep = 3: everything shifts up and .3 falls off.
+        let names = ["access.log", "access.log.1", "access.log.2", "access.log.3", "access.log.4"];
+        for (name, content) in names.iter().zip(["0", "1", "2", "3"]) {
+            write(name, content);
+        }
+        rotate(&path, 3).unwrap();
+        let files: Vec<Option<String>> = names.iter().map(|name| read(name)).collect();
+        let expected = [None, Some("0"), Some("1"), Some("2"), None];
+        assert_eq!(files, expected.map(|c| c.map(str::to_string)));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn file_sink_rotates_before_exceeding_max_bytes() {
+        let dir = log_dir("sink");
+        let path = dir.join("access.log");
+        let mut sink = Sink::open(Destination::File { path: path.clone(), max_bytes: 12, keep: 1 }).unwrap();
+        for line in ["first", "second", "third"] {
+            sink.write_line(line).unwrap();
+        }
+        sink.flush().unwrap();
+        assert_eq!(fs::read_to_string(dir.join("access.log.1")).unwrap(), "second\n");
+        assert_eq!(fs::read_to_string(&path).unwrap(), "third\n");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
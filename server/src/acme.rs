@@ -1,10 +1,41 @@
-use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+//! TLS certificates: self-signed (Gemini), operator-supplied files, and
+//! certificates issued by an ACME CA (HTTPS, optionally Gemini).
+
+use parking_lot::RwLock;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
 use rustls::ServerConfig;
+use std::collections::HashMap;
 use std::fs;
 use std::io::Cursor;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+mod client;
+mod jws;
+mod x509;
+
+use client::{ChallengeResponder, Client};
+
+pub const LETS_ENCRYPT_DIRECTORY: &str = "https://acme-v02.api.letsencrypt.org/directory";
+
+/// ALPN protocol a CA uses to ask for a TLS-ALPN-01 challenge cert (RFC 8737).
+pub const ACME_TLS_ALPN: &[u8] = b"acme-tls/1";
 
+// Let's Encrypt certs last 90 days; renewing with 30 left leaves three weeks
+// of daily retries if the CA or our challenge path is broken.
+const RENEW_BEFORE: Duration = Duration::from_secs(30 * 24 * 3600);
+const CHECK_INTERVAL: Duration = Duration::from_secs(12 * 3600);
+const RETRY_INTERVAL: Duration = Duration::from_secs(3600);
+
+lazy_static::lazy_static! {
+    /// Pending HTTP-01 challenges: token -> key authorization.
+    static ref HTTP01_TOKENS: RwLock<HashMap<String, String>> = RwLock::new(HashMap::new());
+}
+
+#[derive(PartialEq)]
 pub struct CertificateData {
     pub cert_pem: String,
     pub privkey_pem: String,
@@ -35,8 +66,7 @@ pub fn load_or_generate_persistent_certificate(
     dir: &Path,
     domain: &str,
 ) -> Result<CertificateData, Box<dyn std::error::Error + Send + Sync>> {
-    let cert_path = dir.join("gemini.crt");
-    let key_path = dir.join("gemini.key");
+    let (cert_path, key_path) = persistent_certificate_paths(dir);
 
     if cert_path.exists() && key_path.exists() {
         let cert_pem = fs::read_to_string(&cert_path)?;
@@ -47,36 +77,82 @@ pub fn load_or_generate_persistent_certificate(
     fs::create_dir_all(dir)?;
     let data = generate_self_signed_certificate(domain)?;
 
-    // Write key with 0600 before cert so a partial failure leaves no key on disk
-    // readable by group/other.
-    write_private(&key_path, &data.privkey_pem)?;
-    fs::write(&cert_path, &data.cert_pem)?;
+    // Key before cert: a half-written pair has no cert and is regenerated.
+    write_atomic(&key_path, &data.privkey_pem, true)?;
+    write_atomic(&cert_path, &data.cert_pem, false)?;
 
     Ok(data)
 }
 
-#[cfg(unix)]
-fn write_private(path: &Path, contents: &str) -> std::io::Result<()> {
-    use std::os::unix::fs::OpenOptionsExt;
+/// Where `load_or_generate_persistent_certificate` keeps its cert and key.
+pub fn persistent_certificate_paths(dir: &Path) -> (PathBuf, PathBuf) {
+    (dir.join("gemini.crt"), dir.join("gemini.key"))
+}
+
+/// Replace `path` via a synced temporary file in the same directory, so a
+/// crash or full disk leaves either the old contents or the new, never a
+/// truncated file. `private` files (keys) are created 0600.
+fn write_atomic(path: &Path, contents: &str, private: bool) -> std::io::Result<()> {
     use std::io::Write;
-    let mut f = fs::OpenOptions::new()
-        .write(true)
-        .create(true)
-        .truncate(true)
-        .mode(0o600)
-        .open(path)?;
-    f.write_all(contents.as_bytes())
+    let mut tmp_name = path.file_name().unwrap_or_default().to_os_string();
+    tmp_name.push(".tmp");
+    let tmp = path.with_file_name(tmp_name);
+    // A leftover from an earlier crash would keep its old mode.
+    let _ = fs::remove_file(&tmp);
+
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    if private {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut f = options.open(&tmp)?;
+    f.write_all(contents.as_bytes())?;
+    f.sync_all()?;
+    fs::rename(&tmp, path)?;
+    // The rename itself is durable once the directory is.
+    #[cfg(unix)]
+    if let Some(dir) = path.parent() {
+        fs::File::open(dir)?.sync_all()?;
+    }
+    Ok(())
 }
 
-#[cfg(not(unix))]
-fn write_private(path: &Path, contents: &str) -> std::io::Result<()> {
-    fs::write(path, contents)
+/// Read a cert chain + private key PEM pair from explicit paths, e.g. files
+/// managed by certbot or another ACME client.
+pub fn load_certificate_files(
+    cert_path: &Path,
+    key_path: &Path,
+) -> Result<CertificateData, Box<dyn std::error::Error + Send + Sync>> {
+    let cert_pem = fs::read_to_string(cert_path)
+        .map_err(|e| format!("{}: {}", cert_path.display(), e))?;
+    let privkey_pem = fs::read_to_string(key_path)
+        .map_err(|e| format!("{}: {}", key_path.display(), e))?;
+    Ok(CertificateData { cert_pem, privkey_pem })
 }
 
 pub fn build_tls_config(
     cert_pem: &str,
     privkey_pem: &str,
 ) -> Result<Arc<ServerConfig>, Box<dyn std::error::Error + Send + Sync>> {
+    Ok(Arc::new(server_config(cert_pem, privkey_pem)?))
+}
+
+/// TLS config whose certificate comes from `store` and can be swapped at
+/// runtime, e.g. after an ACME renewal.
+pub fn build_resolver_tls_config(store: Arc<CertStore>, alpn_protocols: &[&[u8]]) -> Arc<ServerConfig> {
+    let mut config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_cert_resolver(store);
+    config.alpn_protocols = alpn_protocols.iter().map(|p| p.to_vec()).collect();
+    Arc::new(config)
+}
+
+fn server_config(
+    cert_pem: &str,
+    privkey_pem: &str,
+) -> Result<ServerConfig, Box<dyn std::error::Error + Send + Sync>> {
     let mut cert_cursor = Cursor::new(cert_pem.as_bytes());
     let cert_chain: Vec<CertificateDer<'static>> = rustls_pemfile::certs(&mut cert_cursor)
         .collect::<Result<Vec<_>, _>>()?;
@@ -85,18 +161,265 @@ pub fn build_tls_config(
         return Err("No certificates found in PEM".into());
     }
 
+    // Accept PKCS#8, PKCS#1 (RSA) and SEC1 (EC) keys: rcgen writes PKCS#8,
+    // but externally issued certificates often come with the other two.
     let mut key_cursor = Cursor::new(privkey_pem.as_bytes());
-    let mut pkcs8_keys: Vec<PrivatePkcs8KeyDer<'static>> =
-        rustls_pemfile::pkcs8_private_keys(&mut key_cursor)
-            .collect::<Result<Vec<_>, _>>()?;
-    if pkcs8_keys.is_empty() {
-        return Err("No private key found in PEM".into());
-    }
-    let private_key = PrivateKeyDer::Pkcs8(pkcs8_keys.remove(0));
+    let private_key: PrivateKeyDer<'static> = rustls_pemfile::private_key(&mut key_cursor)?
+        .ok_or("No private key found in PEM")?;
 
     let config = ServerConfig::builder()
         .with_no_client_auth()
         .with_single_cert(cert_chain, private_key)?;
 
-    Ok(Arc::new(config))
+    Ok(config)
+}
+
+fn certified_key(data: &CertificateData) -> Result<CertifiedKey, Box<dyn std::error::Error + Send + Sync>> {
+    let cert_chain: Vec<CertificateDer<'static>> = rustls_pemfile::certs(&mut data.cert_pem.as_bytes())
+        .collect::<Result<Vec<_>, _>>()?;
+    if cert_chain.is_empty() {
+        return Err("No certificates found in PEM".into());
+    }
+    let private_key = rustls_pemfile::private_key(&mut data.privkey_pem.as_bytes())?
+        .ok_or("No private key found in PEM")?;
+    let provider = rustls::crypto::aws_lc_rs::default_provider();
+    Ok(CertifiedKey::from_der(cert_chain, private_key, &provider)?)
+}
+
+/// Holds the certificate a TLS listener presents, plus any in-flight
+/// TLS-ALPN-01 challenge certificates. Swapping the certificate takes effect
+/// on the next handshake; open connections are unaffected.
+#[derive(Debug, Default)]
+pub struct CertStore {
+    current: RwLock<Option<Arc<CertifiedKey>>>,
+    alpn_challenges: RwLock<HashMap<String, Arc<CertifiedKey>>>,
+}
+
+impl CertStore {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self::default())
+    }
+
+    pub fn set_certificate(&self, data: &CertificateData) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let key = certified_key(data)?;
+        *self.current.write() = Some(Arc::new(key));
+        Ok(())
+    }
+}
+
+impl ResolvesServerCert for CertStore {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let is_challenge = client_hello
+            .alpn()
+            .map(|mut protocols| protocols.any(|p| p == ACME_TLS_ALPN))
+            .unwrap_or(false);
+        if is_challenge {
+            let name = client_hello.server_name()?;
+            return self.alpn_challenges.read().get(name).cloned();
+        }
+        self.current.read().clone()
+    }
+}
+
+/// Key authorization to answer `GET /.well-known/acme-challenge/<token>`
+/// with, if that token belongs to a challenge in progress.
+pub fn http01_key_authorization(token: &str) -> Option<String> {
+    HTTP01_TOKENS.read().get(token).cloned()
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChallengeType {
+    Http01,
+    TlsAlpn01,
+}
+
+impl ChallengeType {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ChallengeType::Http01 => "http-01",
+            ChallengeType::TlsAlpn01 => "tls-alpn-01",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "http-01" => Some(ChallengeType::Http01),
+            "tls-alpn-01" => Some(ChallengeType::TlsAlpn01),
+            _ => None,
+        }
+    }
+}
+
+pub struct AcmeConfig {
+    pub directory_url: String,
+    pub contact: Option<String>,
+    pub domains: Vec<String>,
+    pub challenge: ChallengeType,
+    /// Account key and issued certificate live in `<state_dir>/acme/`.
+    pub state_dir: PathBuf,
+    /// Extra trust anchor for the directory's TLS cert (Pebble's minica).
+    pub ca_root: Option<PathBuf>,
+}
+
+impl AcmeConfig {
+    fn dir(&self) -> PathBuf {
+        self.state_dir.join("acme")
+    }
+}
+
+/// Load a previously issued certificate into `store`. If there is none yet,
+/// install a self-signed placeholder so the listener can complete
+/// handshakes (and answer TLS-ALPN-01) until the first issuance.
+pub fn load_persisted_certificate(
+    config: &AcmeConfig,
+    store: &CertStore,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let dir = config.dir();
+    if let Ok(data) = load_certificate_files(&dir.join("cert.pem"), &dir.join("key.pem")) {
+        match store.set_certificate(&data) {
+            Ok(()) => return Ok(()),
+            // The manager re-issues: see renew_if_needed.
+            Err(e) => eprintln!("ACME: ignoring the persisted certificate: {}", e),
+        }
+    }
+    let domain = config.domains.first().map(String::as_str).unwrap_or("localhost");
+    store.set_certificate(&generate_self_signed_certificate(domain)?)
+}
+
+/// Issue and renew forever. Runs as a background task; failures are logged
+/// and retried, never fatal, since the current certificate keeps working.
+pub async fn run_manager(config: AcmeConfig, store: Arc<CertStore>) {
+    loop {
+        let wait = match renew_if_needed(&config, &store).await {
+            Ok(wait) => wait,
+            Err(e) => {
+                eprintln!("ACME: {}", e);
+                RETRY_INTERVAL
+            }
+        };
+        tokio::time::sleep(wait).await;
+    }
+}
+
+/// Issue if the persisted cert is missing, expiring, or for other names.
+/// Returns how long to sleep before checking again.
+async fn renew_if_needed(
+    config: &AcmeConfig,
+    store: &CertStore,
+) -> Result<Duration, Box<dyn std::error::Error + Send + Sync>> {
+    let dir = config.dir();
+    let wanted_domains = config.domains.join("\n");
+
+    let remaining = fs::read_to_string(dir.join("domains"))
+        .ok()
+        .filter(|issued_for| *issued_for == wanted_domains)
+        .and_then(|_| fs::read_to_string(dir.join("cert.pem")).ok())
+        .and_then(|pem| client::leaf_der(&pem))
+        .and_then(|der| x509::not_after(&der))
+        .map(|not_after| {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+            Duration::from_secs(not_after.saturating_sub(now))
+        });
+
+    if let Some(remaining) = remaining {
+        if remaining > RENEW_BEFORE {
+            return Ok((remaining - RENEW_BEFORE).min(CHECK_INTERVAL));
+        }
+    }
+
+    fs::create_dir_all(&dir)?;
+    let account_key = load_or_create_account_key(&dir.join("account.key"))?;
+    let ca_root = match &config.ca_root {
+        Some(path) => Some(fs::read_to_string(path)?),
+        None => None,
+    };
+
+    let mut client = Client::new(&config.directory_url, account_key, ca_root.as_deref()).await?;
+    // newAccount is idempotent for a known key, so no need to persist the URL.
+    client.register(config.contact.as_deref()).await?;
+
+    let responder = Responder { store };
+    let data = client.issue(&config.domains, config.challenge, &responder).await?;
+
+    // `domains` vouches for the pair, so it goes first and comes back last:
+    // a crash in between leaves no `domains` and the next check re-issues
+    // instead of trusting a key that may not match its certificate.
+    if let Err(e) = fs::remove_file(dir.join("domains")) {
+        if e.kind() != std::io::ErrorKind::NotFound {
+            return Err(e.into());
+        }
+    }
+    write_atomic(&dir.join("key.pem"), &data.privkey_pem, true)?;
+    write_atomic(&dir.join("cert.pem"), &data.cert_pem, false)?;
+    write_atomic(&dir.join("domains"), &wanted_domains, false)?;
+    store.set_certificate(&data)?;
+
+    println!("ACME: issued certificate for {}", config.domains.join(", "));
+    Ok(CHECK_INTERVAL)
+}
+
+fn load_or_create_account_key(path: &Path) -> Result<jws::AccountKey, Box<dyn std::error::Error + Send + Sync>> {
+    if let Ok(pem) = fs::read_to_string(path) {
+        return match rustls_pemfile::private_key(&mut pem.as_bytes())? {
+            Some(PrivateKeyDer::Pkcs8(der)) => jws::AccountKey::from_pkcs8(der.secret_pkcs8_der()),
+            _ => Err(format!("{}: expected a PKCS#8 private key", path.display()).into()),
+        };
+    }
+    let key = jws::AccountKey::generate()?;
+    write_atomic(path, &key.pkcs8_pem(), true)?;
+    Ok(key)
+}
+
+/// Publishes challenge responses where our listeners will find them.
+struct Responder<'a> {
+    store: &'a CertStore,
+}
+
+impl ChallengeResponder for Responder<'_> {
+    fn publish(
+        &self,
+        kind: ChallengeType,
+        domain: &str,
+        token: &str,
+        key_authorization: &str,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        match kind {
+            ChallengeType::Http01 => {
+                HTTP01_TOKENS
+                    .write()
+                    .insert(token.to_string(), key_authorization.to_string());
+            }
+            ChallengeType::TlsAlpn01 => {
+                // RFC 8737 §3: self-signed cert for the domain carrying the
+                // SHA-256 of the key authorization in a critical extension.
+                let digest = ring::digest::digest(&ring::digest::SHA256, key_authorization.as_bytes());
+                let mut params = rcgen::CertificateParams::new(vec![domain.to_string()])?;
+                params
+                    .custom_extensions
+                    .push(rcgen::CustomExtension::new_acme_identifier(digest.as_ref()));
+                let key_pair = rcgen::KeyPair::generate()?;
+                let cert = params.self_signed(&key_pair)?;
+                let key = certified_key(&CertificateData {
+                    cert_pem: cert.pem(),
+                    privkey_pem: key_pair.serialize_pem(),
+                })?;
+                self.store
+                    .alpn_challenges
+                    .write()
+                    .insert(domain.to_string(), Arc::new(key));
+            }
+        }
+        Ok(())
+    }
+
+    fn withdraw(&self, kind: ChallengeType, domain: &str, token: &str) {
+        match kind {
+            ChallengeType::Http01 => {
+                HTTP01_TOKENS.write().remove(token);
+            }
+            ChallengeType::TlsAlpn01 => {
+                self.store.alpn_challenges.write().remove(domain);
+            }
+        }
+    }
 }
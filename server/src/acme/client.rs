@@ -0,0 +1,414 @@
+//! Minimal ACME v2 client (RFC 8555)
+//!
+//! Covers exactly what a single-host server needs: one account, orders for a
+//! handful of DNS names, HTTP-01 or TLS-ALPN-01 challenges, and certificate
+//! download. Pebble (see DEPLOYMENT.md) is the local test target.
+
+use hyper::{header, Body, Method, Request, StatusCode};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::time::{sleep, timeout, Instant};
+use tokio_rustls::rustls::pki_types::{CertificateDer, ServerName};
+use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+use tokio_rustls::TlsConnector;
+
+use super::jws::AccountKey;
+use super::{CertificateData, ChallengeType};
+
+type Error = Box<dyn std::error::Error + Send + Sync>;
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+// How long to wait for the CA to validate a challenge or finalize an order.
+// Polls start at POLL_INTERVAL and back off to MAX_POLL_INTERVAL, or wait
+// as long as the CA's Retry-After asks within those bounds.
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+const MAX_POLL_INTERVAL: Duration = Duration::from_secs(30);
+const POLL_TIMEOUT: Duration = Duration::from_secs(300);
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Directory {
+    new_nonce: String,
+    new_account: String,
+    new_order: String,
+}
+
+#[derive(Deserialize)]
+struct Order {
+    status: String,
+    authorizations: Vec<String>,
+    finalize: String,
+    certificate: Option<String>,
+    /// Why an `invalid` order failed, as a problem document (RFC 7807).
+    error: Option<Value>,
+}
+
+#[derive(Deserialize)]
+struct Authorization {
+    status: String,
+    identifier: Identifier,
+    challenges: Vec<Challenge>,
+}
+
+#[derive(Deserialize)]
+struct Identifier {
+    value: String,
+}
+
+#[derive(Deserialize)]
+struct Challenge {
+    #[serde(rename = "type")]
+    kind: String,
+    url: String,
+    token: String,
+}
+
+struct HttpResponse {
+    status: StatusCode,
+    headers: hyper::HeaderMap,
+    body: Vec<u8>,
+}
+
+/// Hooks for making a challenge response visible to the CA while it
+/// validates, and withdrawing it afterwards.
+pub trait ChallengeResponder: Sync {
+    fn publish(&self, kind: ChallengeType, domain: &str, token: &str, key_authorization: &str)
+        -> Result<(), Error>;
+    fn withdraw(&self, kind: ChallengeType, domain: &str, token: &str);
+}
+
+pub struct Client {
+    tls: TlsConnector,
+    directory: Directory,
+    key: AccountKey,
+    kid: Option<String>,
+    nonce: Option<String>,
+}
+
+impl Client {
+    /// Fetch the directory. `extra_root_pem` adds a trust anchor on top of
+    /// the web PKI roots, e.g. Pebble's test CA.
+    pub async fn new(
+        directory_url: &str,
+        key: AccountKey,
+        extra_root_pem: Option<&str>,
+    ) -> Result<Self, Error> {
+        let mut roots = RootCertStore {
+            roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+        };
+        if let Some(pem) = extra_root_pem {
+            for cert in rustls_pemfile::certs(&mut pem.as_bytes()) {
+                roots.add(cert?)?;
+            }
+        }
+        let config = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+
+        let mut client = Self {
+            tls: TlsConnector::from(Arc::new(config)),
+            directory: Directory {
+                new_nonce: String::new(),
+                new_account: String::new(),
+                new_order: String::new(),
+            },
+            key,
+            kid: None,
+            nonce: None,
+        };
+
+        let response = client.request(Method::GET, directory_url, None).await?;
+        if !response.status.is_success() {
+            return Err(format!("ACME directory returned {}", response.status).into());
+        }
+        client.directory = serde_json::from_slice(&response.body)?;
+        Ok(client)
+    }
+
+    /// Find or create the account for our key. Returns the account URL,
+    /// which doubles as the `kid` for every later request.
+    pub async fn register(&mut self, contact: Option<&str>) -> Result<String, Error> {
+        let mut payload = json!({ "termsOfServiceAgreed": true });
+        if let Some(email) = contact {
+            payload["contact"] = json!([format!("mailto:{}", email)]);
+        }
+        let url = self.directory.new_account.clone();
+        let response = self.post(&url, Some(&payload)).await?;
+        let kid = location(&response).ok_or("ACME newAccount response has no Location")?;
+        self.kid = Some(kid.clone());
+        Ok(kid)
+    }
+
+    pub fn key_authorization(&self, token: &str) -> String {
+        format!("{}.{}", token, self.key.thumbprint())
+    }
+
+    /// Run a complete order for `domains` and return the issued chain plus
+    /// its freshly generated private key. Fails if the chain is for another
+    /// key, as it can be when the CA reuses an order finalized earlier.
+    pub async fn issue(
+        &mut self,
+        domains: &[String],
+        kind: ChallengeType,
+        responder: &dyn ChallengeResponder,
+    ) -> Result<CertificateData, Error> {
+        let identifiers: Vec<Value> = domains
+            .iter()
+            .map(|d| json!({ "type": "dns", "value": d }))
+            .collect();
+        let url = self.directory.new_order.clone();
+        let response = self.post(&url, Some(&json!({ "identifiers": identifiers }))).await?;
+        let order_url = location(&response).ok_or("ACME newOrder response has no Location")?;
+        let order: Order = serde_json::from_slice(&response.body)?;
+
+        for authz_url in &order.authorizations {
+            self.authorize(authz_url, kind, responder).await?;
+        }
+
+        // The certificate key never leaves this process except via the state
+        // directory, so a fresh one per issuance costs nothing.
+        let key_pair = rcgen::KeyPair::generate()?;
+        let params = rcgen::CertificateParams::new(domains.to_vec())?;
+        let csr = params.serialize_request(&key_pair)?;
+        let csr_b64 = base64::Engine::encode(
+            &base64::engine::general_purpose::URL_SAFE_NO_PAD,
+            csr.der(),
+        );
+        // Finalizing is only allowed once the order is `ready` (RFC 8555
+        // §7.4); the CA may take a moment to notice the last authorization.
+        // A reused order can also come back already finalized.
+        let order = self.poll(&order_url, |o: &Order| o.status != "pending").await?;
+        match order.status.as_str() {
+            "ready" => {
+                self.post(&order.finalize, Some(&json!({ "csr": csr_b64 }))).await?;
+            }
+            "processing" | "valid" => {}
+            _ => expect_status(&order, "ready")?,
+        }
+
+        let order = self
+            .poll(&order_url, |o: &Order| o.status != "processing" && o.status != "ready")
+            .await?;
+        expect_status(&order, "valid")?;
+        let cert_url = order.certificate.ok_or("ACME order is valid but has no certificate URL")?;
+        let response = self.post(&cert_url, None).await?;
+
+        let data = CertificateData {
+            cert_pem: String::from_utf8(response.body)?,
+            privkey_pem: key_pair.serialize_pem(),
+        };
+        super::certified_key(&data).map_err(|e| format!("ACME certificate doesn't match our key: {}", e))?;
+        Ok(data)
+    }
+
+    async fn authorize(
+        &mut self,
+        authz_url: &str,
+        kind: ChallengeType,
+        responder: &dyn ChallengeResponder,
+    ) -> Result<(), Error> {
+        let response = self.post(authz_url, None).await?;
+        let authz: Authorization = serde_json::from_slice(&response.body)?;
+        if authz.status == "valid" {
+            return Ok(());
+        }
+
+        let domain = authz.identifier.value;
+        let challenge = authz
+            .challenges
+            .into_iter()
+            .find(|c| c.kind == kind.as_str())
+            .ok_or_else(|| format!("CA offers no {} challenge for {}", kind.as_str(), domain))?;
+
+        let key_authorization = self.key_authorization(&challenge.token);
+        responder.publish(kind, &domain, &challenge.token, &key_authorization)?;
+
+        // Always withdraw, even when validation fails.
+        let result = async {
+            self.post(&challenge.url, Some(&json!({}))).await?;
+            self.poll(authz_url, |a: &Authorization| a.status != "pending").await
+        }
+        .await;
+        responder.withdraw(kind, &domain, &challenge.token);
+
+        let authz = result?;
+        if authz.status != "valid" {
+            return Err(format!("ACME authorization for {} is '{}'", domain, authz.status).into());
+        }
+        Ok(())
+    }
+
+    /// POST-as-GET `url` until `done` returns true.
+    async fn poll<T, F>(&mut self, url: &str, done: F) -> Result<T, Error>
+    where
+        T: for<'de> Deserialize<'de>,
+        F: Fn(&T) -> bool,
+    {
+        let deadline = Instant::now() + POLL_TIMEOUT;
+        let mut backoff = POLL_INTERVAL;
+        loop {
+            let response = self.post(url, None).await?;
+            let value: T = serde_json::from_slice(&response.body)?;
+            if done(&value) {
+                return Ok(value);
+            }
+            let wait = retry_after(&response)
+                .unwrap_or(backoff)
+                .clamp(POLL_INTERVAL, MAX_POLL_INTERVAL);
+            backoff = (backoff * 2).min(MAX_POLL_INTERVAL);
+            if Instant::now() + wait > deadline {
+                return Err(format!("Timed out waiting for {}", url).into());
+            }
+            sleep(wait).await;
+        }
+    }
+
+    /// Signed POST. `payload: None` is a POST-as-GET (RFC 8555 §6.3).
+    async fn post(&mut self, url: &str, payload: Option<&Value>) -> Result<HttpResponse, Error> {
+        // One retry on badNonce, which servers may return at any time.
+        for attempt in 0..2 {
+            let nonce = match self.nonce.take() {
+                Some(n) => n,
+                None => self.fresh_nonce().await?,
+            };
+            let mut protected = json!({ "alg": "ES256", "nonce": nonce, "url": url });
+            match &self.kid {
+                Some(kid) => protected["kid"] = json!(kid),
+                None => protected["jwk"] = self.key.jwk(),
+            }
+            let body = serde_json::to_vec(&self.key.sign(&protected, payload)?)?;
+            let response = self.request(Method::POST, url, Some(body)).await?;
+
+            if response.status.is_success() {
+                return Ok(response);
+            }
+            let problem: Value = serde_json::from_slice(&response.body).unwrap_or(Value::Null);
+            let kind = problem["type"].as_str().unwrap_or("");
+            if kind == "urn:ietf:params:acme:error:badNonce" && attempt == 0 {
+                continue;
+            }
+            return Err(format!("ACME request to {} failed ({}): {}", url, response.status, describe(&problem)).into());
+        }
+        unreachable!()
+    }
+
+    async fn fresh_nonce(&mut self) -> Result<String, Error> {
+        let url = self.directory.new_nonce.clone();
+        let response = self.request(Method::HEAD, &url, None).await?;
+        self.nonce
+            .take()
+            .or_else(|| replay_nonce(&response))
+            .ok_or_else(|| "ACME newNonce returned no Replay-Nonce".into())
+    }
+
+    /// One HTTPS request on a fresh connection. ACME traffic is a few dozen
+    /// requests per renewal, so pooling isn't worth the code.
+    async fn request(
+        &mut self,
+        method: Method,
+        url: &str,
+        body: Option<Vec<u8>>,
+    ) -> Result<HttpResponse, Error> {
+        let uri: hyper::Uri = url.parse()?;
+        if uri.scheme_str() != Some("https") {
+            return Err(format!("ACME URLs must be https: {}", url).into());
+        }
+        let host = uri.host().ok_or("ACME URL has no host")?.to_string();
+        let port = uri.port_u16().unwrap_or(443);
+
+        let response = timeout(REQUEST_TIMEOUT, async {
+            let tcp = TcpStream::connect((host.as_str(), port)).await?;
+            let server_name = ServerName::try_from(host.clone())?;
+            let tls = self.tls.connect(server_name, tcp).await?;
+            let (mut sender, conn) = hyper::client::conn::handshake(tls).await?;
+            tokio::spawn(async move {
+                let _ = conn.await;
+            });
+
+            let path = uri.path_and_query().map(|p| p.as_str()).unwrap_or("/");
+            let mut req = Request::builder()
+                .method(method)
+                .uri(path)
+                .header(header::HOST, &host)
+                .header(header::USER_AGENT, "static-server-acme");
+            let req = match body {
+                Some(b) => {
+                    req = req.header(header::CONTENT_TYPE, "application/jose+json");
+                    req.body(Body::from(b))?
+                }
+                None => req.body(Body::empty())?,
+            };
+
+            let response = sender.send_request(req).await?;
+            let (parts, body) = response.into_parts();
+            let body = hyper::body::to_bytes(body).await?;
+            Ok::<_, Error>(HttpResponse {
+                status: parts.status,
+                headers: parts.headers,
+                body: body.to_vec(),
+            })
+        })
+        .await
+        .map_err(|_| format!("ACME request to {} timed out", url))?
+        .map_err(|e| format!("ACME request to {} failed: {}", url, e))?;
+
+        if let Some(nonce) = replay_nonce(&response) {
+            self.nonce = Some(nonce);
+        }
+        Ok(response)
+    }
+}
+
+/// An error unless `order` has reached `expected`, with the CA's reason if
+/// it went `invalid` instead.
+fn expect_status(order: &Order, expected: &str) -> Result<(), Error> {
+    match order.status.as_str() {
+        status if status == expected => Ok(()),
+        "invalid" => Err(format!(
+            "ACME order is invalid: {}",
+            order.error.as_ref().map(describe).unwrap_or_default()
+        )
+        .into()),
+        status => Err(format!("ACME order is '{}', expected '{}'", status, expected).into()),
+    }
+}
+
+/// `type detail` of an ACME problem document.
+fn describe(problem: &Value) -> String {
+    format!(
+        "{} {}",
+        problem["type"].as_str().unwrap_or(""),
+        problem["detail"].as_str().unwrap_or("")
+    )
+}
+
+/// `Retry-After` in delta-seconds, the form ACME CAs send while an
+/// order or authorization is still being processed.
+fn retry_after(response: &HttpResponse) -> Option<Duration> {
+    let value = response.headers.get(header::RETRY_AFTER)?.to_str().ok()?;
+    value.trim().parse().ok().map(Duration::from_secs)
+}
+
+fn replay_nonce(response: &HttpResponse) -> Option<String> {
+    response
+        .headers
+        .get("Replay-Nonce")
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string)
+}
+
+fn location(response: &HttpResponse) -> Option<String> {
+    response
+        .headers
+        .get(header::LOCATION)
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string)
+}
+
+/// DER of the first (leaf) certificate in a PEM chain.
+pub fn leaf_der(cert_pem: &str) -> Option<CertificateDer<'static>> {
+    rustls_pemfile::certs(&mut cert_pem.as_bytes()).next()?.ok()
+}
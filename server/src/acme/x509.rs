@@ -0,0 +1,137 @@
+//! Just enough DER to read a certificate's notAfter
+//!
+//! Pulling in a full X.509 parser for one timestamp isn't worth the binary
+//! size; the path to `validity` is fixed by RFC 5280 §4.1.
+
+use crate::date::days_from_civil;
+
+const TAG_SEQUENCE: u8 = 0x30;
+const TAG_UTC_TIME: u8 = 0x17;
+const TAG_GENERALIZED_TIME: u8 = 0x18;
+const TAG_EXPLICIT_0: u8 = 0xa0;
+
+/// Read one TLV, returning (tag, contents, rest).
+fn read_tlv(input: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, input) = input.split_first()?;
+    let (&first, mut input) = input.split_first()?;
+    let len = if first < 0x80 {
+        first as usize
+    } else {
+        let n = (first & 0x7f) as usize;
+        if n == 0 || n > 4 || input.len() < n {
+            return None;
+        }
+        let mut len = 0usize;
+        for &b in &input[..n] {
+            len = (len << 8) | b as usize;
+        }
+        input = &input[n..];
+        len
+    };
+    if input.len() < len {
+        return None;
+    }
+    Some((tag, &input[..len], &input[len..]))
+}
+
+/// notAfter of a DER certificate, as seconds since the Unix epoch.
+pub fn not_after(cert_der: &[u8]) -> Option<u64> {
+    // Certificate ::= SEQUENCE { tbsCertificate, signatureAlgorithm, signature }
+    let (tag, cert, _) = read_tlv(cert_der)?;
+    if tag != TAG_SEQUENCE {
+        return None;
+    }
+    let (tag, tbs, _) = read_tlv(cert)?;
+    if tag != TAG_SEQUENCE {
+        return None;
+    }
+
+    // TBSCertificate ::= SEQUENCE { [0] version OPTIONAL, serialNumber,
+    //     signature, issuer, validity, ... }
+    let mut rest = tbs;
+    let (tag, _, after) = read_tlv(rest)?;
+    if tag == TAG_EXPLICIT_0 {
+        rest = after;
+    }
+    for _ in 0..3 {
+        let (_, _, after) = read_tlv(rest)?;
+        rest = after;
+    }
+    let (tag, validity, _) = read_tlv(rest)?;
+    if tag != TAG_SEQUENCE {
+        return None;
+    }
+
+    // Validity ::= SEQUENCE { notBefore Time, notAfter Time }
+    let (_, _, rest) = read_tlv(validity)?;
+    let (tag, time, _) = read_tlv(rest)?;
+    let time = std::str::from_utf8(time).ok()?;
+    match tag {
+        TAG_UTC_TIME => parse_time(time, false),
+        TAG_GENERALIZED_TIME => parse_time(time, true),
+        _ => None,
+    }
+}
+
+/// UTCTime is `YYMMDDHHMMSSZ`, GeneralizedTime is `YYYYMMDDHHMMSSZ`.
+fn parse_time(s: &str, four_digit_year: bool) -> Option<u64> {
+    let s = s.strip_suffix('Z')?;
+    if !s.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let (year, rest) = if four_digit_year {
+        (s.get(..4)?.parse::<i64>().ok()?, s.get(4..)?)
+    } else {
+        // RFC 5280: YY >= 50 is 19YY, otherwise 20YY.
+        let yy = s.get(..2)?.parse::<i64>().ok()?;
+        (if yy >= 50 { 1900 + yy } else { 2000 + yy }, s.get(2..)?)
+    };
+    if rest.len() != 10 {
+        return None;
+    }
+    let field = |i: usize| rest[i..i + 2].parse::<i64>().ok();
+    let (month, day) = (field(0)?, field(2)?);
+    let (hour, min, sec) = (field(4)?, field(6)?, field(8)?);
+
+    let days = days_from_civil(year, month, day);
+    let secs = days * 86400 + hour * 3600 + min * 60 + sec;
+    u64::try_from(secs).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cert_expiring(year: i32, month: u8, day: u8) -> Vec<u8> {
+        let mut params = rcgen::CertificateParams::new(vec!["example.org".to_string()]).unwrap();
+        params.not_after = rcgen::date_time_ymd(year, month, day);
+        let key = rcgen::KeyPair::generate().unwrap();
+        params.self_signed(&key).unwrap().der().to_vec()
+    }
+
+    #[test]
+    fn reads_not_after() {
+        // Encoded as UTCTime before 2050 and GeneralizedTime from then on.
+        assert_eq!(not_after(&cert_expiring(2031, 5, 17)), Some(1936742400));
+        assert_eq!(not_after(&cert_expiring(2051, 1, 2)), Some(2556230400));
+    }
+
+    #[test]
+    fn rejects_truncated_and_foreign_der() {
+        let der = cert_expiring(2031, 5, 17);
+        assert_eq!(not_after(&der[..der.len() / 2]), None);
+        assert_eq!(not_after(&[]), None);
+        assert_eq!(not_after(&[0x02, 0x01, 0x00]), None);
+    }
+
+    #[test]
+    fn times() {
+        assert_eq!(parse_time("491231235959Z", false), Some(2524607999));
+        assert_eq!(parse_time("20510102030405Z", true), Some(2556241445));
+        // Two-digit years from 50 on are 19YY, which is before the epoch.
+        assert_eq!(parse_time("500101000000Z", false), None);
+        assert_eq!(parse_time("491231235959", false), None);
+        assert_eq!(parse_time("4912312359Z", false), None);
+        assert_eq!(parse_time("49123123595aZ", false), None);
+    }
+}
@@ -0,0 +1,55 @@
+// Empty assets - run 'hugo --minify' first
+#![allow(dead_code)]
+use std::collections::HashMap;
+
+#[derive(Clone)]
+pub struct Asset {
+    pub content_raw: &'static [u8],
+    pub content_gzip: Option<&'static [u8]>,
+    pub content_brotli: Option<&'static [u8]>,
+    pub content_zstd: Option<&'static [u8]>,
+    pub content_type: &'static str,
+    pub etag: &'static str,
+    /// ETags of the compressed bodies, set with the matching `content_*`
+    pub etag_gzip: Option<&'static str>,
+    pub etag_brotli: Option<&'static str>,
+    pub etag_zstd: Option<&'static str>,
+    pub is_compressible: bool,
+    /// Hash-based Content-Security-Policy for HTML pages
+    pub csp: Option<&'static str>,
+    /// `<html lang>` of HTML pages
+    pub content_language: Option<&'static str>,
+    /// (hreflang, path) of every language version of this page,
+    /// default language first; empty if it exists in one language
+    pub alternates: &'static [(&'static str, &'static str)],
+    /// Per-theme bodies of this page, by `theme` cookie value
+    pub theme_variants: &'static [(&'static str, &'static Asset)],
+    /// `Link` preload header for HTML pages (also sent as 103 Early Hints)
+    pub preload: Option<&'static str>,
+    /// Modification time, Unix seconds (Hugo lastmod, git or mtime)
+    pub last_modified: Option<u64>,
+}
+
+#[derive(Clone)]
+pub struct GeminiAsset {
+    pub content: &'static [u8],
+    pub content_type: &'static str,
+    pub etag: &'static str,
+}
+
+#[derive(Clone, Copy)]
+pub struct Redirect {
+    pub from: &'static str,
+    pub to: &'static str,
+    pub status: u16,
+}
+
+pub fn get_routes() -> HashMap<&'static str, &'static Asset> {
+    HashMap::new()
+}
+pub fn get_gemini_routes() -> HashMap<&'static str, &'static GeminiAsset> {
+    HashMap::new()
+}
+pub fn get_redirects() -> Vec<Redirect> {
+    Vec::new()
+}
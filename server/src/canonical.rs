@@ -0,0 +1,106 @@
+//! Canonical URL enforcement
+//!
+//! The router and the Gemini handler are lenient: `/posts`, `/posts/`,
+//! `/posts/index.html` and `//posts` all find the same page. With
+//! canonicalization on, every such variant is redirected to the one form the
+//! page is actually stored under, so search engines see a single URL.
+//!
+//! Off by default; set `canonical_urls = true` in the config file (or
+//! override it with `CANONICAL_URLS=true` in the environment) to turn it on.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+pub fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// The canonical form of `path`, if it differs from `path` and names
+/// something that `exists`. Duplicate slashes are collapsed, a trailing
+/// `index_file` is stripped, and the trailing slash is added or removed to
+/// match the stored route. Unknown paths yield `None` and are left to the
+/// usual 404 and redirect handling.
+pub fn canonicalize(path: &str, index_file: &str, exists: impl Fn(&str) -> bool) -> Option<String> {
+    let mut candidate = String::with_capacity(path.len() + 1);
+    for (i, segment) in path.split('/').enumerate() {
+        if i > 0 && segment.is_empty() {
+            continue;
+        }
+        if i > 0 {
+            candidate.push('/');
+        }
+        candidate.push_str(segment);
+    }
+    if path.ends_with('/') && !candidate.ends_with('/') {
+        candidate.push('/');
+    }
+    if candidate.is_empty() {
+        candidate.push('/');
+    }
+
+    if let Some(dir) = candidate.strip_suffix(index_file).filter(|d| d.ends_with('/')) {
+        candidate.truncate(dir.len());
+    }
+
+    let canonical = if exists(&candidate) {
+        candidate
+    } else if candidate.len() > 1 && candidate.ends_with('/') {
+        let without_slash = &candidate[..candidate.len() - 1];
+        exists(without_slash).then(|| without_slash.to_string())?
+    } else {
+        let with_slash = format!("{}/", candidate);
+        exists(&with_slash).then_some(with_slash)?
+    };
+
+    (canonical != path).then_some(canonical)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ROUTES: &[&str] = &["/", "/posts/", "/about", "/style.css"];
+
+    fn canonical(path: &str) -> Option<String> {
+        canonicalize(path, "index.html", |p| ROUTES.contains(&p))
+    }
+
+    #[test]
+    fn canonical_paths_are_left_alone() {
+        for path in ROUTES {
+            assert_eq!(canonical(path), None, "{}", path);
+        }
+    }
+
+    #[test]
+    fn index_file_is_stripped() {
+        assert_eq!(canonical("/index.html").as_deref(), Some("/"));
+        assert_eq!(canonical("/posts/index.html").as_deref(), Some("/posts/"));
+        // Only a whole trailing segment counts.
+        assert_eq!(canonical("/postsindex.html"), None);
+    }
+
+    #[test]
+    fn trailing_slash_follows_the_stored_route() {
+        assert_eq!(canonical("/posts").as_deref(), Some("/posts/"));
+        assert_eq!(canonical("/about/").as_deref(), Some("/about"));
+    }
+
+    #[test]
+    fn duplicate_slashes_are_collapsed() {
+        assert_eq!(canonical("//posts").as_deref(), Some("/posts/"));
+        assert_eq!(canonical("/posts//index.html").as_deref(), Some("/posts/"));
+        assert_eq!(canonical("//").as_deref(), Some("/"));
+    }
+
+    #[test]
+    fn unknown_paths_are_left_to_the_router() {
+        assert_eq!(canonical("/missing"), None);
+        assert_eq!(canonical("/missing/index.html"), None);
+    }
+}
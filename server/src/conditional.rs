@@ -0,0 +1,201 @@
+//! Conditional requests (RFC 9110 §13)
+//!
+//! Preconditions are evaluated in the order §13.2.2 prescribes: If-Match,
+//! else If-Unmodified-Since; then If-None-Match, else If-Modified-Since.
+//! If-Range is left to the range code, which asks `if_range_matches`.
+//! Dates only count when the header parses and the asset has a time.
+
+use hyper::header::{self, HeaderMap};
+use hyper::Method;
+
+use crate::date::parse_http_date;
+
+#[derive(Debug, PartialEq)]
+pub enum Outcome {
+    /// Serve the representation (or the requested range).
+    Proceed,
+    NotModified,
+    PreconditionFailed,
+}
+
+/// `etags` are the quoted ETags of every representation of the resource;
+/// a client validator matching any of them counts.
+pub fn evaluate(
+    method: &Method,
+    headers: &HeaderMap,
+    etags: &[String],
+    last_modified: Option<u64>,
+) -> Outcome {
+    let header = |name| headers.get(name).and_then(|v| v.to_str().ok());
+    let date = |name| header(name).and_then(parse_http_date);
+
+    // Step 1/2: the client's copy must still be current.
+    if let Some(if_match) = header(header::IF_MATCH) {
+        if !etag_list_matches(if_match, etags, false) {
+            return Outcome::PreconditionFailed;
+        }
+    } else if let (Some(since), Some(modified)) = (date(header::IF_UNMODIFIED_SINCE), last_modified) {
+        if modified > since {
+            return Outcome::PreconditionFailed;
+        }
+    }
+
+    // Step 3/4: the client's copy may be reused.
+    let is_read = method == Method::GET || method == Method::HEAD;
+    if let Some(if_none_match) = header(header::IF_NONE_MATCH) {
+        if etag_list_matches(if_none_match, etags, true) {
+            return if is_read { Outcome::NotModified } else { Outcome::PreconditionFailed };
+        }
+    } else if let (true, Some(since), Some(modified)) =
+        (is_read, date(header::IF_MODIFIED_SINCE), last_modified)
+    {
+        if modified <= since {
+            return Outcome::NotModified;
+        }
+    }
+    Outcome::Proceed
+}
+
+/// If-Range holds an entity tag (strong comparison) or the exact
+/// Last-Modified date. Absent header means the Range applies
+/// unconditionally.
+pub fn if_range_matches(headers: &HeaderMap, etag: &str, last_modified: Option<u64>) -> bool {
+    let Some(value) = headers.get(header::IF_RANGE) else {
+        return true;
+    };
+    let Ok(value) = value.to_str() else {
+        return false;
+    };
+    let value = value.trim();
+    if value.starts_with('"') || value.starts_with("W/") {
+        return !value.starts_with("W/") && value == etag;
+    }
+    parse_http_date(value).is_some_and(|date| Some(date) == last_modified)
+}
+
+/// Whether `*` or any tag in a comma-separated list matches one of
+/// `etags`. Weak comparison ignores the `W/` prefix on either side; strong
+/// comparison never matches a weak tag.
+fn etag_list_matches(list: &str, etags: &[String], weak: bool) -> bool {
+    if list.trim() == "*" {
+        return true;
+    }
+    let opaque = |tag: &str| tag.strip_prefix("W/").unwrap_or(tag).to_string();
+    list.split(',').map(str::trim).filter(|t| !t.is_empty()).any(|candidate| {
+        etags.iter().any(|etag| {
+            if weak {
+                opaque(candidate) == opaque(etag)
+            } else {
+                !candidate.starts_with("W/") && !etag.starts_with("W/") && candidate == etag
+            }
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::header::{HeaderName, HeaderValue};
+
+    const ETAG: &str = "\"abc\"";
+    // Sun, 06 Nov 1994 08:49:37 GMT
+    const MODIFIED: u64 = 784111777;
+    const AT: &str = "Sun, 06 Nov 1994 08:49:37 GMT";
+    const BEFORE: &str = "Sun, 06 Nov 1994 08:49:36 GMT";
+
+    fn headers(pairs: &[(HeaderName, &str)]) -> HeaderMap {
+        let mut map = HeaderMap::new();
+        for (name, value) in pairs {
+            map.insert(name.clone(), HeaderValue::from_str(value).unwrap());
+        }
+        map
+    }
+
+    fn outcome(method: Method, pairs: &[(HeaderName, &str)], etags: &[&str], last: Option<u64>) -> Outcome {
+        let etags: Vec<String> = etags.iter().map(|e| e.to_string()).collect();
+        evaluate(&method, &headers(pairs), &etags, last)
+    }
+
+    fn get(pairs: &[(HeaderName, &str)]) -> Outcome {
+        outcome(Method::GET, pairs, &[ETAG], Some(MODIFIED))
+    }
+
+    fn if_range(value: &str, modified: Option<u64>) -> bool {
+        if_range_matches(&headers(&[(header::IF_RANGE, value)]), ETAG, modified)
+    }
+
+    #[test]
+    fn no_preconditions() {
+        assert_eq!(get(&[]), Outcome::Proceed);
+    }
+
+    #[test]
+    fn if_none_match_uses_weak_comparison() {
+        assert_eq!(get(&[(header::IF_NONE_MATCH, "\"abc\"")]), Outcome::NotModified);
+        assert_eq!(get(&[(header::IF_NONE_MATCH, "W/\"abc\"")]), Outcome::NotModified);
+        assert_eq!(get(&[(header::IF_NONE_MATCH, "\"x\", \"abc\"")]), Outcome::NotModified);
+        assert_eq!(get(&[(header::IF_NONE_MATCH, "*")]), Outcome::NotModified);
+        assert_eq!(get(&[(header::IF_NONE_MATCH, "\"x\"")]), Outcome::Proceed);
+        let post = outcome(Method::POST, &[(header::IF_NONE_MATCH, "*")], &[ETAG], None);
+        assert_eq!(post, Outcome::PreconditionFailed);
+    }
+
+    #[test]
+    fn if_match_uses_strong_comparison() {
+        assert_eq!(get(&[(header::IF_MATCH, "\"abc\"")]), Outcome::Proceed);
+        assert_eq!(get(&[(header::IF_MATCH, "*")]), Outcome::Proceed);
+        assert_eq!(get(&[(header::IF_MATCH, "W/\"abc\"")]), Outcome::PreconditionFailed);
+        assert_eq!(get(&[(header::IF_MATCH, "\"x\"")]), Outcome::PreconditionFailed);
+        let weak = "W/\"abc\"";
+        let weak_only = outcome(Method::GET, &[(header::IF_MATCH, weak)], &[weak], None);
+        assert_eq!(weak_only, Outcome::PreconditionFailed);
+    }
+
+    #[test]
+    fn dates() {
+        assert_eq!(get(&[(header::IF_MODIFIED_SINCE, AT)]), Outcome::NotModified);
+        assert_eq!(get(&[(header::IF_MODIFIED_SINCE, BEFORE)]), Outcome::Proceed);
+        assert_eq!(get(&[(header::IF_MODIFIED_SINCE, "yesterday")]), Outcome::Proceed);
+        assert_eq!(get(&[(header::IF_UNMODIFIED_SINCE, AT)]), Outcome::Proceed);
+        assert_eq!(get(&[(header::IF_UNMODIFIED_SINCE, BEFORE)]), Outcome::PreconditionFailed);
+        // Without a modification time, dates can't be compared.
+        assert_eq!(outcome(Method::GET, &[(header::IF_MODIFIED_SINCE, AT)], &[], None), Outcome::Proceed);
+    }
+
+    #[test]
+    fn etags_take_precedence_over_dates() {
+        // A changed ETag wins over a date that says nothing changed...
+        let changed = [(header::IF_NONE_MATCH, "\"x\""), (header::IF_MODIFIED_SINCE, AT)];
+        assert_eq!(get(&changed), Outcome::Proceed);
+        // ...and a matching one over a date that says it did.
+        let unchanged = [(header::IF_MATCH, ETAG), (header::IF_UNMODIFIED_SINCE, BEFORE)];
+        assert_eq!(get(&unchanged), Outcome::Proceed);
+    }
+
+    #[test]
+    fn any_encoding_etag_validates() {
+        // A client that cached the gzip body still holds a valid copy when
+        // the server would now send brotli.
+        let etags = ["\"abc\"", "\"abc-gz\"", "\"abc-br\""];
+        let cached = |name: HeaderName, value| outcome(Method::GET, &[(name, value)], &etags, None);
+        assert_eq!(cached(header::IF_NONE_MATCH, "\"abc-gz\""), Outcome::NotModified);
+        assert_eq!(cached(header::IF_NONE_MATCH, "W/\"abc-br\""), Outcome::NotModified);
+        assert_eq!(cached(header::IF_NONE_MATCH, "\"abc-zstd\""), Outcome::Proceed);
+        assert_eq!(cached(header::IF_MATCH, "\"abc-br\""), Outcome::Proceed);
+        assert_eq!(cached(header::IF_MATCH, "\"abc-zstd\""), Outcome::PreconditionFailed);
+    }
+
+    #[test]
+    fn if_range_falls_back_to_full_body() {
+        assert!(if_range_matches(&HeaderMap::new(), ETAG, None));
+        assert!(if_range(ETAG, Some(MODIFIED)));
+        assert!(!if_range("\"x\"", Some(MODIFIED)));
+        // Weak tags never match: the range must come from identical bytes.
+        assert!(!if_range("W/\"abc\"", Some(MODIFIED)));
+        assert!(if_range(AT, Some(MODIFIED)));
+        assert!(!if_range(BEFORE, Some(MODIFIED)));
+        assert!(!if_range(AT, None));
+        // Anything else can't vouch for the client's copy: full body.
+        assert!(!if_range("garbage", Some(MODIFIED)));
+    }
+}
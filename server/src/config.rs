@@ -0,0 +1,945 @@
+//! Server configuration
+//!
+//! One typed TOML file holds every setting: bind addresses, limits,
+//! timeouts and feature toggles. It's optional; without it the defaults
+//! below apply. Every key can also come from the environment, named after
+//! its path in upper case with `.` as `_` (`gemini.max_per_ip` →
+//! `GEMINI_MAX_PER_IP`), which wins over the file. The variables from
+//! before the file existed (`PORT`, `ENABLE_GEMINI`, `TLS_CERT_PATH`, …)
+//! still work. Durations are whole seconds.
+//!
+//! ```toml
+//! domain = "example.org"
+//!
+//! [http]
+//! listen = ["127.0.0.1:8080", "[::1]:8080"]
+//!
+//! [gemini]
+//! max_per_ip = 8
+//! ```
+//!
+//! Loading reports every problem at once, each with the file position or
+//! variable it came from, so `--check-config` can list them all.
+
+use parking_lot::RwLock;
+use std::collections::HashMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use toml_edit::{DocumentMut, ImDocument, Item, TableLike, Value};
+
+use crate::access_log;
+use crate::acme::{self, ChallengeType};
+use crate::headers::HeaderPolicy;
+
+lazy_static::lazy_static! {
+    static ref CONFIG: RwLock<Arc<Config>> = RwLock::new(Arc::new(Config::default()));
+}
+
+pub struct Config {
+    /// Fallback host for redirects, name in the Gemini certificate.
+    pub domain: String,
+    /// Persistent state: Gemini certificate, ACME account, access log.
+    pub state_directory: Option<PathBuf>,
+    /// Header policy file (see `headers`); the built-in policy without it.
+    pub headers_file: Option<PathBuf>,
+    pub canonical_urls: bool,
+    pub http: Http,
+    pub https: Https,
+    pub acme: Acme,
+    pub gemini: Gemini,
+    pub websocket: WebSocket,
+    pub access_log: AccessLog,
+    pub shutdown: Shutdown,
+}
+
+pub struct Http {
+    pub listen: Vec<SocketAddr>,
+}
+
+/// Enabled by `cert` and `key` or by `acme.enabled`.
+pub struct Https {
+    pub listen: Vec<SocketAddr>,
+    pub cert: Option<PathBuf>,
+    pub key: Option<PathBuf>,
+    pub handshake_timeout: Duration,
+    /// Connections across all HTTPS listeners, handshakes included.
+    pub max_concurrent: usize,
+    /// Redirect plain HTTP on `redirect_listen` to HTTPS.
+    pub redirect: bool,
+    pub redirect_listen: Vec<SocketAddr>,
+}
+
+pub struct Acme {
+    pub enabled: bool,
+    pub directory: String,
+    pub contact: Option<String>,
+    /// Names on the certificate; `[domain]` if left empty.
+    pub domains: Vec<String>,
+    pub challenge: ChallengeType,
+    pub ca_root: Option<PathBuf>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum GeminiCert {
+    /// Generated once and kept in `state_directory`, for TOFU clients.
+    SelfSigned,
+    /// The HTTPS certificate from ACME.
+    Acme,
+}
+
+pub struct Gemini {
+    pub enabled: bool,
+    pub listen: Vec<SocketAddr>,
+    pub cert: GeminiCert,
+    pub max_concurrent: usize,
+    pub max_per_ip: usize,
+    pub handshake_timeout: Duration,
+    /// Budget for reading a whole request.
+    pub request_timeout: Duration,
+    /// Budget for each read of a request.
+    pub request_chunk_timeout: Duration,
+    /// How long a transfer may go on once shutdown starts.
+    pub shutdown_deadline: Duration,
+    pub not_found_suggestions: bool,
+    /// Log dropped connections and failed handshakes.
+    pub debug: bool,
+}
+
+pub struct WebSocket {
+    pub max_clients: usize,
+    pub ping_interval: Duration,
+    pub pong_deadline: Duration,
+    pub send_timeout: Duration,
+    /// How long to wait for the client's Close on shutdown.
+    pub close_timeout: Duration,
+}
+
+pub struct AccessLog {
+    /// `None` turns the log off.
+    pub format: Option<access_log::Format>,
+    pub anonymize: bool,
+    /// File in `state_directory`; stdout without one.
+    pub file: Option<String>,
+    pub max_bytes: u64,
+    pub keep: usize,
+}
+
+pub struct Shutdown {
+    /// How long to drain connections, comfortably inside systemd's 90s
+    /// stop timeout by default.
+    pub timeout: Duration,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            domain: "localhost".to_string(),
+            state_directory: None,
+            headers_file: None,
+            canonical_urls: false,
+            http: Http { listen: vec![SocketAddr::from(([127, 0, 0, 1], 8080))] },
+            https: Https {
+                listen: vec![SocketAddr::from(([0, 0, 0, 0], 443))],
+                cert: None,
+                key: None,
+                handshake_timeout: Duration::from_secs(10),
+                max_concurrent: 1024,
+                redirect: true,
+                redirect_listen: vec![SocketAddr::from(([0, 0, 0, 0], 80))],
+            },
+            acme: Acme {
+                enabled: false,
+                directory: acme::LETS_ENCRYPT_DIRECTORY.to_string(),
+                contact: None,
+                domains: Vec::new(),
+                challenge: ChallengeType::TlsAlpn01,
+                ca_root: None,
+            },
+            gemini: Gemini {
+                enabled: true,
+                listen: vec![SocketAddr::from(([0, 0, 0, 0], 1965))],
+                cert: GeminiCert::SelfSigned,
+                max_concurrent: 256,
+                max_per_ip: 4,
+                handshake_timeout: Duration::from_secs(10),
+                // A well-behaved client sends ~50 bytes in one segment.
+                request_timeout: Duration::from_secs(5),
+                request_chunk_timeout: Duration::from_secs(1),
+                shutdown_deadline: Duration::from_secs(10),
+                not_found_suggestions: false,
+                debug: false,
+            },
+            websocket: WebSocket {
+                max_clients: 64,
+                ping_interval: Duration::from_secs(30),
+                pong_deadline: Duration::from_secs(60),
+                send_timeout: Duration::from_secs(10),
+                close_timeout: Duration::from_secs(2),
+            },
+            access_log: AccessLog {
+                format: None,
+                anonymize: false,
+                file: None,
+                max_bytes: 10 * 1024 * 1024,
+                keep: 5,
+            },
+            shutdown: Shutdown { timeout: Duration::from_secs(30) },
+        }
+    }
+}
+
+impl Config {
+    pub fn https_enabled(&self) -> bool {
+        self.https.cert.is_some() || self.acme.enabled
+    }
+
+    /// Settings for the ACME manager; only meaningful with `acme.enabled`,
+    /// which validation ties to a state directory.
+    pub fn acme_config(&self) -> acme::AcmeConfig {
+        acme::AcmeConfig {
+            directory_url: self.acme.directory.clone(),
+            contact: self.acme.contact.clone(),
+            domains: self.acme.domains.clone(),
+            challenge: self.acme.challenge,
+            state_dir: self.state_directory.clone().unwrap_or_default(),
+            ca_root: self.acme.ca_root.clone(),
+        }
+    }
+
+    /// The effective configuration as TOML, in the layout the file uses.
+    pub fn to_toml(&self) -> String {
+        let mut doc = DocumentMut::new();
+        for key in KEYS {
+            let Some(value) = (key.get)(self) else {
+                continue;
+            };
+            match key.path.split_once('.') {
+                Some((section, name)) => {
+                    let table = doc.entry(section).or_insert_with(toml_edit::table);
+                    if let Some(table) = table.as_table_mut() {
+                        table.insert(name, toml_edit::value(value));
+                    }
+                }
+                None => {
+                    doc.insert(key.path, toml_edit::value(value));
+                }
+            }
+        }
+        doc.to_string()
+    }
+}
+
+/// The configuration currently in effect.
+pub fn current() -> Arc<Config> {
+    Arc::clone(&CONFIG.read())
+}
+
+pub fn set(config: Config) {
+    *CONFIG.write() = Arc::new(config);
+}
+
+/// A problem with one setting.
+pub struct ConfigError {
+    /// `file:line:column` or `env NAME`; `None` for a default value.
+    pub location: Option<String>,
+    /// Dotted path of the setting, empty for a file that doesn't parse.
+    pub key: String,
+    pub message: String,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(location) = &self.location {
+            write!(f, "{}: ", location)?;
+        }
+        if !self.key.is_empty() {
+            write!(f, "{}: ", self.key)?;
+        }
+        f.write_str(&self.message)
+    }
+}
+
+/// Defaults, then the file at `path` if given, then the environment.
+pub fn load(path: Option<&Path>) -> Result<Config, Vec<ConfigError>> {
+    let mut loader = Loader { config: Config::default(), errors: Vec::new(), origins: HashMap::new() };
+    if let Some(path) = path {
+        match std::fs::read_to_string(path) {
+            Ok(source) => loader.file(path, &source),
+            Err(e) => loader.errors.push(ConfigError {
+                location: Some(path.display().to_string()),
+                key: String::new(),
+                message: format!("can't read: {}", e),
+            }),
+        }
+    }
+    loader.env(|name| std::env::var(name).ok());
+    loader.validate();
+    if loader.errors.is_empty() {
+        Ok(loader.config)
+    } else {
+        Err(loader.errors)
+    }
+}
+
+/// `load`, plus the header policy `headers_file` points to, whose problems
+/// count as config errors.
+pub fn load_with_headers(path: Option<&Path>) -> Result<(Config, Option<HeaderPolicy>), Vec<ConfigError>> {
+    let config = load(path)?;
+    let Some(file) = &config.headers_file else {
+        return Ok((config, None));
+    };
+    let policy = std::fs::read_to_string(file)
+        .map_err(|e| format!("can't read: {}", e))
+        .and_then(|source| HeaderPolicy::from_toml(&source))
+        .map_err(|message| {
+            vec![ConfigError {
+                location: Some(file.display().to_string()),
+                key: "headers_file".to_string(),
+                message,
+            }]
+        })?;
+    Ok((config, Some(policy)))
+}
+
+/// Settings that differ between `old` and `new`, with the new value as it
+/// would appear in the file.
+pub fn changes(old: &Config, new: &Config) -> Vec<(&'static str, String)> {
+    KEYS.iter()
+        .filter_map(|key| {
+            let before = (key.get)(old).map(|v| v.to_string());
+            let after = (key.get)(new).map(|v| v.to_string());
+            (before != after).then(|| (key.path, after.unwrap_or_else(|| "unset".to_string())))
+        })
+        .collect()
+}
+
+/// A setting as found in the file or in a variable.
+#[derive(Clone, Copy)]
+enum Raw<'a> {
+    Toml(&'a Item),
+    Env(&'a str),
+}
+
+impl<'a> Raw<'a> {
+    fn string(self) -> Result<String, String> {
+        match self {
+            Raw::Toml(item) => item.as_str().map(str::to_string).ok_or_else(|| expected("a string", item)),
+            Raw::Env(value) => Ok(value.to_string()),
+        }
+    }
+
+    fn bool(self) -> Result<bool, String> {
+        match self {
+            Raw::Toml(item) => item.as_bool().ok_or_else(|| expected("true or false", item)),
+            Raw::Env(value) => value.parse().map_err(|_| format!("expected true or false, found {:?}", value)),
+        }
+    }
+
+    fn integer(self, min: i64) -> Result<i64, String> {
+        let n = match self {
+            Raw::Toml(item) => item.as_integer().ok_or_else(|| expected("an integer", item))?,
+            Raw::Env(value) => value.parse().map_err(|_| format!("expected an integer, found {:?}", value))?,
+        };
+        if n < min {
+            return Err(format!("must be at least {}", min));
+        }
+        Ok(n)
+    }
+
+    /// A string or an array of them; comma-separated in a variable.
+    fn list(self) -> Result<Vec<String>, String> {
+        match self {
+            Raw::Toml(item) => {
+                if let Some(s) = item.as_str() {
+                    return Ok(vec![s.to_string()]);
+                }
+                let array = item.as_array().ok_or_else(|| expected("an array of strings", item))?;
+                array
+                    .iter()
+                    .map(|v| v.as_str().map(str::to_string))
+                    .collect::<Option<_>>()
+                    .ok_or_else(|| "expected an array of strings".to_string())
+            }
+            Raw::Env(value) => {
+                Ok(value.split(',').map(str::trim).filter(|s| !s.is_empty()).map(str::to_string).collect())
+            }
+        }
+    }
+
+    fn path(self) -> Result<Option<PathBuf>, String> {
+        Ok(Some(self.string()?).filter(|s| !s.is_empty()).map(PathBuf::from))
+    }
+
+    fn count(self) -> Result<usize, String> {
+        Ok(self.integer(1)? as usize)
+    }
+
+    fn secs(self) -> Result<Duration, String> {
+        Ok(Duration::from_secs(self.integer(1)? as u64))
+    }
+
+    fn addrs(self) -> Result<Vec<SocketAddr>, String> {
+        self.list()?
+            .iter()
+            .map(|a| a.parse().map_err(|_| format!("{:?} is not an address like 0.0.0.0:80 or [::]:80", a)))
+            .collect()
+    }
+}
+
+fn expected(what: &str, item: &Item) -> String {
+    format!("expected {}, found {}", what, item.type_name())
+}
+
+fn addrs_value(addrs: &[SocketAddr]) -> Value {
+    Value::Array(addrs.iter().map(|a| a.to_string()).collect())
+}
+
+fn secs_value(duration: Duration) -> Value {
+    Value::from(duration.as_secs() as i64)
+}
+
+fn path_value(path: &Option<PathBuf>) -> Option<Value> {
+    path.as_ref().map(|p| Value::from(p.display().to_string()))
+}
+
+/// A setting: where it lives in the file, how to store it, how to show it.
+struct Key {
+    path: &'static str,
+    set: fn(&mut Config, Raw) -> Result<(), String>,
+    get: fn(&Config) -> Option<Value>,
+}
+
+const KEYS: &[Key] = &[
+    Key {
+        path: "domain",
+        set: |c, raw| raw.string().map(|v| c.domain = v),
+        get: |c| Some(c.domain.as_str().into()),
+    },
+    Key {
+        path: "state_directory",
+        set: |c, raw| raw.path().map(|v| c.state_directory = v),
+        get: |c| path_value(&c.state_directory),
+    },
+    Key {
+        path: "headers_file",
+        set: |c, raw| raw.path().map(|v| c.headers_file = v),
+        get: |c| path_value(&c.headers_file),
+    },
+    Key {
+        path: "canonical_urls",
+        set: |c, raw| raw.bool().map(|v| c.canonical_urls = v),
+        get: |c| Some(c.canonical_urls.into()),
+    },
+    Key {
+        path: "http.listen",
+        set: |c, raw| raw.addrs().map(|v| c.http.listen = v),
+        get: |c| Some(addrs_value(&c.http.listen)),
+    },
+    Key {
+        path: "https.listen",
+        set: |c, raw| raw.addrs().map(|v| c.https.listen = v),
+        get: |c| Some(addrs_value(&c.https.listen)),
+    },
+    Key {
+        path: "https.cert",
+        set: |c, raw| raw.path().map(|v| c.https.cert = v),
+        get: |c| path_value(&c.https.cert),
+    },
+    Key {
+        path: "https.key",
+        set: |c, raw| raw.path().map(|v| c.https.key = v),
+        get: |c| path_value(&c.https.key),
+    },
+    Key {
+        path: "https.handshake_timeout",
+        set: |c, raw| raw.secs().map(|v| c.https.handshake_timeout = v),
+        get: |c| Some(secs_value(c.https.handshake_timeout)),
+    },
+    Key {
+        path: "https.max_concurrent",
+        set: |c, raw| raw.count().map(|v| c.https.max_concurrent = v),
+        get: |c| Some((c.https.max_concurrent as i64).into()),
+    },
+    Key {
+        path: "https.redirect",
+        set: |c, raw| raw.bool().map(|v| c.https.redirect = v),
+        get: |c| Some(c.https.redirect.into()),
+    },
+    Key {
+        path: "https.redirect_listen",
+        set: |c, raw| raw.addrs().map(|v| c.https.redirect_listen = v),
+        get: |c| Some(addrs_value(&c.https.redirect_listen)),
+    },
+    Key {
+        path: "acme.enabled",
+        set: |c, raw| raw.bool().map(|v| c.acme.enabled = v),
+        get: |c| Some(c.acme.enabled.into()),
+    },
+    Key {
+        path: "acme.directory",
+        set: |c, raw| raw.string().map(|v| c.acme.directory = v),
+        get: |c| Some(c.acme.directory.as_str().into()),
+    },
+    Key {
+        path: "acme.contact",
+        set: |c, raw| raw.string().map(|v| c.acme.contact = Some(v).filter(|v| !v.is_empty())),
+        get: |c| c.acme.contact.as_deref().map(Value::from),
+    },
+    Key {
+        path: "acme.domains",
+        set: |c, raw| raw.list().map(|v| c.acme.domains = v),
+        get: |c| Some(Value::Array(c.acme.domains.iter().collect())),
+    },
+    Key {
+        path: "acme.challenge",
+        set: |c, raw| {
+            let value = raw.string()?;
+            c.acme.challenge = ChallengeType::parse(&value)
+                .ok_or_else(|| format!("expected \"http-01\" or \"tls-alpn-01\", found {:?}", value))?;
+            Ok(())
+        },
+        get: |c| Some(c.acme.challenge.as_str().into()),
+    },
+    Key {
+        path: "acme.ca_root",
+        set: |c, raw| raw.path().map(|v| c.acme.ca_root = v),
+        get: |c| path_value(&c.acme.ca_root),
+    },
+    Key {
+        path: "gemini.enabled",
+        set: |c, raw| raw.bool().map(|v| c.gemini.enabled = v),
+        get: |c| Some(c.gemini.enabled.into()),
+    },
+    Key {
+        path: "gemini.listen",
+        set: |c, raw| raw.addrs().map(|v| c.gemini.listen = v),
+        get: |c| Some(addrs_value(&c.gemini.listen)),
+    },
+    Key {
+        path: "gemini.cert",
+        set: |c, raw| {
+            c.gemini.cert = match raw.string()?.as_str() {
+                "self-signed" => GeminiCert::SelfSigned,
+                "acme" => GeminiCert::Acme,
+                other => return Err(format!("expected \"self-signed\" or \"acme\", found {:?}", other)),
+            };
+            Ok(())
+        },
+        get: |c| {
+            Some(match c.gemini.cert {
+                GeminiCert::SelfSigned => "self-signed".into(),
+                GeminiCert::Acme => "acme".into(),
+            })
+        },
+    },
+    Key {
+        path: "gemini.max_concurrent",
+        set: |c, raw| raw.count().map(|v| c.gemini.max_concurrent = v),
+        get: |c| Some((c.gemini.max_concurrent as i64).into()),
+    },
+    Key {
+        path: "gemini.max_per_ip",
+        set: |c, raw| raw.count().map(|v| c.gemini.max_per_ip = v),
+        get: |c| Some((c.gemini.max_per_ip as i64).into()),
+    },
+    Key {
+        path: "gemini.handshake_timeout",
+        set: |c, raw| raw.secs().map(|v| c.gemini.handshake_timeout = v),
+        get: |c| Some(secs_value(c.gemini.handshake_timeout)),
+    },
+    Key {
+        path: "gemini.request_timeout",
+        set: |c, raw| raw.secs().map(|v| c.gemini.request_timeout = v),
+        get: |c| Some(secs_value(c.gemini.request_timeout)),
+    },
+    Key {
+        path: "gemini.request_chunk_timeout",
+        set: |c, raw| raw.secs().map(|v| c.gemini.request_chunk_timeout = v),
+        get: |c| Some(secs_value(c.gemini.request_chunk_timeout)),
+    },
+    Key {
+        path: "gemini.shutdown_deadline",
+        set: |c, raw| raw.secs().map(|v| c.gemini.shutdown_deadline = v),
+        get: |c| Some(secs_value(c.gemini.shutdown_deadline)),
+    },
+    Key {
+        path: "gemini.not_found_suggestions",
+        set: |c, raw| raw.bool().map(|v| c.gemini.not_found_suggestions = v),
+        get: |c| Some(c.gemini.not_found_suggestions.into()),
+    },
+    Key {
+        path: "gemini.debug",
+        set: |c, raw| raw.bool().map(|v| c.gemini.debug = v),
+        get: |c| Some(c.gemini.debug.into()),
+    },
+    Key {
+        path: "websocket.max_clients",
+        set: |c, raw| raw.count().map(|v| c.websocket.max_clients = v),
+        get: |c| Some((c.websocket.max_clients as i64).into()),
+    },
+    Key {
+        path: "websocket.ping_interval",
+        set: |c, raw| raw.secs().map(|v| c.websocket.ping_interval = v),
+        get: |c| Some(secs_value(c.websocket.ping_interval)),
+    },
+    Key {
+        path: "websocket.pong_deadline",
+        set: |c, raw| raw.secs().map(|v| c.websocket.pong_deadline = v),
+        get: |c| Some(secs_value(c.websocket.pong_deadline)),
+    },
+    Key {
+        path: "websocket.send_timeout",
+        set: |c, raw| raw.secs().map(|v| c.websocket.send_timeout = v),
+        get: |c| Some(secs_value(c.websocket.send_timeout)),
+    },
+    Key {
+        path: "websocket.close_timeout",
+        set: |c, raw| raw.secs().map(|v| c.websocket.close_timeout = v),
+        get: |c| Some(secs_value(c.websocket.close_timeout)),
+    },
+    Key {
+        path: "access_log.format",
+        set: |c, raw| {
+            let value = raw.string()?;
+            c.access_log.format = match value.as_str() {
+                "" | "off" => None,
+                format => Some(access_log::Format::parse(format).ok_or_else(|| {
+                    format!("expected \"common\", \"combined\", \"json\" or \"off\", found {:?}", value)
+                })?),
+            };
+            Ok(())
+        },
+        get: |c| Some(c.access_log.format.map_or("off", access_log::Format::as_str).into()),
+    },
+    Key {
+        path: "access_log.anonymize",
+        set: |c, raw| raw.bool().map(|v| c.access_log.anonymize = v),
+        get: |c| Some(c.access_log.anonymize.into()),
+    },
+    Key {
+        path: "access_log.file",
+        set: |c, raw| raw.string().map(|v| c.access_log.file = Some(v).filter(|v| !v.is_empty())),
+        get: |c| c.access_log.file.as_deref().map(Value::from),
+    },
+    Key {
+        path: "access_log.max_bytes",
+        set: |c, raw| raw.integer(1).map(|v| c.access_log.max_bytes = v as u64),
+        get: |c| Some((c.access_log.max_bytes as i64).into()),
+    },
+    Key {
+        path: "access_log.keep",
+        set: |c, raw| raw.integer(0).map(|v| c.access_log.keep = v as usize),
+        get: |c| Some((c.access_log.keep as i64).into()),
+    },
+    Key {
+        path: "shutdown.timeout",
+        set: |c, raw| raw.secs().map(|v| c.shutdown.timeout = v),
+        get: |c| Some(secs_value(c.shutdown.timeout)),
+    },
+];
+
+/// Variables from before the config file, by the key they set.
+const LEGACY_ENV: &[(&str, &str)] = &[
+    ("ENABLE_GEMINI", "gemini.enabled"),
+    ("DEBUG_GEMINI", "gemini.debug"),
+    ("TLS_CERT_PATH", "https.cert"),
+    ("TLS_KEY_PATH", "https.key"),
+    ("ENABLE_HTTP_REDIRECT", "https.redirect"),
+    ("ENABLE_ACME", "acme.enabled"),
+    ("ACCESS_LOG", "access_log.format"),
+];
+
+/// Variables that change only the port of every address of a listener.
+const PORT_ENV: &[(&str, &str)] =
+    &[("PORT", "http.listen"), ("HTTPS_PORT", "https.listen"), ("HTTP_REDIRECT_PORT", "https.redirect_listen")];
+
+struct Loader {
+    config: Config,
+    errors: Vec<ConfigError>,
+    /// Where each setting that isn't a default came from.
+    origins: HashMap<&'static str, String>,
+}
+
+impl Loader {
+    fn file(&mut self, path: &Path, source: &str) {
+        let name = path.display().to_string();
+        let locate = |offset: usize| {
+            let before = &source[..offset.min(source.len())];
+            let line = before.matches('\n').count() + 1;
+            let column = before.len() - before.rfind('\n').map_or(0, |i| i + 1) + 1;
+            format!("{}:{}:{}", name, line, column)
+        };
+        let doc = match ImDocument::parse(source) {
+            Ok(doc) => doc,
+            Err(e) => {
+                self.errors.push(ConfigError {
+                    location: Some(e.span().map_or_else(|| name.clone(), |span| locate(span.start))),
+                    key: String::new(),
+                    message: e.message().trim_end().replace('\n', ": "),
+                });
+                return;
+            }
+        };
+
+        let mut settings = Vec::new();
+        collect(doc.as_table(), "", &mut settings);
+        for (path, offset, item) in settings {
+            let location = locate(offset);
+            match KEYS.iter().find(|key| key.path == path) {
+                Some(key) => self.set(key, Raw::Toml(item), location),
+                None => self.errors.push(ConfigError {
+                    location: Some(location),
+                    key: path,
+                    message: "unknown setting".to_string(),
+                }),
+            }
+        }
+    }
+
+    /// Overrides from the variables `lookup` finds; empty ones are unset.
+    fn env(&mut self, lookup: impl Fn(&str) -> Option<String>) {
+        let var = |name: &str| lookup(name).filter(|v| !v.is_empty());
+        for (name, path) in LEGACY_ENV {
+            if let (Some(value), Some(key)) = (var(name), KEYS.iter().find(|k| k.path == *path)) {
+                self.set(key, Raw::Env(&value), format!("env {}", name));
+            }
+        }
+        for key in KEYS {
+            let name = key.path.replace('.', "_").to_uppercase();
+            if let Some(value) = var(&name) {
+                self.set(key, Raw::Env(&value), format!("env {}", name));
+            }
+        }
+        for (name, path) in PORT_ENV {
+            let Some(value) = var(name) else {
+                continue;
+            };
+            let location = format!("env {}", name);
+            match value.parse::<u16>() {
+                Ok(port) => {
+                    for addr in self.listen_mut(path) {
+                        addr.set_port(port);
+                    }
+                    self.origins.insert(path, location);
+                }
+                Err(_) => self.errors.push(ConfigError {
+                    location: Some(location),
+                    key: path.to_string(),
+                    message: format!("expected a port number, found {:?}", value),
+                }),
+            }
+        }
+    }
+
+    fn listen_mut(&mut self, path: &str) -> &mut Vec<SocketAddr> {
+        match path {
+            "http.listen" => &mut self.config.http.listen,
+            "https.listen" => &mut self.config.https.listen,
+            "https.redirect_listen" => &mut self.config.https.redirect_listen,
+            _ => &mut self.config.gemini.listen,
+        }
+    }
+
+    fn set(&mut self, key: &Key, raw: Raw, location: String) {
+        match (key.set)(&mut self.config, raw) {
+            Ok(()) => {
+                self.origins.insert(key.path, location);
+            }
+            Err(message) => {
+                self.errors.push(ConfigError { location: Some(location), key: key.path.to_string(), message })
+            }
+        }
+    }
+
+    /// Rules that span settings, checked once everything is in.
+    fn validate(&mut self) {
+        let c = &mut self.config;
+        if c.acme.domains.is_empty() {
+            c.acme.domains = vec![c.domain.clone()];
+        }
+
+        let mut problems = Vec::new();
+        let mut needs_listener = |path: &'static str, listen: &[SocketAddr]| {
+            if listen.is_empty() {
+                problems.push((path, "needs at least one address".to_string()));
+            }
+        };
+        needs_listener("http.listen", &c.http.listen);
+        if c.https_enabled() {
+            needs_listener("https.listen", &c.https.listen);
+            if c.https.redirect {
+                needs_listener("https.redirect_listen", &c.https.redirect_listen);
+            }
+        }
+        if c.gemini.enabled {
+            needs_listener("gemini.listen", &c.gemini.listen);
+        }
+        match (&c.https.cert, &c.https.key) {
+            (Some(_), None) => problems.push(("https.cert", "needs https.key as well".to_string())),
+            (None, Some(_)) => problems.push(("https.key", "needs https.cert as well".to_string())),
+            (Some(_), Some(_)) if c.acme.enabled => problems.push((
+                "acme.enabled",
+                "conflicts with https.cert: the certificate comes from files or from ACME".to_string(),
+            )),
+            _ => {}
+        }
+        if c.acme.enabled && c.state_directory.is_none() {
+            problems.push(("acme.enabled", "needs state_directory for the account key and certificate".to_string()));
+        }
+        if c.gemini.cert == GeminiCert::Acme && !c.acme.enabled {
+            problems.push(("gemini.cert", "\"acme\" needs acme.enabled".to_string()));
+        }
+        if c.access_log.file.is_some() && c.state_directory.is_none() {
+            problems.push(("access_log.file", "needs state_directory".to_string()));
+        }
+        // Pongs are only checked for on pings, so a shorter deadline would
+        // drop every client. Blame whichever of the two was changed.
+        if c.websocket.pong_deadline <= c.websocket.ping_interval {
+            if self.origins.contains_key("websocket.pong_deadline") {
+                problems.push(("websocket.pong_deadline", "must be longer than websocket.ping_interval".to_string()));
+            } else {
+                problems.push(("websocket.ping_interval", "must be shorter than websocket.pong_deadline".to_string()));
+            }
+        }
+
+        for (path, message) in problems {
+            self.errors.push(ConfigError {
+                location: self.origins.get(path).cloned(),
+                key: path.to_string(),
+                message,
+            });
+        }
+    }
+}
+
+/// Every value in `table` with its dotted path and where its key starts.
+/// Sections are tables at the top level; anything deeper is a value as far
+/// as the keys are concerned, so `KEYS` rejects it.
+fn collect<'a>(table: &'a dyn TableLike, prefix: &str, out: &mut Vec<(String, usize, &'a Item)>) {
+    for (name, item) in table.iter() {
+        let path = if prefix.is_empty() { name.to_string() } else { format!("{}.{}", prefix, name) };
+        let offset = table
+            .get_key_value(name)
+            .and_then(|(key, _)| key.span())
+            .or_else(|| item.span())
+            .map_or(0, |span| span.start);
+        match item.as_table_like() {
+            Some(section) if prefix.is_empty() => collect(section, &path, out),
+            _ => out.push((path, offset, item)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PATH: &str = "/srv/server.toml";
+
+    /// `load` with the file's contents and the environment given inline.
+    fn load_from(source: &str, env: &[(&str, &str)]) -> Result<Config, Vec<String>> {
+        let mut loader = Loader { config: Config::default(), errors: Vec::new(), origins: HashMap::new() };
+        loader.file(Path::new(PATH), source);
+        loader.env(|name| env.iter().find(|(n, _)| *n == name).map(|(_, v)| v.to_string()));
+        loader.validate();
+        if loader.errors.is_empty() {
+            Ok(loader.config)
+        } else {
+            Err(loader.errors.iter().map(ToString::to_string).collect())
+        }
+    }
+
+    fn errors(source: &str, env: &[(&str, &str)]) -> Vec<String> {
+        load_from(source, env).err().expect("config should be rejected")
+    }
+
+    #[test]
+    fn empty_file_gives_defaults() {
+        let config = load_from("", &[]).unwrap();
+        assert_eq!(config.domain, "localhost");
+        assert_eq!(config.acme.domains, ["localhost"]);
+        assert_eq!(config.gemini.max_per_ip, 4);
+    }
+
+    #[test]
+    fn file_sets_values() {
+        let source = "domain = \"example.org\"\n\n[http]\nlisten = [\"[::1]:80\"]\n\n[gemini]\nmax_per_ip = 8\n";
+        let config = load_from(source, &[]).unwrap();
+        assert_eq!(config.domain, "example.org");
+        assert_eq!(config.http.listen, ["[::1]:80".parse::<SocketAddr>().unwrap()]);
+        assert_eq!(config.gemini.max_per_ip, 8);
+    }
+
+    #[test]
+    fn errors_name_line_and_key() {
+        let source = "domain = \"example.org\"\n[gemini]\nmax_per_ip = \"lots\"\n  bogus = 1\n";
+        assert_eq!(
+            errors(source, &[]),
+            [
+                "/srv/server.toml:3:1: gemini.max_per_ip: expected an integer, found string",
+                "/srv/server.toml:4:3: gemini.bogus: unknown setting",
+            ]
+        );
+        assert_eq!(
+            errors("[websocket]\nping_interval = 0\n", &[]),
+            ["/srv/server.toml:2:1: websocket.ping_interval: must be at least 1"]
+        );
+    }
+
+    #[test]
+    fn unparseable_file_reports_position() {
+        let errors = errors("domain = \"example.org\"\n[http\n", &[]);
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].starts_with("/srv/server.toml:2:"), "{}", errors[0]);
+    }
+
+    #[test]
+    fn cross_setting_rules_blame_where_the_value_came_from() {
+        assert_eq!(
+            errors("[access_log]\nfile = \"access.log\"\n", &[]),
+            ["/srv/server.toml:2:1: access_log.file: needs state_directory"]
+        );
+        assert_eq!(
+            errors("", &[("WEBSOCKET_PONG_DEADLINE", "30")]),
+            ["env WEBSOCKET_PONG_DEADLINE: websocket.pong_deadline: must be longer than websocket.ping_interval"]
+        );
+        assert_eq!(
+            errors("[websocket]\nping_interval = 90\n", &[]),
+            ["/srv/server.toml:2:1: websocket.ping_interval: must be shorter than websocket.pong_deadline"]
+        );
+    }
+
+    #[test]
+    fn environment_overrides_the_file() {
+        let source = "[gemini]\nmax_per_ip = 2\nenabled = true\n";
+        let env = [("GEMINI_MAX_PER_IP", "8"), ("ENABLE_GEMINI", "false"), ("DOMAIN", "")];
+        let config = load_from(source, &env).unwrap();
+        assert_eq!(config.gemini.max_per_ip, 8);
+        assert!(!config.gemini.enabled);
+        // Empty variables count as unset.
+        assert_eq!(config.domain, "localhost");
+    }
+
+    #[test]
+    fn port_variables_change_only_the_port() {
+        let source = "[http]\nlisten = [\"127.0.0.1:8080\", \"[::1]:8080\"]\n";
+        let config = load_from(source, &[("PORT", "9000")]).unwrap();
+        let expected: Vec<SocketAddr> = vec!["127.0.0.1:9000".parse().unwrap(), "[::1]:9000".parse().unwrap()];
+        assert_eq!(config.http.listen, expected);
+        assert_eq!(
+            errors("", &[("PORT", "http")]),
+            ["env PORT: http.listen: expected a port number, found \"http\""]
+        );
+    }
+
+    #[test]
+    fn changes_lists_new_values() {
+        let old = load_from("", &[]).unwrap();
+        let new = load_from("[gemini]\nmax_per_ip = 8\n", &[]).unwrap();
+        assert_eq!(changes(&old, &new), [("gemini.max_per_ip", "8".to_string())]);
+        assert!(changes(&old, &old).is_empty());
+    }
+}
@@ -0,0 +1,279 @@
+//! 103 Early Hints (RFC 8297) for HTTP/1.1
+//!
+//! hyper 0.14 has no way to send an informational response, so the
+//! connection's IO is wrapped instead: `send` queues a raw 103 head, which
+//! the wrapper writes and flushes the next time hyper polls the connection,
+//! and the request waits for that before producing its final response. So
+//! the 103 reaches the client on its own, ahead of the 200.
+//!
+//! A hint is only queued while the connection is idle, meaning hyper has
+//! flushed everything it wrote so far. Otherwise the 103 could land in the
+//! middle of the previous response on a pipelined connection. HTTP/2 isn't
+//! covered: its interim responses would have to go through the h2 framing.
+
+use hyper::{Body, Request, Version};
+use parking_lot::Mutex;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, Waker};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+#[derive(Default)]
+struct State {
+    pending: Vec<u8>,
+    idle: bool,
+    // The request waiting for its hint to be flushed.
+    waiter: Option<Waker>,
+}
+
+/// Handle for queueing a 103 on one connection, carried in the request
+/// extensions.
+#[derive(Clone)]
+pub struct EarlyHints(Arc<Mutex<State>>);
+
+impl EarlyHints {
+    /// Queue `103 Early Hints` carrying `links` as its `Link` header.
+    /// Returns false if it can't be sent on this connection right now.
+    fn queue(&self, links: &str) -> bool {
+        let mut state = self.0.lock();
+        if !state.idle || !state.pending.is_empty() {
+            return false;
+        }
+        state.pending = format!("HTTP/1.1 103 Early Hints\r\nLink: {}\r\n\r\n", links).into_bytes();
+        true
+    }
+
+    /// Resolves once the queued hint has been written and flushed.
+    async fn flushed(&self) {
+        std::future::poll_fn(|cx| {
+            let mut state = self.0.lock();
+            if state.pending.is_empty() && state.idle {
+                Poll::Ready(())
+            } else {
+                state.waiter = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        })
+        .await
+    }
+}
+
+/// Send early hints for `req` and wait until they're on the wire, if its
+/// connection supports them. Only HTTP/1.1 clients are guaranteed to
+/// understand 1xx responses.
+pub async fn send(req: &Request<Body>, links: &str) -> bool {
+    if req.version() != Version::HTTP_11 {
+        return false;
+    }
+    let Some(hints) = req.extensions().get::<EarlyHints>() else {
+        return false;
+    };
+    if !hints.queue(links) {
+        return false;
+    }
+    hints.flushed().await;
+    true
+}
+
+/// Connection IO that can write a queued 103 ahead of hyper's output.
+pub struct HintedIo<T> {
+    inner: T,
+    state: Arc<Mutex<State>>,
+}
+
+/// Wrap `io` for an HTTP/1 connection, returning the handle that queues
+/// hints on it.
+pub fn wrap<T>(io: T) -> (HintedIo<T>, EarlyHints) {
+    let state = Arc::new(Mutex::new(State { idle: true, ..State::default() }));
+    (HintedIo { inner: io, state: Arc::clone(&state) }, EarlyHints(state))
+}
+
+impl<T: AsyncWrite + Unpin> HintedIo<T> {
+    /// Write out any queued hint. Ready(Ok) once nothing is pending.
+    fn poll_pending(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let mut state = self.state.lock();
+        state.idle = false;
+        while !state.pending.is_empty() {
+            match Pin::new(&mut self.inner).poll_write(cx, &state.pending) {
+                Poll::Ready(Ok(0)) => return Poll::Ready(Err(io::ErrorKind::WriteZero.into())),
+                Poll::Ready(Ok(n)) => {
+                    state.pending.drain(..n);
+                }
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for HintedIo<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for HintedIo<T> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.poll_pending(cx) {
+            Poll::Ready(Ok(())) => Pin::new(&mut self.inner).poll_write(cx, buf),
+            Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        match self.poll_pending(cx) {
+            Poll::Ready(Ok(())) => Pin::new(&mut self.inner).poll_write_vectored(cx, bufs),
+            Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        // A hint queued since the last write still has to go out first.
+        match self.poll_pending(cx) {
+            Poll::Ready(Ok(())) => {}
+            other => return other,
+        }
+        let result = Pin::new(&mut self.inner).poll_flush(cx);
+        // hyper only flushes the IO once its own buffer is empty, so a
+        // finished flush means everything written so far is on the wire.
+        // hyper also flushes while a request is being handled, which is
+        // when a freshly queued hint goes out.
+        if let Poll::Ready(Ok(())) = result {
+            let mut state = self.state.lock();
+            state.idle = true;
+            if let Some(waiter) = state.waiter.take() {
+                waiter.wake();
+            }
+        }
+        result
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::server::conn::Http;
+    use hyper::service::service_fn;
+    use hyper::Response;
+    use std::convert::Infallible;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+
+    const LINK: &str = "</app.css>; rel=preload; as=style";
+
+    /// The server end of a test connection, logging what reaches it.
+    struct LoggedIo {
+        inner: DuplexStream,
+        log: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl AsyncRead for LoggedIo {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.inner).poll_read(cx, buf)
+        }
+    }
+
+    impl AsyncWrite for LoggedIo {
+        fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+            let result = Pin::new(&mut self.inner).poll_write(cx, buf);
+            if let Poll::Ready(Ok(n)) = result {
+                self.log.lock().push(String::from_utf8_lossy(&buf[..n]).into_owned());
+            }
+            result
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            self.log.lock().push("flush".to_string());
+            Pin::new(&mut self.inner).poll_flush(cx)
+        }
+
+        fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.inner).poll_shutdown(cx)
+        }
+    }
+
+    /// Serve one hyper connection whose handler sends `LINK` as early hints
+    /// and answers with whether that worked, right away.
+    fn serve() -> (DuplexStream, Arc<Mutex<Vec<String>>>) {
+        let (client, server) = tokio::io::duplex(4096);
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let (io, hints) = wrap(LoggedIo { inner: server, log: Arc::clone(&log) });
+        let service = service_fn(move |mut req: Request<Body>| {
+            req.extensions_mut().insert(hints.clone());
+            async move {
+                let sent = send(&req, LINK).await;
+                Ok::<_, Infallible>(Response::new(Body::from(if sent { "hinted" } else { "plain" })))
+            }
+        });
+        tokio::spawn(Http::new().http1_only(true).serve_connection(io, service));
+        (client, log)
+    }
+
+    /// Read from `client` until what it sent ends with `suffix`.
+    async fn read_until(client: &mut DuplexStream, suffix: &str) -> String {
+        let mut received = Vec::new();
+        let read = async {
+            while !received.ends_with(suffix.as_bytes()) {
+                let mut chunk = [0; 1024];
+                let n = client.read(&mut chunk).await.unwrap();
+                assert!(n > 0, "connection closed after {:?}", String::from_utf8_lossy(&received));
+                received.extend_from_slice(&chunk[..n]);
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), read).await.expect("timed out");
+        String::from_utf8(received).unwrap()
+    }
+
+    #[tokio::test]
+    async fn hint_is_flushed_before_the_response_is_written() {
+        let (mut client, log) = serve();
+        let hint = format!("HTTP/1.1 103 Early Hints\r\nLink: {}\r\n\r\n", LINK);
+
+        for _ in 0..2 {
+            log.lock().clear();
+            client.write_all(b"GET / HTTP/1.1\r\nHost: x\r\n\r\n").await.unwrap();
+            let response = read_until(&mut client, "hinted").await;
+            assert!(response.starts_with(&format!("{}HTTP/1.1 200 OK\r\n", hint)), "{}", response);
+
+            // On the wire on its own, before hyper even had the 200.
+            let log = log.lock();
+            let hint_at = log.iter().position(|event| *event == hint).unwrap();
+            let response_at = log.iter().position(|event| event.starts_with("HTTP/1.1 200")).unwrap();
+            assert!(log[hint_at..response_at].contains(&"flush".to_string()), "{:?}", log);
+        }
+    }
+
+    #[tokio::test]
+    async fn http_1_0_gets_no_hint() {
+        let (mut client, _) = serve();
+        client.write_all(b"GET / HTTP/1.0\r\n\r\n").await.unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.0 200 OK\r\n"), "{}", response);
+        assert!(response.ends_with("plain"), "{}", response);
+    }
+}
@@ -9,38 +9,69 @@
 //! - 30: Redirect (meta is new URL)
 //! - 40: Temporary failure
 //! - 50: Permanent failure
+//! - 31: Permanent redirect
 //! - 51: Not found
+//! - 52: Gone
 //! - 59: Bad request
 
+use std::borrow::Cow;
 use std::collections::HashMap;
-use std::time::Duration;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Instant;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpStream;
 use tokio::time::timeout;
 use tokio_rustls::server::TlsStream;
 
+use crate::access_log;
+use crate::acme;
 use crate::assets::{get_gemini_routes, GeminiAsset};
+use crate::canonical;
+use crate::config;
+use crate::redirects;
+use crate::suggest;
 
 lazy_static::lazy_static! {
     static ref GEMINI_ROUTES: HashMap<&'static str, &'static GeminiAsset> = get_gemini_routes();
+    static ref GEMINI_PAGES: Vec<&'static str> = {
+        let mut pages: Vec<&'static str> = GEMINI_ROUTES
+            .keys()
+            .copied()
+            .filter(|route| route.ends_with('/'))
+            .collect();
+        pages.sort_unstable();
+        pages
+    };
 }
 
+// 51 can't carry a body, so suggestions mean answering 20 with a gemtext
+// "not found" page instead. Off by default: crawlers take 20 at face value.
+static NOT_FOUND_SUGGESTIONS: AtomicBool = AtomicBool::new(false);
+const MAX_SUGGESTIONS: usize = 3;
+
 const MAX_REQUEST_SIZE: usize = 1024;
-// Total budget for reading a complete request. A well-behaved client sends
-// ~50 bytes in one segment; anything slower is either broken or hostile.
-const REQUEST_READ_TIMEOUT: Duration = Duration::from_secs(5);
-// Per-read deadline. A peer that can't deliver another byte within a second
-// of the previous read is wasting a permit; drop them.
-const REQUEST_CHUNK_TIMEOUT: Duration = Duration::from_secs(1);
 
 /// Return the number of Gemini routes
 pub fn route_count() -> usize {
     GEMINI_ROUTES.len()
 }
 
+/// The self-signed certificate kept in the state directory, or a fresh one
+/// each start without it.
+pub fn self_signed_certificate(
+    config: &config::Config,
+) -> Result<acme::CertificateData, Box<dyn std::error::Error + Send + Sync>> {
+    match &config.state_directory {
+        Some(dir) => acme::load_or_generate_persistent_certificate(dir, &config.domain),
+        None => acme::generate_self_signed_certificate(&config.domain),
+    }
+}
+
 /// Handle a single Gemini connection
 pub async fn handle_connection(
     mut stream: TlsStream<TcpStream>,
+    peer: SocketAddr,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     // Read request (URL + CRLF, max 1024 bytes per spec)
     let mut buf = [0u8; MAX_REQUEST_SIZE + 2]; // +2 for CRLF
@@ -53,9 +84,13 @@ pub async fn handle_connection(
         Stalled,
     }
 
-    let read_result = timeout(REQUEST_READ_TIMEOUT, async {
+    // Total budget for the request, and one per read: a peer that is slower
+    // than that is either broken or hostile, and holds a permit meanwhile.
+    let config = config::current();
+    let (read_timeout, chunk_timeout) = (config.gemini.request_timeout, config.gemini.request_chunk_timeout);
+    let read_result = timeout(read_timeout, async {
         loop {
-            let n = match timeout(REQUEST_CHUNK_TIMEOUT, stream.read(&mut buf[pos..])).await {
+            let n = match timeout(chunk_timeout, stream.read(&mut buf[pos..])).await {
                 Ok(r) => r?,
                 Err(_) => return Ok::<ReadOutcome, std::io::Error>(ReadOutcome::Stalled),
             };
@@ -93,50 +128,107 @@ pub async fn handle_connection(
         Ok(Err(e)) => return Err(e.into()),
     }
 
-    // Parse URL (strip CRLF)
-    let request = match std::str::from_utf8(&buf[..pos - 2]) {
-        Ok(s) => s,
-        Err(_) => {
-            stream.write_all(b"59 Invalid UTF-8 in request\r\n").await?;
-            return Ok(());
-        }
-    };
+    // Strip CRLF
+    let request = &buf[..pos - 2];
+    let start = Instant::now();
+    let (header, body) = respond(request);
+    stream.write_all(header.as_bytes()).await?;
+    if let Some(body) = &body {
+        stream.write_all(body).await?;
+    }
 
-    // Parse as URL
-    let url = match url::Url::parse(request) {
-        Ok(u) => u,
-        Err(_) => {
-            stream.write_all(b"59 Invalid URL\r\n").await?;
-            return Ok(());
-        }
+    // Flush any TLS buffer and send close_notify. Without this, large
+    // bodies (e.g. images) get truncated when the stream drops mid-flush.
+    let _ = stream.shutdown().await;
+
+    if access_log::enabled() {
+        let mut entry = access_log::Entry::gemini(peer, &String::from_utf8_lossy(request));
+        entry.status = header.get(..2).and_then(|code| code.parse().ok()).unwrap_or(0);
+        entry.bytes = (header.len() + body.map_or(0, |b| b.len())) as u64;
+        entry.latency = start.elapsed();
+        access_log::log(entry);
+    }
+
+    Ok(())
+}
+
+/// Response header line and body for a request line (without CRLF). The
+/// header and body are written separately so static content is never
+/// copied just to prepend a header.
+fn respond(request: &[u8]) -> (String, Option<Cow<'static, [u8]>>) {
+    let header = |line: &str| (format!("{}\r\n", line), None);
+
+    let Ok(request) = std::str::from_utf8(request) else {
+        return header("59 Invalid UTF-8 in request");
+    };
+    let Ok(url) = url::Url::parse(request) else {
+        return header("59 Invalid URL");
     };
 
     // Only handle gemini:// scheme
     if url.scheme() != "gemini" {
-        stream.write_all(b"59 Only gemini:// URLs are supported\r\n").await?;
-        return Ok(());
+        return header("59 Only gemini:// URLs are supported");
     }
 
     let path = url.path();
 
-    // Route to content. Write header then body in two calls so we don't
-    // allocate a Vec just to concatenate a static header with static content.
-    match lookup(path) {
-        Some(asset) => {
-            let header = format!("20 {}\r\n", asset.content_type);
-            stream.write_all(header.as_bytes()).await?;
-            stream.write_all(asset.content).await?;
-        }
-        None => {
-            stream.write_all(b"51 Not found\r\n").await?;
+    if canonical::enabled() {
+        if let Some(canonical) = canonical::canonicalize(path, "index.gmi", |p| GEMINI_ROUTES.contains_key(p)) {
+            let location = match url.query() {
+                Some(query) => format!("{}?{}", canonical, query),
+                None => canonical,
+            };
+            return header(&format!("31 {}", location));
         }
     }
 
-    // Flush any TLS buffer and send close_notify. Without this, large
-    // bodies (e.g. images) get truncated when the stream drops mid-flush.
-    let _ = stream.shutdown().await;
+    // Route to content
+    match lookup(path) {
+        Some(asset) => (format!("20 {}\r\n", asset.content_type), Some(Cow::Borrowed(asset.content))),
+        None => match redirects::lookup(path) {
+            // Same rules as HTTP: 301/308 are permanent, 302/307 temporary.
+            Some(target) => match &target.location {
+                Some(location) => {
+                    let code = if target.is_permanent() { 31 } else { 30 };
+                    header(&format!("{} {}", code, location))
+                }
+                None => header("52 Gone"),
+            },
+            None => match not_found_page(path) {
+                Some((lang, page)) => (
+                    format!("20 text/gemini; lang={}\r\n", lang),
+                    Some(Cow::Owned(page.into_bytes())),
+                ),
+                None => header("51 Not found"),
+            },
+        },
+    }
+}
 
-    Ok(())
+pub fn set_not_found_suggestions(enabled: bool) {
+    NOT_FOUND_SUGGESTIONS.store(enabled, Ordering::Relaxed);
+}
+
+/// Gemtext "not found" page listing the closest existing pages, in the
+/// language of the path. `None` when disabled or nothing is close.
+fn not_found_page(path: &str) -> Option<(&'static str, String)> {
+    if !NOT_FOUND_SUGGESTIONS.load(Ordering::Relaxed) {
+        return None;
+    }
+    let suggestions = suggest::closest(path, GEMINI_PAGES.iter().copied(), MAX_SUGGESTIONS);
+    if suggestions.is_empty() {
+        return None;
+    }
+
+    let (lang, title) = match suggest::language_prefix(path) {
+        Some("de") => ("de", "Nicht gefunden"),
+        _ => ("en", "Not found"),
+    };
+    let mut page = format!("# {}\n\n{}\n", title, suggest::heading(lang));
+    for suggestion in suggestions {
+        page.push_str(&format!("=> {}\n", suggestion));
+    }
+    Some((lang, page))
 }
 
 /// Look up the static asset for a Gemini path, if any.
@@ -0,0 +1,295 @@
+//! Response header policy
+//!
+//! Security headers, CSP, HSTS and CORS used to be Caddy's job; this keeps
+//! the binary correct on its own. A policy is an ordered list of rules, each
+//! matching by path glob and/or Content-Type prefix. Every matching rule
+//! applies in order, so later rules override earlier ones for the same
+//! header, and an empty value removes a header set by an earlier rule.
+//!
+//! Embedded HTML pages carry their own hash-based CSP from build.rs, which
+//! `router::route` sets after the policy; the CSP here covers everything else.
+//!
+//! Without a policy file the built-in default below is used. It has no
+//! HSTS: the server can't tell whether the client's connection was TLS
+//! (Caddy terminates it in production), and a year of pinning is for the
+//! deployer to opt into. A file replaces the default entirely:
+//!
+//! ```toml
+//! [[rule]]
+//! [rule.headers]
+//! Strict-Transport-Security = "max-age=31536000"
+//!
+//! [[rule]]
+//! path = "/fonts/*"
+//! [rule.headers]
+//! Access-Control-Allow-Origin = "*"
+//!
+//! [[rule]]
+//! content_type = "text/html"
+//! [rule.headers]
+//! X-Frame-Options = "DENY"
+//! ```
+
+use hyper::header::{HeaderName, HeaderValue, CONTENT_TYPE};
+use hyper::{Body, Response};
+use parking_lot::RwLock;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+lazy_static::lazy_static! {
+    static ref POLICY: RwLock<Arc<HeaderPolicy>> = RwLock::new(Arc::new(HeaderPolicy::default_policy()));
+}
+
+pub struct HeaderPolicy {
+    rules: Vec<HeaderRule>,
+}
+
+struct HeaderRule {
+    path: Option<String>,
+    content_type: Option<String>,
+    headers: Vec<(HeaderName, Option<HeaderValue>)>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PolicyFile {
+    #[serde(default)]
+    rule: Vec<RuleFile>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuleFile {
+    path: Option<String>,
+    content_type: Option<String>,
+    headers: BTreeMap<String, String>,
+}
+
+impl HeaderPolicy {
+    /// Mirrors the Caddy header block from LIGHTHOUSE_PLAN.md, minus HSTS,
+    /// plus CORS on fonts, which browsers always fetch in CORS mode.
+    pub fn default_policy() -> Self {
+        let rule = |path: Option<&str>, content_type: Option<&str>, headers: &[(&'static str, &'static str)]| {
+            HeaderRule {
+                path: path.map(str::to_string),
+                content_type: content_type.map(str::to_string),
+                headers: headers
+                    .iter()
+                    .map(|(name, value)| {
+                        (HeaderName::from_static(name), Some(HeaderValue::from_static(value)))
+                    })
+                    .collect(),
+            }
+        };
+
+        Self {
+            rules: vec![
+                rule(None, None, &[
+                    ("x-content-type-options", "nosniff"),
+                    ("referrer-policy", "strict-origin-when-cross-origin"),
+                ]),
+                rule(None, Some("text/html"), &[
+                    ("x-frame-options", "DENY"),
+                    ("cross-origin-opener-policy", "same-origin"),
+                    ("content-security-policy",
+                        "default-src 'self'; script-src 'self' 'unsafe-inline'; style-src 'self'; \
+                         img-src 'self' data:; font-src 'self'; connect-src 'self'; \
+                         frame-ancestors 'none'; base-uri 'self'; form-action 'self'"),
+                ]),
+                rule(None, Some("font/"), &[
+                    ("access-control-allow-origin", "*"),
+                ]),
+            ],
+        }
+    }
+
+    pub fn from_toml(source: &str) -> Result<Self, String> {
+        let file: PolicyFile = toml::from_str(source).map_err(|e| e.to_string())?;
+        let mut rules = Vec::with_capacity(file.rule.len());
+        for (i, r) in file.rule.into_iter().enumerate() {
+            let mut headers = Vec::with_capacity(r.headers.len());
+            for (name, value) in r.headers {
+                let name = HeaderName::from_bytes(name.as_bytes())
+                    .map_err(|_| format!("rule {}: invalid header name '{}'", i + 1, name))?;
+                let value = if value.is_empty() {
+                    None
+                } else {
+                    Some(HeaderValue::from_str(&value).map_err(|_| {
+                        format!("rule {}: invalid value for header '{}'", i + 1, name)
+                    })?)
+                };
+                headers.push((name, value));
+            }
+            rules.push(HeaderRule {
+                path: r.path,
+                content_type: r.content_type,
+                headers,
+            });
+        }
+        Ok(Self { rules })
+    }
+
+    pub fn apply(&self, path: &str, response: &mut Response<Body>) {
+        let content_type = response
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or("")
+            .to_ascii_lowercase();
+
+        for rule in &self.rules {
+            if let Some(pattern) = &rule.path {
+                if !glob_match(pattern, path) {
+                    continue;
+                }
+            }
+            if let Some(prefix) = &rule.content_type {
+                if !content_type.starts_with(prefix.as_str()) {
+                    continue;
+                }
+            }
+            let headers = response.headers_mut();
+            for (name, value) in &rule.headers {
+                match value {
+                    Some(v) => {
+                        headers.insert(name.clone(), v.clone());
+                    }
+                    None => {
+                        headers.remove(name);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// The policy currently in effect.
+pub fn policy() -> Arc<HeaderPolicy> {
+    Arc::clone(&POLICY.read())
+}
+
+pub fn set_policy(policy: HeaderPolicy) {
+    *POLICY.write() = Arc::new(policy);
+}
+
+/// `*` matches any run of characters (including `/`), `?` exactly one.
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let (p, t) = (pattern.as_bytes(), text.as_bytes());
+    let (mut pi, mut ti) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while ti < t.len() {
+        if pi < p.len() && (p[pi] == b'?' || p[pi] == t[ti]) {
+            pi += 1;
+            ti += 1;
+        } else if pi < p.len() && p[pi] == b'*' {
+            backtrack = Some((pi, ti));
+            pi += 1;
+        } else if let Some((star_p, star_t)) = backtrack {
+            pi = star_p + 1;
+            ti = star_t + 1;
+            backtrack = Some((star_p, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+    p[pi..].iter().all(|&c| c == b'*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(content_type: &str) -> Response<Body> {
+        Response::builder().header(CONTENT_TYPE, content_type).body(Body::empty()).unwrap()
+    }
+
+    fn applied(policy: &HeaderPolicy, path: &str, content_type: &str) -> Response<Body> {
+        let mut response = response(content_type);
+        policy.apply(path, &mut response);
+        response
+    }
+
+    fn header<'a>(response: &'a Response<Body>, name: &str) -> Option<&'a str> {
+        response.headers().get(name).map(|v| v.to_str().unwrap())
+    }
+
+    #[test]
+    fn globs() {
+        assert!(glob_match("/fonts/*", "/fonts/a.woff2"));
+        assert!(glob_match("/fonts/*", "/fonts/sub/a.woff2"));
+        assert!(glob_match("*.css", "/css/main.css"));
+        assert!(glob_match("/a?c", "/abc"));
+        assert!(glob_match("/a*b*c", "/axxbyybc"));
+        assert!(glob_match("/x**", "/x"));
+        assert!(!glob_match("/fonts/*", "/font/a.woff2"));
+        assert!(!glob_match("/a?c", "/ac"));
+        assert!(!glob_match("/a*b", "/abc"));
+        assert!(!glob_match("/exact", "/exact/"));
+    }
+
+    #[test]
+    fn default_policy() {
+        let policy = HeaderPolicy::default_policy();
+        let html = applied(&policy, "/", "text/html; charset=utf-8");
+        assert_eq!(header(&html, "x-frame-options"), Some("DENY"));
+        assert!(header(&html, "content-security-policy").is_some());
+        assert_eq!(header(&html, "strict-transport-security"), None);
+        let font = applied(&policy, "/fonts/a.woff2", "font/woff2");
+        assert_eq!(header(&font, "access-control-allow-origin"), Some("*"));
+        assert_eq!(header(&font, "x-content-type-options"), Some("nosniff"));
+        assert_eq!(header(&font, "x-frame-options"), None);
+    }
+
+    #[test]
+    fn later_rules_override_and_empty_values_remove() {
+        let policy = HeaderPolicy::from_toml(
+            r#"
+            [[rule]]
+            [rule.headers]
+            X-Frame-Options = "DENY"
+            Referrer-Policy = "no-referrer"
+
+            [[rule]]
+            path = "/embed/*"
+            [rule.headers]
+            X-Frame-Options = "SAMEORIGIN"
+
+            [[rule]]
+            path = "/embed/*"
+            content_type = "text/html"
+            [rule.headers]
+            Referrer-Policy = ""
+            "#,
+        )
+        .unwrap();
+
+        let page = applied(&policy, "/about/", "text/html");
+        assert_eq!(header(&page, "x-frame-options"), Some("DENY"));
+        assert_eq!(header(&page, "referrer-policy"), Some("no-referrer"));
+
+        let embed = applied(&policy, "/embed/chart", "TEXT/HTML");
+        assert_eq!(header(&embed, "x-frame-options"), Some("SAMEORIGIN"));
+        assert_eq!(header(&embed, "referrer-policy"), None);
+
+        // Both conditions of a rule must hold.
+        let script = applied(&policy, "/embed/chart.js", "text/javascript");
+        assert_eq!(header(&script, "referrer-policy"), Some("no-referrer"));
+    }
+
+    #[test]
+    fn bad_files_name_the_rule() {
+        let error = |source: &str| HeaderPolicy::from_toml(source).err().unwrap();
+        assert_eq!(
+            error("[[rule]]\n[rule.headers]\n\"Bad Name\" = \"x\"\n"),
+            "rule 1: invalid header name 'Bad Name'"
+        );
+        assert_eq!(
+            error("[[rule]]\n[rule.headers]\n[[rule]]\n[rule.headers]\nX-A = \"a\\nb\"\n"),
+            "rule 2: invalid value for header 'x-a'"
+        );
+        assert!(error("[[rule]]\npaths = \"/\"\n[rule.headers]\n").contains("unknown field"));
+        assert!(HeaderPolicy::from_toml("").unwrap().rules.is_empty());
+    }
+}
@@ -0,0 +1,197 @@
+//! Native HTTPS listener
+//!
+//! Serves the same `router::route` as the plain HTTP listener, over TLS with
+//! HTTP/1.1 and HTTP/2 negotiated via ALPN. Optional: production sits behind
+//! Caddy, but small deployments can terminate TLS here and skip the proxy.
+//!
+//! A companion plain-HTTP listener (normally :80) answers everything with a
+//! permanent redirect to the HTTPS origin, except ACME HTTP-01 challenges.
+
+use hyper::server::conn::{AddrIncoming, Http};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{header, Body, Request, Response, Server, StatusCode};
+use std::convert::Infallible;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tokio::time::timeout;
+use tokio_rustls::rustls::ServerConfig;
+
+use crate::access_log;
+use crate::acme;
+use crate::config;
+use crate::early_hints;
+use crate::metrics::Metrics;
+use crate::reload;
+use crate::router;
+use crate::shutdown;
+
+/// HTTP/2 first, and the TLS-ALPN-01 challenge protocol for ACME.
+pub const ALPN_PROTOCOLS: &[&[u8]] = &[b"h2", b"http/1.1", acme::ACME_TLS_ALPN];
+
+lazy_static::lazy_static! {
+    // Shared by all HTTPS listeners.
+    static ref HTTPS_CONNECTIONS: reload::Limit = reload::Limit::new(|c| c.https.max_concurrent);
+}
+
+/// TLS config presenting the certificate from `cert` and `key` (PEM).
+pub fn tls_config_from_files(
+    cert: &Path,
+    key: &Path,
+) -> Result<Arc<ServerConfig>, Box<dyn std::error::Error + Send + Sync>> {
+    tls_config(&acme::load_certificate_files(cert, key)?)
+}
+
+/// TLS config presenting `certificate`.
+pub fn tls_config(
+    certificate: &acme::CertificateData,
+) -> Result<Arc<ServerConfig>, Box<dyn std::error::Error + Send + Sync>> {
+    let store = acme::CertStore::new();
+    store.set_certificate(certificate)?;
+    Ok(acme::build_resolver_tls_config(store, ALPN_PROTOCOLS))
+}
+
+pub async fn start_https_server(
+    tls_config: Arc<reload::TlsConfig>,
+    listener: TcpListener,
+    metrics: Arc<Metrics>,
+) {
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = shutdown::triggered() => return,
+        };
+        let (stream, peer_addr) = match accepted {
+            Ok(conn) => conn,
+            Err(e) => {
+                eprintln!("HTTPS accept error: {}", e);
+                tokio::time::sleep(crate::ACCEPT_ERROR_BACKOFF).await;
+                continue;
+            }
+        };
+        // Drop connections over the cap rather than queuing unbounded
+        // handshakes; a slow one only holds its slot until the timeout.
+        let Some(permit) = HTTPS_CONNECTIONS.try_acquire() else {
+            drop(stream);
+            continue;
+        };
+        let tls_acceptor = tls_config.acceptor();
+        let metrics = Arc::clone(&metrics);
+        let handshake_timeout = config::current().https.handshake_timeout;
+        let connection = shutdown::track();
+
+        tokio::spawn(async move {
+            let _permit = permit;
+            let _connection = connection;
+            let tls_stream = match timeout(handshake_timeout, tls_acceptor.accept(stream)).await {
+                Ok(Ok(s)) => s,
+                // Scanners and clients that hang up mid-handshake are routine
+                // on a public port; not worth a log line each.
+                Ok(Err(_)) | Err(_) => return,
+            };
+
+            let alpn = tls_stream.get_ref().1.alpn_protocol();
+            // A TLS-ALPN-01 validation is over once the handshake is done.
+            if alpn == Some(acme::ACME_TLS_ALPN) {
+                return;
+            }
+            let is_h2 = alpn == Some(b"h2");
+
+            // Early hints only ever go out on HTTP/1.1; see early_hints.
+            let (io, hints) = early_hints::wrap(tls_stream);
+            let service = service_fn(move |mut req| {
+                req.extensions_mut().insert(hints.clone());
+                req.extensions_mut().insert(access_log::PeerAddr(peer_addr));
+                let metrics = Arc::clone(&metrics);
+                router::route(req, metrics)
+            });
+
+            let conn = Http::new()
+                .http2_only(is_h2)
+                .serve_connection(io, service)
+                .with_upgrades();
+            tokio::pin!(conn);
+            // HTTP/2 clients get a GOAWAY and finish their open streams.
+            let result = tokio::select! {
+                result = conn.as_mut() => result,
+                _ = shutdown::triggered() => {
+                    conn.as_mut().graceful_shutdown();
+                    conn.await
+                }
+            };
+            if let Err(e) = result {
+                if !e.is_incomplete_message() {
+                    eprintln!("HTTPS connection error from {}: {}", peer_addr, e);
+                }
+            }
+        });
+    }
+}
+
+/// Plain HTTP listener that sends every request to the HTTPS origin.
+pub async fn start_redirect_server(
+    listener: TcpListener,
+    https_port: u16,
+    fallback_host: String,
+) -> Result<(), hyper::Error> {
+    let fallback_host = Arc::new(fallback_host);
+    let make_svc = make_service_fn(move |_conn| {
+        let fallback_host = Arc::clone(&fallback_host);
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                let response = redirect_to_https(&req, https_port, &fallback_host);
+                async move { Ok::<_, Infallible>(response) }
+            }))
+        }
+    });
+
+    Server::builder(AddrIncoming::from_listener(listener)?)
+        .serve(make_svc)
+        .with_graceful_shutdown(shutdown::triggered())
+        .await
+}
+
+fn redirect_to_https(req: &Request<Body>, https_port: u16, fallback_host: &str) -> Response<Body> {
+    // HTTP-01 validation always arrives on port 80 and must not be redirected
+    // to a certificate that may not exist yet.
+    if let Some(response) = router::acme_challenge(req.uri().path()) {
+        return response;
+    }
+
+    // Use the Host the client asked for so multi-name setups keep their
+    // name, but only if it looks like a hostname; anything else would let a
+    // client steer the Location header.
+    let host = req
+        .headers()
+        .get(header::HOST)
+        .and_then(|v| v.to_str().ok())
+        .map(|h| h.rsplit_once(':').map_or(h, |(name, _)| name))
+        .filter(|h| is_valid_hostname(h))
+        .unwrap_or(fallback_host);
+
+    let path_and_query = req
+        .uri()
+        .path_and_query()
+        .map(|pq| pq.as_str())
+        .unwrap_or("/");
+
+    let location = if https_port == 443 {
+        format!("https://{}{}", host, path_and_query)
+    } else {
+        format!("https://{}:{}{}", host, https_port, path_and_query)
+    };
+
+    Response::builder()
+        .status(StatusCode::PERMANENT_REDIRECT)
+        .header(header::LOCATION, location)
+        .body(Body::empty())
+        .unwrap()
+}
+
+fn is_valid_hostname(host: &str) -> bool {
+    !host.is_empty()
+        && host.len() <= 253
+        && host
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'.' || b == b'-')
+}
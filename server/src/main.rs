@@ -1,18 +1,21 @@
-use hyper::service::{make_service_fn, service_fn};
-use hyper::Server;
+use futures_util::future::join_all;
+use hyper::server::conn::Http;
+use hyper::service::service_fn;
 use std::collections::HashMap;
-use std::convert::Infallible;
 use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
 use tokio::net::TcpListener;
-use tokio::sync::Semaphore;
 use tokio::time::timeout;
-use tokio_rustls::TlsAcceptor;
 
-const GEMINI_TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
-const GEMINI_MAX_CONCURRENT: usize = 256;
-const GEMINI_MAX_PER_IP: usize = 4;
+/// Pause after a failed accept (usually EMFILE) instead of spinning on it.
+const ACCEPT_ERROR_BACKOFF: Duration = Duration::from_millis(100);
+
+lazy_static::lazy_static! {
+    // Shared by all Gemini listeners.
+    static ref GEMINI_CONNECTIONS: reload::Limit = reload::Limit::new(|c| c.gemini.max_concurrent);
+}
 
 /// RAII guard that decrements a per-IP connection counter on drop.
 struct PerIpGuard {
@@ -21,11 +24,12 @@ struct PerIpGuard {
 }
 
 impl PerIpGuard {
-    /// Try to acquire a slot for `ip`. Returns `None` if the per-IP cap is hit.
-    fn try_acquire(table: &Arc<Mutex<HashMap<IpAddr, usize>>>, ip: IpAddr) -> Option<Self> {
+    /// Try to acquire a slot for `ip`. Returns `None` if `ip` already has
+    /// `max` of them.
+    fn try_acquire(table: &Arc<Mutex<HashMap<IpAddr, usize>>>, ip: IpAddr, max: usize) -> Option<Self> {
         let mut t = table.lock().unwrap();
         let count = t.entry(ip).or_insert(0);
-        if *count >= GEMINI_MAX_PER_IP {
+        if *count >= max {
             return None;
         }
         *count += 1;
@@ -45,96 +49,370 @@ impl Drop for PerIpGuard {
     }
 }
 
+mod access_log;
 mod acme;
 mod assets;
+mod canonical;
+mod conditional;
+mod config;
+mod date;
+mod early_hints;
 mod gemini;
+mod headers;
+mod https;
 mod metrics;
+mod negotiate;
+mod range;
+mod redirects;
+mod reload;
 mod router;
+mod shutdown;
+mod suggest;
+mod systemd;
+mod upgrade;
 mod websocket;
 
+// build.rs's page processing, compiled here only for its unit tests.
+#[cfg(test)]
+#[path = "../build/site.rs"]
+#[allow(dead_code)]
+mod build_site;
+
 #[global_allocator]
 static GLOBAL: mimalloc::MiMalloc = mimalloc::MiMalloc;
 
-#[tokio::main]
-async fn main() {
-    let port: u16 = std::env::var("PORT")
-        .ok()
-        .and_then(|p| p.parse().ok())
-        .unwrap_or(8080);
+fn main() {
+    // Sockets passed by systemd take the place of binding our own. Their
+    // variables, and the upgrade's, are cleared from the environment, which
+    // is only sound while no other thread runs, so before the runtime
+    // starts its workers.
+    let activated = systemd::listen_fds();
+    upgrade::init();
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .expect("Failed to start the async runtime")
+        .block_on(serve(activated));
+}
 
-    let addr = SocketAddr::from(([127, 0, 0, 1], port));
+async fn serve(mut activated: systemd::Activated) {
+    let (config_path, config, policy) = load_config();
+    if let Some(policy) = policy {
+        headers::set_policy(policy);
+    }
+    config::set(config);
+    let config = config::current();
 
-    let metrics = metrics::Metrics::new();
+    canonical::set_enabled(config.canonical_urls);
+    gemini::set_not_found_suggestions(config.gemini.not_found_suggestions);
 
-    let make_svc = make_service_fn(move |_conn| {
-        let metrics = Arc::clone(&metrics);
-        async move {
-            Ok::<_, Infallible>(service_fn(move |req| {
-                let metrics = Arc::clone(&metrics);
-                router::route(req, metrics)
-            }))
-        }
-    });
+    access_log::configure_from(&config).expect("Failed to open access log");
 
-    let server = Server::bind(&addr).serve(make_svc);
+    tokio::spawn(shutdown::on_signal());
+    tokio::spawn(upgrade::on_signal());
+    let hangups = reload::hangups();
+    let mut reloader = reload::Reloader { path: config_path, https: None, gemini: None };
 
-    println!("HTTP server listening on http://{}", addr);
+    let metrics = metrics::Metrics::new();
+
+    // Accept loops the watchdog checks on, besides the main HTTP ones.
+    let mut listeners = Vec::new();
+    let mut status = format!("Serving {} routes", router::route_count());
+
+    let http_listeners = activated
+        .listeners("http", &config.http.listen)
+        .unwrap_or_else(|e| panic!("Failed to bind {}", e));
+    for listener in &http_listeners {
+        upgrade::register("http", listener);
+        println!("HTTP server listening on http://{}", local_addr(listener));
+    }
     println!("Serving {} routes", router::route_count());
+    if redirects::count() > 0 {
+        println!("Loaded {} redirect rules", redirects::count());
+    }
+
+    // Optional native HTTPS, for deployments without a TLS-terminating proxy.
+    // The certificate comes either from files or from an ACME CA.
+    let cert_store = acme::CertStore::new();
+    let https_tls = if let (Some(cert_path), Some(key_path)) = (&config.https.cert, &config.https.key) {
+        let tls_config = https::tls_config_from_files(cert_path, key_path)
+            .unwrap_or_else(|e| panic!("Failed to load HTTPS certificate: {}", e));
+        // Certificate files can be replaced and reloaded.
+        let tls_config = reload::TlsConfig::new(tls_config);
+        reloader.https = Some(Arc::clone(&tls_config));
+        Some(tls_config)
+    } else if config.acme.enabled {
+        let acme_config = config.acme_config();
+        acme::load_persisted_certificate(&acme_config, &cert_store)
+            .expect("Failed to load ACME certificate");
+        let store = Arc::clone(&cert_store);
+        tokio::spawn(acme::run_manager(acme_config, store));
+        Some(reload::TlsConfig::new(acme::build_resolver_tls_config(
+            Arc::clone(&cert_store),
+            https::ALPN_PROTOCOLS,
+        )))
+    } else {
+        None
+    };
+
+    if let Some(tls_config) = https_tls {
+        // Redirects point at the port HTTPS actually got, which for a
+        // passed socket is whatever the socket unit says.
+        let mut https_port = config.https.listen.first().map_or(443, SocketAddr::port);
+        match activated.listeners("https", &config.https.listen) {
+            Ok(https_listeners) => {
+                if let Some(first) = https_listeners.first() {
+                    https_port = local_addr(first).port();
+                }
+                for https_listener in https_listeners {
+                    upgrade::register("https", &https_listener);
+                    println!("HTTPS server listening on https://{}", local_addr(&https_listener));
+                    let task = tokio::spawn(https::start_https_server(
+                        Arc::clone(&tls_config),
+                        https_listener,
+                        Arc::clone(&metrics),
+                    ));
+                    listeners.push(("HTTPS", task));
+                }
+            }
+            // A configured port that isn't served would go unnoticed.
+            Err(e) => panic!("Failed to bind {}", e),
+        }
+
+        if config.https.redirect {
+            match activated.listeners("http-redirect", &config.https.redirect_listen) {
+                Ok(redirect_listeners) => {
+                    for redirect_listener in redirect_listeners {
+                        upgrade::register("http-redirect", &redirect_listener);
+                        println!(
+                            "HTTP redirect listening on http://{} -> :{}",
+                            local_addr(&redirect_listener),
+                            https_port
+                        );
+                        let fallback_host = config.domain.clone();
+                        let connection = shutdown::track();
+                        let task = tokio::spawn(async move {
+                            let _connection = connection;
+                            if let Err(e) =
+                                https::start_redirect_server(redirect_listener, https_port, fallback_host).await
+                            {
+                                eprintln!("HTTP redirect server error: {}", e);
+                            }
+                        });
+                        listeners.push(("HTTP redirect", task));
+                    }
+                }
+                Err(e) => panic!("Failed to bind {}", e),
+            }
+        }
+    }
 
     // Start Gemini server if content exists
-    let gemini_enabled = std::env::var("ENABLE_GEMINI")
-        .unwrap_or_else(|_| "true".to_string()) == "true";
-
-    if gemini_enabled && gemini::route_count() > 0 {
-        let domain = std::env::var("DOMAIN").unwrap_or_else(|_| "localhost".to_string());
-        let cert_data = match std::env::var("STATE_DIRECTORY").ok() {
-            Some(dir) if !dir.is_empty() => {
-                let path = std::path::PathBuf::from(dir);
-                acme::load_or_generate_persistent_certificate(&path, &domain)
-                    .expect("Failed to load/generate persistent Gemini certificate")
+    if config.gemini.enabled && gemini::route_count() > 0 {
+        // Gemini clients pin certificates (TOFU), so a CA-issued cert that
+        // rotates every 60 days is opt-in.
+        let tls_config = if config.gemini.cert == config::GeminiCert::Acme {
+            reload::TlsConfig::new(acme::build_resolver_tls_config(Arc::clone(&cert_store), &[]))
+        } else {
+            let certificate = gemini::self_signed_certificate(&config)
+                .unwrap_or_else(|e| panic!("Failed to set up the Gemini certificate: {}", e));
+            let tls_config = acme::build_tls_config(&certificate.cert_pem, &certificate.privkey_pem)
+                .unwrap_or_else(|e| panic!("Failed to set up the Gemini certificate: {}", e));
+            let tls_config = reload::TlsConfig::new(tls_config);
+            // Only a persisted certificate can be reloaded without breaking pins.
+            if let Some(dir) = &config.state_directory {
+                reloader.gemini = Some(reload::PersistedCert::new(Arc::clone(&tls_config), dir, certificate));
             }
-            _ => acme::generate_self_signed_certificate(&domain)
-                .expect("Failed to generate self-signed certificate for Gemini"),
+            tls_config
         };
-        let tls_config = acme::build_tls_config(&cert_data.cert_pem, &cert_data.privkey_pem)
-            .expect("Failed to build TLS config for Gemini");
+        // The per-IP cap holds across all Gemini listeners together.
+        let per_ip: Arc<Mutex<HashMap<IpAddr, usize>>> = Arc::new(Mutex::new(HashMap::new()));
 
-        let gemini_port = 1965u16;
-        tokio::spawn(async move {
-            if let Err(e) = start_gemini_server(tls_config, gemini_port).await {
-                eprintln!("Gemini server error: {}", e);
+        match activated.listeners("gemini", &config.gemini.listen) {
+            Ok(gemini_listeners) => {
+                for gemini_listener in gemini_listeners {
+                    upgrade::register("gemini", &gemini_listener);
+                    let addr = local_addr(&gemini_listener);
+                    let tls_config = Arc::clone(&tls_config);
+                    let per_ip = Arc::clone(&per_ip);
+                    let task = tokio::spawn(start_gemini_server(tls_config, gemini_listener, per_ip));
+                    listeners.push(("Gemini", task));
+                    println!(
+                        "Gemini server listening on {} (gemini://{}:{})",
+                        addr,
+                        config.domain,
+                        addr.port()
+                    );
+                }
+                status.push_str(&format!(", {} Gemini routes", gemini::route_count()));
+                println!("Serving {} Gemini routes", gemini::route_count());
             }
-        });
+            Err(e) => panic!("Failed to bind {}", e),
+        }
+    }
+    activated.report_unused();
+    tokio::spawn(reload::on_signal(hangups, reloader));
 
-        println!("Gemini server listening on gemini://{}:{}", domain, gemini_port);
-        println!("Serving {} Gemini routes", gemini::route_count());
+    systemd::notify(&format!("READY=1\nSTATUS={}", status));
+    upgrade::notify_parent();
+    if let Some(interval) = systemd::watchdog_interval() {
+        tokio::spawn(systemd::run_watchdog(interval, status, listeners));
     }
 
-    if let Err(e) = server.await {
-        eprintln!("Server error: {}", e);
-        std::process::exit(1);
+    join_all(
+        http_listeners
+            .into_iter()
+            .map(|listener| start_http_server(listener, Arc::clone(&metrics))),
+    )
+    .await;
+    upgrade::release_listeners();
+
+    // After a handoff the service isn't stopping, only this process is.
+    if !upgrade::handed_over() {
+        systemd::notify("STOPPING=1\nSTATUS=Draining connections");
     }
+
+    let shutdown_timeout = config::current().shutdown.timeout;
+    let open = shutdown::drain(shutdown_timeout).await;
+    if open > 0 {
+        eprintln!(
+            "Shutdown timeout ({}s) reached with {} connections open; closing them",
+            shutdown_timeout.as_secs(),
+            open
+        );
+    } else {
+        println!("All connections drained");
+    }
+    access_log::close();
 }
 
-async fn start_gemini_server(
-    tls_config: Arc<tokio_rustls::rustls::ServerConfig>,
-    port: u16,
-) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let addr = SocketAddr::from(([0, 0, 0, 0], port));
-    let listener = TcpListener::bind(&addr).await?;
-    let tls_acceptor = TlsAcceptor::from(tls_config);
-    let semaphore = Arc::new(Semaphore::new(GEMINI_MAX_CONCURRENT));
-    let per_ip: Arc<Mutex<HashMap<IpAddr, usize>>> = Arc::new(Mutex::new(HashMap::new()));
+/// Read the config file named by `--config` or CONFIG_FILE, apply the
+/// environment and load the header policy it points to. Every error is
+/// printed before exiting. `--check-config` stops here, after printing the
+/// effective config; otherwise it's printed as startup begins.
+fn load_config() -> (Option<PathBuf>, config::Config, Option<headers::HeaderPolicy>) {
+    let usage = "Usage: static-server [--config FILE] [--check-config]";
+    let mut path = std::env::var_os("CONFIG_FILE").filter(|p| !p.is_empty()).map(PathBuf::from);
+    let mut check_only = false;
+    let mut args = std::env::args_os().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.to_str() {
+            Some("--config") => match args.next() {
+                Some(file) => path = Some(PathBuf::from(file)),
+                None => {
+                    eprintln!("{}", usage);
+                    std::process::exit(2);
+                }
+            },
+            Some("--check-config") => check_only = true,
+            _ => {
+                eprintln!("{}", usage);
+                std::process::exit(2);
+            }
+        }
+    }
+
+    let (config, policy) = match config::load_with_headers(path.as_deref()) {
+        Ok(loaded) => loaded,
+        Err(errors) => {
+            for error in &errors {
+                eprintln!("{}", error);
+            }
+            eprintln!("Invalid configuration: {} error(s)", errors.len());
+            std::process::exit(1);
+        }
+    };
+    let source = path.as_ref().map_or_else(|| "defaults".to_string(), |p| p.display().to_string());
+    if check_only {
+        print!("{}", config.to_toml());
+        eprintln!("Configuration OK ({} and environment)", source);
+        std::process::exit(0);
+    }
+    println!("Configuration ({} and environment):\n{}", source, config.to_toml());
+    (path, config, policy)
+}
 
+/// The main HTTP listener. Connections are served by hand rather than via
+/// `Server::bind` so their IO can carry early hints, requests know their
+/// peer address and each connection is tracked for shutdown. Returns once
+/// shutdown is triggered; open connections finish their current request.
+async fn start_http_server(listener: TcpListener, metrics: Arc<metrics::Metrics>) {
     loop {
-        let (stream, peer_addr) = listener.accept().await?;
-        let tls_acceptor = tls_acceptor.clone();
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = shutdown::triggered() => return,
+        };
+        let (stream, peer_addr) = match accepted {
+            Ok(conn) => conn,
+            Err(e) => {
+                eprintln!("HTTP accept error: {}", e);
+                tokio::time::sleep(ACCEPT_ERROR_BACKOFF).await;
+                continue;
+            }
+        };
+        let metrics = Arc::clone(&metrics);
+        let connection = shutdown::track();
+
+        tokio::spawn(async move {
+            let _connection = connection;
+            let (io, hints) = early_hints::wrap(stream);
+            let service = service_fn(move |mut req| {
+                req.extensions_mut().insert(hints.clone());
+                req.extensions_mut().insert(access_log::PeerAddr(peer_addr));
+                let metrics = Arc::clone(&metrics);
+                router::route(req, metrics)
+            });
+
+            let conn = Http::new().serve_connection(io, service).with_upgrades();
+            tokio::pin!(conn);
+            let result = tokio::select! {
+                result = conn.as_mut() => result,
+                _ = shutdown::triggered() => {
+                    conn.as_mut().graceful_shutdown();
+                    conn.await
+                }
+            };
+            if let Err(e) = result {
+                if !e.is_incomplete_message() {
+                    eprintln!("HTTP connection error from {}: {}", peer_addr, e);
+                }
+            }
+        });
+    }
+}
+
+/// The address a listener actually got, which for a passed socket is
+/// whatever the socket unit says.
+fn local_addr(listener: &TcpListener) -> SocketAddr {
+    listener.local_addr().expect("Listening socket has no local address")
+}
+
+async fn start_gemini_server(
+    tls_config: Arc<reload::TlsConfig>,
+    listener: TcpListener,
+    per_ip: Arc<Mutex<HashMap<IpAddr, usize>>>,
+) {
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = shutdown::triggered() => return,
+        };
+        let (stream, peer_addr) = match accepted {
+            Ok(conn) => conn,
+            Err(e) => {
+                eprintln!("Gemini accept error: {}", e);
+                tokio::time::sleep(ACCEPT_ERROR_BACKOFF).await;
+                continue;
+            }
+        };
+        let tls_acceptor = tls_config.acceptor();
+        let config = config::current();
 
         // Drop connections over the cap rather than queuing unbounded work.
-        let permit = match Arc::clone(&semaphore).try_acquire_owned() {
-            Ok(p) => p,
-            Err(_) => {
-                if std::env::var("DEBUG_GEMINI").is_ok() {
+        let permit = match GEMINI_CONNECTIONS.try_acquire() {
+            Some(p) => p,
+            None => {
+                if config.gemini.debug {
                     eprintln!("Gemini connection dropped (at cap) from {}", peer_addr);
                 }
                 drop(stream);
@@ -143,10 +421,10 @@ async fn start_gemini_server(
         };
 
         // Per-IP cap: cheap defense against a single peer hogging permits.
-        let ip_guard = match PerIpGuard::try_acquire(&per_ip, peer_addr.ip()) {
+        let ip_guard = match PerIpGuard::try_acquire(&per_ip, peer_addr.ip(), config.gemini.max_per_ip) {
             Some(g) => g,
             None => {
-                if std::env::var("DEBUG_GEMINI").is_ok() {
+                if config.gemini.debug {
                     eprintln!("Gemini connection dropped (per-IP cap) from {}", peer_addr);
                 }
                 drop(stream);
@@ -154,31 +432,48 @@ async fn start_gemini_server(
             }
         };
 
+        let connection = shutdown::track();
         tokio::spawn(async move {
             let _permit = permit;
             let _ip_guard = ip_guard;
+            let _connection = connection;
             let tls_stream = match timeout(
-                GEMINI_TLS_HANDSHAKE_TIMEOUT,
+                config.gemini.handshake_timeout,
                 tls_acceptor.accept(stream),
             )
             .await
             {
                 Ok(Ok(s)) => s,
                 Ok(Err(e)) => {
-                    if std::env::var("DEBUG_GEMINI").is_ok() {
+                    if config.gemini.debug {
                         eprintln!("Gemini TLS error from {}: {}", peer_addr, e);
                     }
                     return;
                 }
                 Err(_) => {
-                    if std::env::var("DEBUG_GEMINI").is_ok() {
+                    if config.gemini.debug {
                         eprintln!("Gemini TLS handshake timeout from {}", peer_addr);
                     }
                     return;
                 }
             };
 
-            if let Err(e) = gemini::handle_connection(tls_stream).await {
+            // A transfer in progress at shutdown gets a deadline to finish.
+            let transfer = gemini::handle_connection(tls_stream, peer_addr);
+            tokio::pin!(transfer);
+            let result = tokio::select! {
+                result = transfer.as_mut() => result,
+                _ = shutdown::triggered() => {
+                    match timeout(config.gemini.shutdown_deadline, transfer).await {
+                        Ok(result) => result,
+                        Err(_) => {
+                            eprintln!("Gemini transfer to {} cut off by shutdown", peer_addr);
+                            Ok(())
+                        }
+                    }
+                }
+            };
+            if let Err(e) = result {
                 eprintln!("Gemini connection error from {}: {}", peer_addr, e);
             }
         });
@@ -0,0 +1,196 @@
+//! Proactive content negotiation (RFC 9110 §12)
+//!
+//! Parses the `token;q=value` lists shared by Accept-Encoding and friends and
+//! picks the best of the representations we actually have.
+
+/// One element of a weighted list, e.g. `br;q=0.8`. `q` is in thousandths so
+/// comparisons stay exact.
+pub struct Preference<'a> {
+    pub token: &'a str,
+    pub q: u16,
+}
+
+/// Parse a comma-separated weighted list. Elements with an unparsable
+/// q-value are dropped; missing q means 1.
+pub fn parse_qlist(header: &str) -> Vec<Preference<'_>> {
+    header
+        .split(',')
+        .filter_map(|element| {
+            let mut parts = element.split(';');
+            let token = parts.next()?.trim();
+            if token.is_empty() {
+                return None;
+            }
+            let mut q = 1000;
+            for param in parts {
+                let (name, value) = param.split_once('=')?;
+                if name.trim().eq_ignore_ascii_case("q") {
+                    q = parse_qvalue(value.trim())?;
+                }
+            }
+            Some(Preference { token, q })
+        })
+        .collect()
+}
+
+/// `qvalue = ( "0" [ "." 0*3DIGIT ] ) / ( "1" [ "." 0*3("0") ] )`
+fn parse_qvalue(value: &str) -> Option<u16> {
+    let (int, frac) = match value.split_once('.') {
+        Some((i, f)) => (i, f),
+        None => (value, ""),
+    };
+    if frac.len() > 3 || !frac.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let mut thousandths = 0u16;
+    for (i, b) in frac.bytes().enumerate() {
+        thousandths += u16::from(b - b'0') * [100, 10, 1][i];
+    }
+    match int {
+        "0" => Some(thousandths),
+        "1" if thousandths == 0 => Some(1000),
+        _ => None,
+    }
+}
+
+/// Pick a content coding for a response.
+///
+/// `available` lists the codings we can serve, in server preference order
+/// (used to break q-value ties); `"identity"` must be included if the raw
+/// body is an option. Returns `None` if the client refused everything we
+/// have, in which case the caller should answer 406.
+pub fn choose_encoding<'a>(header: Option<&str>, available: &[&'a str]) -> Option<&'a str> {
+    // No Accept-Encoding at all: the client didn't express a preference, and
+    // identity is the only coding every client is guaranteed to understand.
+    let header = match header {
+        Some(h) => h,
+        None => return available.iter().copied().find(|&c| c == "identity"),
+    };
+
+    let prefs = parse_qlist(header);
+    let wildcard = prefs.iter().find(|p| p.token == "*").map(|p| p.q);
+
+    let weight = |coding: &str| -> u16 {
+        let explicit = prefs.iter().find(|p| {
+            p.token.eq_ignore_ascii_case(coding)
+                || (coding == "gzip" && p.token.eq_ignore_ascii_case("x-gzip"))
+        });
+        match (explicit, wildcard) {
+            (Some(p), _) => p.q,
+            (None, Some(q)) => q,
+            // Identity is implicitly acceptable unless excluded (§12.5.3).
+            (None, None) if coding == "identity" => 1,
+            (None, None) => 0,
+        }
+    };
+
+    let mut best: Option<(&'a str, u16)> = None;
+    for &coding in available {
+        let q = weight(coding);
+        if q == 0 {
+            continue;
+        }
+        // Strictly greater: earlier entries win ties.
+        if best.map(|(_, bq)| q > bq).unwrap_or(true) {
+            best = Some((coding, q));
+        }
+    }
+    best.map(|(c, _)| c)
+}
+
+/// Pick a language for a response from Accept-Language.
+///
+/// `available` lists our language tags in server preference order (used to
+/// break ties). A range matches a tag exactly or as a prefix ending at a
+/// subtag boundary (`de` matches `de-de`, RFC 4647 §3.3.1); failing that, a
+/// range with the same primary subtag still matches, since `de-at` readers
+/// are better served by `de-de` than by the default. Returns `None` if the
+/// client accepts none of them.
+pub fn choose_language<'a>(header: &str, available: &[&'a str]) -> Option<&'a str> {
+    let prefs = parse_qlist(header);
+    let primary = |tag: &str| tag.split('-').next().unwrap_or("").to_ascii_lowercase();
+
+    let weight = |tag: &str| -> u16 {
+        let tag_lower = tag.to_ascii_lowercase();
+        let max_q = |matches: &dyn Fn(&str) -> bool| {
+            prefs.iter().filter(|p| matches(p.token)).map(|p| p.q).max()
+        };
+        let prefix = max_q(&|range| {
+            let range = range.to_ascii_lowercase();
+            tag_lower == range || tag_lower.starts_with(&format!("{}-", range))
+        });
+        let same_primary = max_q(&|range| range != "*" && primary(range) == primary(tag));
+        let wildcard = max_q(&|range| range == "*");
+        prefix.or(same_primary).or(wildcard).unwrap_or(0)
+    };
+
+    let mut best: Option<(&'a str, u16)> = None;
+    for &tag in available {
+        let q = weight(tag);
+        if q == 0 {
+            continue;
+        }
+        if best.map(|(_, bq)| q > bq).unwrap_or(true) {
+            best = Some((tag, q));
+        }
+    }
+    best.map(|(t, _)| t)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn qlist(header: &str) -> Vec<(&str, u16)> {
+        parse_qlist(header).iter().map(|p| (p.token, p.q)).collect()
+    }
+
+    #[test]
+    fn qlist_weights() {
+        assert_eq!(qlist("gzip, br;q=0.8, zstd;q=0"), [("gzip", 1000), ("br", 800), ("zstd", 0)]);
+        assert_eq!(qlist(" br ; Q=0.25 ,, identity;q=1.000"), [("br", 250), ("identity", 1000)]);
+        assert_eq!(qlist("br;level=5;q=0.5"), [("br", 500)]);
+    }
+
+    #[test]
+    fn qlist_drops_invalid_qvalues() {
+        assert_eq!(qlist("a;q=1.5, b;q=abc, c;q=0.1234, d;q=2, e;q, f;q=0.5"), [("f", 500)]);
+    }
+
+    #[test]
+    fn encoding_without_header_is_identity() {
+        assert_eq!(choose_encoding(None, &["br", "gzip", "identity"]), Some("identity"));
+        assert_eq!(choose_encoding(None, &["br"]), None);
+    }
+
+    #[test]
+    fn encoding_by_weight_then_server_order() {
+        let available = ["zstd", "br", "gzip", "identity"];
+        assert_eq!(choose_encoding(Some("gzip, br"), &available), Some("br"));
+        assert_eq!(choose_encoding(Some("gzip, br;q=0.9"), &available), Some("gzip"));
+        assert_eq!(choose_encoding(Some("*"), &available), Some("zstd"));
+        assert_eq!(choose_encoding(Some("x-gzip"), &available), Some("gzip"));
+        assert_eq!(choose_encoding(Some("deflate"), &available), Some("identity"));
+    }
+
+    #[test]
+    fn q_zero_excludes() {
+        let available = ["br", "gzip", "identity"];
+        assert_eq!(choose_encoding(Some("br;q=0, gzip"), &available), Some("gzip"));
+        assert_eq!(choose_encoding(Some("*;q=0, gzip;q=0.1"), &available), Some("gzip"));
+        assert_eq!(choose_encoding(Some("identity;q=0"), &available), None);
+        assert_eq!(choose_encoding(Some("*;q=0"), &available), None);
+        assert_eq!(choose_encoding(Some("br;q=0"), &["br", "identity"]), Some("identity"));
+    }
+
+    #[test]
+    fn languages() {
+        let available = ["en", "de"];
+        assert_eq!(choose_language("de-DE, en;q=0.5", &available), Some("de"));
+        assert_eq!(choose_language("de-AT", &available), Some("de"));
+        assert_eq!(choose_language("en;q=0.5, de;q=0.5", &available), Some("en"));
+        assert_eq!(choose_language("*", &available), Some("en"));
+        assert_eq!(choose_language("fr", &available), None);
+        assert_eq!(choose_language("de;q=0, *", &available), Some("en"));
+    }
+}
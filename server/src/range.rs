@@ -0,0 +1,231 @@
+//! HTTP Range requests (RFC 9110 §14)
+//!
+//! Only the `bytes` unit is supported. Ranges are always resolved against the
+//! identity representation: byte offsets into a brotli body would be
+//! meaningless to a client that asked for offsets into the file.
+
+use std::ops::Range;
+
+// More ranges than this in one request is either a broken client or an
+// attempt to make us build a huge multipart body out of tiny slices.
+const MAX_RANGES: usize = 16;
+
+#[derive(Debug, PartialEq)]
+pub enum RangeRequest {
+    /// No usable Range header: serve the full representation.
+    Full,
+    /// One or more satisfiable ranges, in the order the client asked, or
+    /// sorted and merged if any of them overlap.
+    Partial(Vec<Range<usize>>),
+    /// Syntactically valid but nothing overlaps the body: 416.
+    Unsatisfiable,
+}
+
+/// Parse a `Range` header value against a body of `len` bytes.
+///
+/// Anything we don't understand (unknown unit, malformed spec) degrades to
+/// `Full`, as RFC 9110 requires a server to ignore a Range it can't parse.
+pub fn parse(header: &str, len: usize) -> RangeRequest {
+    let specs = match header.trim().split_once('=') {
+        // Range units are case-insensitive.
+        Some((unit, specs)) if unit.eq_ignore_ascii_case("bytes") => specs,
+        _ => return RangeRequest::Full,
+    };
+
+    let mut ranges = Vec::new();
+    let mut count = 0;
+    for spec in specs.split(',') {
+        let spec = spec.trim();
+        if spec.is_empty() {
+            continue;
+        }
+        count += 1;
+        if count > MAX_RANGES {
+            return RangeRequest::Full;
+        }
+
+        let (first, last) = match spec.split_once('-') {
+            Some(parts) => parts,
+            None => return RangeRequest::Full,
+        };
+
+        let range = if first.is_empty() {
+            // Suffix range: the last N bytes.
+            let n: usize = match last.parse() {
+                Ok(n) => n,
+                Err(_) => return RangeRequest::Full,
+            };
+            if n == 0 || len == 0 {
+                continue;
+            }
+            len.saturating_sub(n)..len
+        } else {
+            let start: usize = match first.parse() {
+                Ok(n) => n,
+                Err(_) => return RangeRequest::Full,
+            };
+            let end = if last.is_empty() {
+                len
+            } else {
+                match last.parse::<usize>() {
+                    Ok(n) if n >= start => n.saturating_add(1).min(len),
+                    _ => return RangeRequest::Full,
+                }
+            };
+            if start >= len {
+                continue;
+            }
+            start..end
+        };
+        ranges.push(range);
+    }
+
+    if count == 0 {
+        RangeRequest::Full
+    } else if ranges.is_empty() {
+        RangeRequest::Unsatisfiable
+    } else {
+        RangeRequest::Partial(coalesce(ranges))
+    }
+}
+
+/// Overlapping ranges would send the same bytes more than once, so
+/// `bytes=0-,0-,0-` could make us send the body many times over. If any
+/// two overlap, they're all sorted and merged (RFC 9110 §14.2 allows it);
+/// otherwise the client's order is kept.
+fn coalesce(mut ranges: Vec<Range<usize>>) -> Vec<Range<usize>> {
+    let overlaps = ranges
+        .iter()
+        .enumerate()
+        .any(|(i, a)| ranges[i + 1..].iter().any(|b| a.start < b.end && b.start < a.end));
+    if !overlaps {
+        return ranges;
+    }
+    ranges.sort_by_key(|r| r.start);
+    let mut merged: Vec<Range<usize>> = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        match merged.last_mut() {
+            Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+            _ => merged.push(range),
+        }
+    }
+    merged
+}
+
+/// `Content-Range` value for one satisfied range.
+pub fn content_range(range: &Range<usize>, len: usize) -> String {
+    format!("bytes {}-{}/{}", range.start, range.end - 1, len)
+}
+
+/// `Content-Range` value for a 416 response.
+pub fn unsatisfied_range(len: usize) -> String {
+    format!("bytes */{}", len)
+}
+
+/// Build a `multipart/byteranges` body. `boundary` must match the one sent
+/// in the response's Content-Type.
+pub fn multipart_body(
+    content: &[u8],
+    ranges: &[Range<usize>],
+    content_type: &str,
+    boundary: &str,
+) -> Vec<u8> {
+    let total: usize = ranges.iter().map(|r| r.len() + 128).sum();
+    let mut body = Vec::with_capacity(total);
+    for range in ranges {
+        body.extend_from_slice(b"\r\n--");
+        body.extend_from_slice(boundary.as_bytes());
+        body.extend_from_slice(b"\r\nContent-Type: ");
+        body.extend_from_slice(content_type.as_bytes());
+        body.extend_from_slice(b"\r\nContent-Range: ");
+        body.extend_from_slice(content_range(range, content.len()).as_bytes());
+        body.extend_from_slice(b"\r\n\r\n");
+        body.extend_from_slice(&content[range.clone()]);
+    }
+    body.extend_from_slice(b"\r\n--");
+    body.extend_from_slice(boundary.as_bytes());
+    body.extend_from_slice(b"--\r\n");
+    body
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use RangeRequest::{Full, Unsatisfiable};
+
+    fn partial(ranges: &[(usize, usize)]) -> RangeRequest {
+        RangeRequest::Partial(ranges.iter().map(|&(start, end)| start..end).collect())
+    }
+
+    #[test]
+    fn single_ranges() {
+        assert_eq!(parse("bytes=0-4", 10), partial(&[(0, 5)]));
+        assert_eq!(parse("bytes=5-", 10), partial(&[(5, 10)]));
+        assert_eq!(parse("bytes=5-100", 10), partial(&[(5, 10)]));
+        assert_eq!(parse(" bytes=9-9 ", 10), partial(&[(9, 10)]));
+    }
+
+    #[test]
+    fn suffix_ranges() {
+        assert_eq!(parse("bytes=-3", 10), partial(&[(7, 10)]));
+        assert_eq!(parse("bytes=-20", 10), partial(&[(0, 10)]));
+        assert_eq!(parse("bytes=-0", 10), Unsatisfiable);
+        assert_eq!(parse("bytes=-5", 0), Unsatisfiable);
+    }
+
+    #[test]
+    fn multiple_ranges_keep_their_order() {
+        assert_eq!(parse("bytes=6-7, 0-1,-2", 10), partial(&[(6, 8), (0, 2), (8, 10)]));
+        // Adjacent ranges don't overlap and stay apart.
+        assert_eq!(parse("bytes=2-3,0-1", 10), partial(&[(2, 4), (0, 2)]));
+        // Unsatisfiable parts are dropped while others remain.
+        assert_eq!(parse("bytes=20-30,0-0", 10), partial(&[(0, 1)]));
+        assert_eq!(parse("bytes=0-1,,2-3", 10), partial(&[(0, 2), (2, 4)]));
+    }
+
+    #[test]
+    fn overlapping_ranges_are_merged() {
+        assert_eq!(parse("bytes=0-5,3-8", 10), partial(&[(0, 9)]));
+        assert_eq!(parse("bytes=6-8,-2,0-1", 10), partial(&[(0, 2), (6, 10)]));
+        let repeated = format!("bytes={}", vec!["0-"; MAX_RANGES].join(","));
+        assert_eq!(parse(&repeated, 10), partial(&[(0, 10)]));
+    }
+
+    #[test]
+    fn unit_is_case_insensitive() {
+        assert_eq!(parse("Bytes=0-1", 10), partial(&[(0, 2)]));
+        assert_eq!(parse("BYTES=0-1", 10), partial(&[(0, 2)]));
+    }
+
+    #[test]
+    fn nothing_satisfiable() {
+        assert_eq!(parse("bytes=10-", 10), Unsatisfiable);
+        assert_eq!(parse("bytes=10-20,15-", 10), Unsatisfiable);
+    }
+
+    #[test]
+    fn anything_unparseable_serves_the_full_body() {
+        for header in ["items=0-1", "bytes=", "bytes=1", "bytes=5-2", "bytes=a-b", "bytes=0-1,x"] {
+            assert_eq!(parse(header, 10), Full, "{}", header);
+        }
+        let too_many = format!("bytes={}", vec!["0-0"; MAX_RANGES + 1].join(","));
+        assert_eq!(parse(&too_many, 10), Full);
+    }
+
+    #[test]
+    fn content_range_values() {
+        assert_eq!(content_range(&(0..5), 10), "bytes 0-4/10");
+        assert_eq!(unsatisfied_range(10), "bytes */10");
+    }
+
+    #[test]
+    fn multipart_parts() {
+        let body = multipart_body(b"0123456789", &[0..2, 8..10], "text/plain", "SEP");
+        assert_eq!(
+            String::from_utf8(body).unwrap(),
+            "\r\n--SEP\r\nContent-Type: text/plain\r\nContent-Range: bytes 0-1/10\r\n\r\n01\
+             \r\n--SEP\r\nContent-Type: text/plain\r\nContent-Range: bytes 8-9/10\r\n\r\n89\
+             \r\n--SEP--\r\n"
+        );
+    }
+}
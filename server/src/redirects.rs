@@ -0,0 +1,215 @@
+//! Redirect rules compiled in from `public/_redirects`
+//!
+//! build.rs validates the file and emits the rules in order; here they are
+//! split into an exact-path map and an ordered list of patterns. Exact rules
+//! win over patterns, and among patterns the first match wins.
+//!
+//! Only consulted once the route table has no asset for a path, so a rule
+//! can never shadow a page that exists.
+
+use std::collections::HashMap;
+
+use crate::assets::{get_redirects, Redirect};
+
+lazy_static::lazy_static! {
+    static ref TABLE: RedirectTable = RedirectTable::new(get_redirects());
+}
+
+struct RedirectTable {
+    exact: HashMap<&'static str, Redirect>,
+    patterns: Vec<Redirect>,
+}
+
+/// Outcome of a matching rule.
+pub struct Target {
+    pub status: u16,
+    /// Where to send the client; `None` for 410 Gone.
+    pub location: Option<String>,
+}
+
+impl Target {
+    /// Whether clients and caches should treat the move as permanent.
+    pub fn is_permanent(&self) -> bool {
+        matches!(self.status, 301 | 308)
+    }
+}
+
+impl RedirectTable {
+    fn new(rules: Vec<Redirect>) -> Self {
+        let mut exact = HashMap::new();
+        let mut patterns = Vec::new();
+        for rule in rules {
+            if rule.from.contains([':', '*']) {
+                patterns.push(rule);
+            } else {
+                // Earlier rules win, as with patterns.
+                exact.entry(rule.from).or_insert(rule);
+            }
+        }
+        Self { exact, patterns }
+    }
+}
+
+pub fn count() -> usize {
+    TABLE.exact.len() + TABLE.patterns.len()
+}
+
+/// Find the rule for `path`, with its placeholders filled in.
+pub fn lookup(path: &str) -> Option<Target> {
+    let trimmed = if path.len() > 1 { path.trim_end_matches('/') } else { path };
+
+    if let Some(rule) = TABLE.exact.get(path).or_else(|| TABLE.exact.get(trimmed)) {
+        return Some(target(rule, &[]));
+    }
+
+    TABLE
+        .patterns
+        .iter()
+        .find_map(|rule| captures(rule.from, path).map(|c| target(rule, &c)))
+}
+
+fn target(rule: &Redirect, captures: &[(&str, &str)]) -> Target {
+    let location = (!rule.to.is_empty()).then(|| {
+        let mut location = rule.to.to_string();
+        // Longest names first, so `:slug` doesn't eat the front of `:slugs`.
+        let mut captures = captures.to_vec();
+        captures.sort_by_key(|(name, _)| std::cmp::Reverse(name.len()));
+        for (name, value) in captures {
+            location = location.replace(&format!(":{}", name), value);
+        }
+        // Captures come from the request, so they must not turn a local
+        // target into another host: `/old/* /:splat` would send
+        // `/old//evil.com` to `//evil.com`. Browsers read `/\` as `//` too.
+        if !is_absolute(rule.to) {
+            let path = location.trim_start_matches(['/', '\\']);
+            location = if location.starts_with(['/', '\\']) || is_absolute(path) {
+                format!("/{}", path)
+            } else {
+                path.to_string()
+            };
+        }
+        location
+    });
+    Target { status: rule.status, location }
+}
+
+/// Whether `url` names its own host: a scheme (`https:`) or `//host`.
+fn is_absolute(url: &str) -> bool {
+    let has_scheme = url.split_once(':').is_some_and(|(scheme, _)| {
+        !scheme.is_empty() && scheme.bytes().all(|b| b.is_ascii_alphanumeric() || b"+-.".contains(&b))
+    });
+    has_scheme || url.starts_with("//")
+}
+
+/// Match `path` against a pattern, returning `(name, value)` captures.
+/// `:name` matches one non-empty segment; a trailing `*` matches the rest
+/// of the path (possibly empty) and is captured as `splat`.
+fn captures<'a>(pattern: &'a str, path: &'a str) -> Option<Vec<(&'a str, &'a str)>> {
+    let mut captures = Vec::new();
+    let (pattern, splat) = match pattern.strip_suffix('*') {
+        Some(prefix) => (prefix, true),
+        None => (pattern, false),
+    };
+
+    let mut rest = path;
+    for segment in pattern.split_inclusive('/') {
+        if let Some(name) = segment.strip_prefix(':') {
+            let (name, slash) = match name.strip_suffix('/') {
+                Some(n) => (n, true),
+                None => (name, false),
+            };
+            let end = rest.find('/').unwrap_or(rest.len());
+            if end == 0 {
+                return None;
+            }
+            captures.push((name, &rest[..end]));
+            rest = &rest[end..];
+            if slash {
+                rest = rest.strip_prefix('/')?;
+            }
+        } else if let Some(after) = rest.strip_prefix(segment) {
+            rest = after;
+        } else if splat && segment.ends_with('/') && rest == &segment[..segment.len() - 1] {
+            // `/blog/*` also covers `/blog` itself.
+            rest = "";
+        } else {
+            return None;
+        }
+    }
+
+    if splat {
+        captures.push(("splat", rest));
+        Some(captures)
+    } else if rest.is_empty() || rest == "/" {
+        Some(captures)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(from: &'static str, to: &'static str) -> Redirect {
+        Redirect { from, to, status: 301 }
+    }
+
+    fn location(from: &'static str, to: &'static str, path: &str) -> Option<String> {
+        let rule = rule(from, to);
+        let captures = captures(rule.from, path)?;
+        target(&rule, &captures).location
+    }
+
+    #[test]
+    fn named_segments() {
+        assert_eq!(
+            captures("/blog/:year/:slug", "/blog/2020/hello"),
+            Some(vec![("year", "2020"), ("slug", "hello")])
+        );
+        assert_eq!(
+            captures("/blog/:year/:slug", "/blog/2020/hello/"),
+            Some(vec![("year", "2020"), ("slug", "hello")])
+        );
+        assert_eq!(captures("/blog/:year/:slug", "/blog/2020"), None);
+        assert_eq!(captures("/blog/:year/:slug", "/blog//hello"), None);
+        assert_eq!(captures("/blog/:year/:slug", "/blog/2020/hello/more"), None);
+    }
+
+    #[test]
+    fn wildcard_captures_the_rest() {
+        assert_eq!(captures("/old/*", "/old/a/b"), Some(vec![("splat", "a/b")]));
+        assert_eq!(captures("/old/*", "/old/"), Some(vec![("splat", "")]));
+        assert_eq!(captures("/old/*", "/old"), Some(vec![("splat", "")]));
+        assert_eq!(captures("/old/*", "/older"), None);
+        assert_eq!(captures("/:lang/old/*", "/de/old/x"), Some(vec![("lang", "de"), ("splat", "x")]));
+    }
+
+    #[test]
+    fn placeholders_are_substituted() {
+        assert_eq!(location("/old/*", "/new/:splat", "/old/a/b").as_deref(), Some("/new/a/b"));
+        assert_eq!(location("/p/:slug/:slugs", "/:slugs/:slug", "/p/a/b").as_deref(), Some("/b/a"));
+        assert_eq!(
+            location("/feed/*", "https://example.org/:splat", "/feed/x").as_deref(),
+            Some("https://example.org/x")
+        );
+    }
+
+    #[test]
+    fn captures_cannot_redirect_off_site() {
+        assert_eq!(location("/old/*", "/:splat", "/old//evil.com").as_deref(), Some("/evil.com"));
+        assert_eq!(location("/old/*", "/:splat", "/old/\\evil.com").as_deref(), Some("/evil.com"));
+        assert_eq!(
+            location("/old/*", ":splat", "/old/https://evil.com").as_deref(),
+            Some("/https://evil.com")
+        );
+        assert_eq!(location("/old/*", ":splat", "/old//evil.com").as_deref(), Some("/evil.com"));
+        assert_eq!(location("/old/*", "posts/:splat", "/old/x").as_deref(), Some("posts/x"));
+    }
+
+    #[test]
+    fn gone_has_no_location() {
+        let rule = Redirect { from: "/gone", to: "", status: 410 };
+        assert_eq!(target(&rule, &[]).location, None);
+    }
+}
@@ -0,0 +1,305 @@
+//! Live configuration reload on SIGHUP
+//!
+//! The config file and environment are read again and checked as a whole
+//! before anything changes: an invalid config, header policy, certificate
+//! or access log file is logged and the running configuration kept. A good
+//! one replaces, in one step, everything read per connection or request:
+//! limits, timeouts, toggles, the header policy, the access log and the TLS
+//! configs new handshakes use. Open connections carry on untouched, so
+//! WebSocket dashboards stay connected and Gemini clients keep seeing the
+//! certificate they pinned.
+//!
+//! Listener addresses, the domain and the ACME setup are only read at
+//! startup. Changing them is logged as needing a restart, or a SIGUSR2
+//! upgrade, which keeps the sockets.
+
+use parking_lot::{Mutex, RwLock};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::signal::unix::{signal, Signal, SignalKind};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::TlsAcceptor;
+
+use crate::access_log;
+use crate::acme::{self, CertificateData};
+use crate::canonical;
+use crate::config::{self, Config, ConfigError};
+use crate::gemini;
+use crate::headers;
+use crate::https;
+use crate::shutdown;
+use crate::systemd;
+
+// Settings only read at startup; a trailing `.` covers a whole section.
+const NEEDS_RESTART: &[&str] = &[
+    "domain",
+    "state_directory",
+    "http.listen",
+    "https.listen",
+    "https.redirect",
+    "https.redirect_listen",
+    "acme.",
+    "gemini.enabled",
+    "gemini.listen",
+    "gemini.cert",
+];
+
+/// A connection cap that follows the config. The one semaphore is resized
+/// in place, so connections already holding a permit count against the new
+/// size too: after shrinking below what's in flight, permits coming back
+/// are taken out of circulation until the cap holds again.
+pub struct Limit {
+    max: fn(&Config) -> usize,
+    semaphore: Arc<Semaphore>,
+    /// The size in effect, and permits still owed from shrinking it.
+    size: Mutex<(usize, usize)>,
+}
+
+impl Limit {
+    pub fn new(max: fn(&Config) -> usize) -> Self {
+        let size = max(&config::current());
+        Self { max, semaphore: Arc::new(Semaphore::new(size)), size: Mutex::new((size, 0)) }
+    }
+
+    /// A permit, or `None` at the cap.
+    pub fn try_acquire(&self) -> Option<OwnedSemaphorePermit> {
+        self.try_acquire_at((self.max)(&config::current()))
+    }
+
+    fn try_acquire_at(&self, max: usize) -> Option<OwnedSemaphorePermit> {
+        let mut size = self.size.lock();
+        let (current, owed) = &mut *size;
+        if max > *current {
+            let repaid = (max - *current).min(*owed);
+            *owed -= repaid;
+            self.semaphore.add_permits(max - *current - repaid);
+        } else {
+            *owed += *current - max;
+        }
+        *current = max;
+
+        loop {
+            *owed -= self.semaphore.forget_permits(*owed);
+            let permit = Arc::clone(&self.semaphore).try_acquire_owned().ok()?;
+            if *owed == 0 {
+                return Some(permit);
+            }
+            // Released since the forget above, so it's owed as well.
+            permit.forget();
+            *owed -= 1;
+        }
+    }
+}
+
+/// The TLS config a listener hands each new connection. Handshakes already
+/// under way finish with the one they started with.
+pub struct TlsConfig(RwLock<Arc<ServerConfig>>);
+
+impl TlsConfig {
+    pub fn new(config: Arc<ServerConfig>) -> Arc<Self> {
+        Arc::new(Self(RwLock::new(config)))
+    }
+
+    pub fn acceptor(&self) -> TlsAcceptor {
+        TlsAcceptor::from(Arc::clone(&self.0.read()))
+    }
+
+    fn replace(&self, config: Arc<ServerConfig>) {
+        *self.0.write() = config;
+    }
+}
+
+/// The self-signed Gemini certificate persisted in the state directory the
+/// server started with. Clients pin it, so a reload only ever picks up
+/// files replaced there by hand: never a new `state_directory` (that takes
+/// a restart) and never a freshly generated certificate.
+pub struct PersistedCert {
+    tls: Arc<TlsConfig>,
+    dir: PathBuf,
+    /// The pair `tls` presents, so unchanged files aren't reloaded.
+    loaded: Mutex<CertificateData>,
+}
+
+impl PersistedCert {
+    pub fn new(tls: Arc<TlsConfig>, dir: &Path, loaded: CertificateData) -> Self {
+        Self { tls, dir: dir.to_path_buf(), loaded: Mutex::new(loaded) }
+    }
+}
+
+/// What a reload needs besides the config: the file to read and the TLS
+/// configs it may replace. Those are `None` where the certificate isn't
+/// ours to reload: ACME renews its own, and a self-signed Gemini
+/// certificate without a state directory exists only in memory.
+pub struct Reloader {
+    pub path: Option<PathBuf>,
+    pub https: Option<Arc<TlsConfig>>,
+    pub gemini: Option<PersistedCert>,
+}
+
+/// Take over SIGHUP, whose default action would kill the process. Done
+/// before the listeners start; signals that arrive meanwhile wait here.
+pub fn hangups() -> Signal {
+    signal(SignalKind::hangup()).expect("Failed to install SIGHUP handler")
+}
+
+/// Reload on every SIGHUP until shutdown.
+pub async fn on_signal(mut hangups: Signal, reloader: Reloader) {
+    loop {
+        tokio::select! {
+            _ = hangups.recv() => {}
+            _ = shutdown::triggered() => return,
+        }
+        println!("Received SIGHUP, reloading configuration");
+        systemd::notify(&format!("RELOADING=1\nMONOTONIC_USEC={}", systemd::monotonic_usec()));
+        if let Err(errors) = reloader.reload() {
+            eprintln!("Reload failed, keeping the current configuration:");
+            for error in &errors {
+                eprintln!("  {}", error);
+            }
+        }
+        systemd::notify("READY=1");
+    }
+}
+
+impl Reloader {
+    fn reload(&self) -> Result<(), Vec<ConfigError>> {
+        let (config, policy) = config::load_with_headers(self.path.as_deref())?;
+        let changes = config::changes(&config::current(), &config);
+        let error = |location: &Path, key: &str, message: String| {
+            vec![ConfigError { location: Some(location.display().to_string()), key: key.to_string(), message }]
+        };
+
+        let read = |path: &Path, key: &str| {
+            fs::read_to_string(path).map_err(|e| error(path, key, e.to_string()))
+        };
+
+        // Everything that can fail comes first, so a failure changes nothing.
+        let https_tls = match (&self.https, &config.https.cert, &config.https.key) {
+            (Some(_), Some(cert), Some(key)) => {
+                let data = CertificateData {
+                    cert_pem: read(cert, "https.cert")?,
+                    privkey_pem: read(key, "https.key")?,
+                };
+                Some(https::tls_config(&data).map_err(|e| error(cert, "https.cert", e.to_string()))?)
+            }
+            _ => None,
+        };
+        let gemini_tls = match &self.gemini {
+            Some(gemini) => {
+                let (cert, key) = acme::persistent_certificate_paths(&gemini.dir);
+                let data = CertificateData {
+                    cert_pem: read(&cert, "gemini.cert")?,
+                    privkey_pem: read(&key, "gemini.cert")?,
+                };
+                if data == *gemini.loaded.lock() {
+                    None
+                } else {
+                    let tls = acme::build_tls_config(&data.cert_pem, &data.privkey_pem)
+                        .map_err(|e| error(&cert, "gemini.cert", e.to_string()))?;
+                    Some((tls, data))
+                }
+            }
+            None => None,
+        };
+        if changes.iter().any(|(path, _)| path.starts_with("access_log.")) {
+            access_log::configure_from(&config).map_err(|e| vec![ConfigError {
+                location: None,
+                key: "access_log.file".to_string(),
+                message: format!("can't open: {}", e),
+            }])?;
+        }
+
+        let policy_file = config.headers_file.clone();
+        headers::set_policy(policy.unwrap_or_else(headers::HeaderPolicy::default_policy));
+        canonical::set_enabled(config.canonical_urls);
+        gemini::set_not_found_suggestions(config.gemini.not_found_suggestions);
+        let mut certificates = Vec::new();
+        if let (Some(slot), Some(tls)) = (&self.https, https_tls) {
+            slot.replace(tls);
+            certificates.push("HTTPS");
+        }
+        if let (Some(gemini), Some((tls, data))) = (&self.gemini, gemini_tls) {
+            gemini.tls.replace(tls);
+            *gemini.loaded.lock() = data;
+            certificates.push("Gemini");
+        }
+        config::set(config);
+
+        if changes.is_empty() {
+            println!("Reloaded configuration, no settings changed");
+        } else {
+            println!("Reloaded configuration, {} settings changed:", changes.len());
+            for (path, value) in &changes {
+                println!("  {} = {}", path, value);
+            }
+        }
+        if let Some(file) = policy_file {
+            println!("Reloaded header policy from {}", file.display());
+        }
+        if !certificates.is_empty() {
+            println!("Reloaded {} certificates for new connections", certificates.join(" and "));
+        }
+        // Certificate paths only apply live where the files were reloaded.
+        let restart: Vec<&str> = changes
+            .iter()
+            .map(|(path, _)| *path)
+            .filter(|path| {
+                NEEDS_RESTART.iter().any(|r| path == r || (r.ends_with('.') && path.starts_with(r)))
+                    || (*path == "https.cert" || *path == "https.key") && !certificates.contains(&"HTTPS")
+            })
+            .collect();
+        if !restart.is_empty() {
+            eprintln!("Changed settings that take a restart to apply: {}", restart.join(", "));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limit(size: usize) -> Limit {
+        Limit { max: |_| 0, semaphore: Arc::new(Semaphore::new(size)), size: Mutex::new((size, 0)) }
+    }
+
+    #[test]
+    fn growing_adds_permits() {
+        let limit = limit(1);
+        let first = limit.try_acquire_at(1);
+        assert!(first.is_some());
+        assert!(limit.try_acquire_at(1).is_none());
+        let second = limit.try_acquire_at(2);
+        assert!(second.is_some());
+        assert!(limit.try_acquire_at(2).is_none());
+    }
+
+    #[test]
+    fn shrinking_counts_connections_in_flight() {
+        let limit = limit(3);
+        let mut held: Vec<_> = (0..3).map(|_| limit.try_acquire_at(3).unwrap()).collect();
+        assert!(limit.try_acquire_at(1).is_none());
+        held.pop();
+        assert!(limit.try_acquire_at(1).is_none());
+        held.pop();
+        assert!(limit.try_acquire_at(1).is_none());
+        held.pop();
+        let only = limit.try_acquire_at(1);
+        assert!(only.is_some());
+        assert!(limit.try_acquire_at(1).is_none());
+    }
+
+    #[test]
+    fn growing_again_repays_what_shrinking_owed() {
+        let limit = limit(4);
+        let held: Vec<_> = (0..4).map(|_| limit.try_acquire_at(4).unwrap()).collect();
+        assert!(limit.try_acquire_at(2).is_none());
+        let extra = limit.try_acquire_at(5);
+        assert!(extra.is_some());
+        drop((held, extra));
+        let again: Vec<_> = (0..5).map_while(|_| limit.try_acquire_at(5)).collect();
+        assert_eq!(again.len(), 5);
+    }
+}
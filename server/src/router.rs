@@ -1,91 +1,372 @@
-use hyper::{Body, Request, Response, StatusCode, header};
+use hyper::body::HttpBody;
+use hyper::{Body, Method, Request, Response, StatusCode, header};
 use std::convert::Infallible;
 use std::collections::HashMap;
 use std::sync::Arc;
 use std::time::Instant;
+use crate::access_log;
+use crate::acme;
 use crate::assets::{Asset, get_routes};
+use crate::canonical;
+use crate::conditional::{self, Outcome};
+use crate::date;
+use crate::early_hints;
+use crate::headers;
 use crate::metrics::Metrics;
+use crate::negotiate;
+use crate::range::{self, RangeRequest};
+use crate::redirects;
+use crate::suggest;
 use crate::websocket;
 
+// Everything we serve is static; there is nothing to POST to.
+const ALLOWED_METHODS: &str = "GET, HEAD, OPTIONS";
+
+// Set by the theme's language switcher to the chosen language's tag.
+const LANGUAGE_COOKIE: &str = "lang";
+// Set by the theme toggle to `light` or `dark`.
+const THEME_COOKIE: &str = "theme";
+
+/// Per-page CSP computed by build.rs, carried from `serve_asset` to `route`
+/// so it can take precedence over the header policy's generic one.
+#[derive(Clone, Copy)]
+struct PageCsp(&'static str);
+
+/// A page's preload `Link` value, carried to `route` so it can go out as
+/// 103 Early Hints too.
+#[derive(Clone, Copy)]
+struct Preload(&'static str);
+
 lazy_static::lazy_static! {
     static ref ROUTES: HashMap<&'static str, &'static Asset> = get_routes();
+    // Page URLs as visitors see them, for 404 suggestions.
+    static ref PAGES: Vec<&'static str> = {
+        let mut pages: Vec<&'static str> = ROUTES
+            .keys()
+            .filter_map(|route| route.strip_suffix("index.html"))
+            .collect();
+        pages.sort_unstable();
+        pages
+    };
 }
 
+const MAX_SUGGESTIONS: usize = 3;
+
 pub fn route_count() -> usize {
     ROUTES.len()
 }
 
+/// Serve `/` in-process, the way a visitor would get it minus logging and
+/// metrics, for the watchdog's health check. True unless it fails with a
+/// server error.
+pub fn self_check() -> bool {
+    let req = Request::get("/").body(Body::empty()).unwrap();
+    let mut response = serve_path(&req, "/");
+    headers::policy().apply("/", &mut response);
+    !response.status().is_server_error()
+}
+
 pub async fn route(req: Request<Body>, metrics: Arc<Metrics>) -> Result<Response<Body>, Infallible> {
     let path = req.uri().path();
+    let start = Instant::now();
 
     // Check for WebSocket metrics endpoint
     if path == "/__metrics__/ws" {
-        return match websocket::handle_websocket(req, Arc::clone(&metrics)).await {
-            Ok(response) => Ok(response),
+        let log_entry = access_log::enabled().then(|| access_log::Entry::http(&req, "websocket"));
+        let response = match websocket::handle_websocket(req, Arc::clone(&metrics)).await {
+            Ok(response) => response,
             Err(e) => {
                 eprintln!("WebSocket upgrade error: {}", e);
-                Ok(Response::builder()
+                Response::builder()
                     .status(StatusCode::INTERNAL_SERVER_ERROR)
                     .body(Body::from("WebSocket upgrade failed"))
-                    .unwrap())
+                    .unwrap()
             }
         };
+        if let Some(entry) = log_entry {
+            log_response(entry, &response, start);
+        }
+        return Ok(response);
     }
 
-    let start = Instant::now();
     metrics.increment_connections();
 
-    let response = {
-        // Try exact match first
-        if let Some(asset) = ROUTES.get(path) {
-            serve_asset(asset, &req, path)
-        }
-        // Try with /index.html appended for directory routes
-        else if let Some(asset) = {
-            let with_index = if path.ends_with('/') {
-                format!("{}index.html", path)
-            } else {
-                format!("{}/index.html", path)
-            };
-            ROUTES.get(with_index.as_str())
-        } {
-            let with_index = if path.ends_with('/') {
-                format!("{}index.html", path)
-            } else {
-                format!("{}/index.html", path)
-            };
-            serve_asset(asset, &req, &with_index)
-        }
-        // Try removing trailing slash
-        else if path.ends_with('/') && path.len() > 1 {
-            let without_slash = &path[..path.len() - 1];
-            if let Some(asset) = ROUTES.get(without_slash) {
-                serve_asset(asset, &req, without_slash)
-            } else {
-                // 404
-                serve_404()
-            }
-        }
-        // 404
-        else {
-            serve_404()
+    let redirect = if canonical::enabled() { canonical_redirect(&req) } else { None };
+
+    let mut response = if let Some(redirect) = redirect {
+        redirect
+    } else {
+        for_method(req.method(), serve_path(&req, path))
+    };
+
+    // The preloads go out first as 103 Early Hints, ahead of the response
+    // that repeats them for clients that ignore 1xx.
+    if let Some(Preload(links)) = response.extensions().get::<Preload>().copied() {
+        if req.method() == Method::GET {
+            early_hints::send(&req, links).await;
         }
+    }
+
+    headers::policy().apply(path, &mut response);
+    if let Some(PageCsp(csp)) = response.extensions().get::<PageCsp>().copied() {
+        response
+            .headers_mut()
+            .insert(header::CONTENT_SECURITY_POLICY, header::HeaderValue::from_static(csp));
+    }
+
+    // HEAD gets exactly the GET headers, including the Content-Length of the
+    // body it would have received.
+    let response = if req.method() == Method::HEAD {
+        strip_body(response)
+    } else {
+        response
     };
 
     metrics.record_request(start.elapsed());
     metrics.decrement_connections();
 
+    if access_log::enabled() {
+        log_response(access_log::Entry::http(&req, "http"), &response, start);
+    }
+
     Ok(response)
 }
 
-fn serve_404() -> Response<Body> {
-    // Check if we have a custom 404.html
-    if let Some(not_found_asset) = ROUTES.get("/404.html") {
+fn log_response(mut entry: access_log::Entry, response: &Response<Body>, start: Instant) {
+    entry.status = response.status().as_u16();
+    entry.bytes = response.body().size_hint().exact().unwrap_or(0);
+    entry.encoding = response
+        .headers()
+        .get(header::CONTENT_ENCODING)
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string);
+    entry.latency = start.elapsed();
+    access_log::log(entry);
+}
+
+fn serve_path(req: &Request<Body>, path: &str) -> Response<Body> {
+    if let Some(response) = acme_challenge(path) {
+        return response;
+    }
+
+    // The root is where first-time visitors land, so that's where language
+    // is negotiated. Every other URL is served in the language it names.
+    if path == "/" {
+        let Some(root) = ROUTES.get("/index.html").filter(|a| !a.alternates.is_empty()) else {
+            return serve_route(req, path);
+        };
+        let mut response = language_redirect(req, root).unwrap_or_else(|| serve_route(req, path));
+        response
+            .headers_mut()
+            .append(header::VARY, header::HeaderValue::from_static("Accept-Language, Cookie"));
+        return response;
+    }
+
+    serve_route(req, path)
+}
+
+fn serve_route(req: &Request<Body>, path: &str) -> Response<Body> {
+    // Try exact match first
+    if let Some(asset) = ROUTES.get(path) {
+        serve_asset(asset, req, path)
+    }
+    // Try with /index.html appended for directory routes
+    else if let Some(asset) = {
+        let with_index = if path.ends_with('/') {
+            format!("{}index.html", path)
+        } else {
+            format!("{}/index.html", path)
+        };
+        ROUTES.get(with_index.as_str())
+    } {
+        let with_index = if path.ends_with('/') {
+            format!("{}index.html", path)
+        } else {
+            format!("{}/index.html", path)
+        };
+        serve_asset(asset, req, &with_index)
+    }
+    // Try removing trailing slash
+    else if path.ends_with('/') && path.len() > 1 {
+        let without_slash = &path[..path.len() - 1];
+        if let Some(asset) = ROUTES.get(without_slash) {
+            serve_asset(asset, req, without_slash)
+        } else {
+            serve_redirect_or_404(path)
+        }
+    }
+    // Redirect rules, then 404
+    else {
+        serve_redirect_or_404(path)
+    }
+}
+
+fn serve_redirect_or_404(path: &str) -> Response<Body> {
+    let Some(target) = redirects::lookup(path) else {
+        return serve_404(path);
+    };
+    let status = StatusCode::from_u16(target.status).unwrap_or(StatusCode::MOVED_PERMANENTLY);
+    match target.location {
+        Some(location) => Response::builder()
+            .status(status)
+            .header(header::LOCATION, location)
+            .body(Body::empty())
+            .unwrap(),
+        None => Response::builder()
+            .status(status)
+            .header(header::CONTENT_TYPE, "text/plain; charset=utf-8")
+            .body(Body::from("410 Gone"))
+            .unwrap(),
+    }
+}
+
+/// Send a visitor on the root to their language's home page: the language
+/// in the choice cookie if set (the switcher sets it, so an explicit choice
+/// always sticks), otherwise the best match for Accept-Language. `None`
+/// means the default language, i.e. stay on `/`.
+fn language_redirect(req: &Request<Body>, root: &Asset) -> Option<Response<Body>> {
+    let tags: Vec<&str> = root.alternates.iter().map(|(lang, _)| *lang).collect();
+    let primary = |tag: &str| tag.split('-').next().unwrap_or("").to_ascii_lowercase();
+
+    let chosen = match cookie(req, LANGUAGE_COOKIE) {
+        Some(choice) => tags.iter().copied().find(|t| primary(t) == primary(choice)),
+        None => req
+            .headers()
+            .get(header::ACCEPT_LANGUAGE)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|h| negotiate::choose_language(h, &tags)),
+    }?;
+    let (_, home) = root.alternates.iter().find(|(lang, _)| *lang == chosen)?;
+    if *home == "/" {
+        return None;
+    }
+
+    Some(
+        Response::builder()
+            .status(StatusCode::FOUND)
+            .header(header::LOCATION, *home)
+            .header(header::CACHE_CONTROL, "private, no-cache")
+            .body(Body::empty())
+            .unwrap(),
+    )
+}
+
+/// Value of the request cookie `name`, if present.
+fn cookie<'a>(req: &'a Request<Body>, name: &str) -> Option<&'a str> {
+    req.headers()
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(';'))
+        .find_map(|pair| {
+            let (key, value) = pair.trim().split_once('=')?;
+            (key == name).then_some(value)
+        })
+}
+
+/// Redirect to the canonical URL of the page `req` asks for, if it used a
+/// different form. 308 for methods other than GET/HEAD so they aren't
+/// rewritten to GET along the way.
+fn canonical_redirect(req: &Request<Body>) -> Option<Response<Body>> {
+    let canonical = canonical::canonicalize(req.uri().path(), "index.html", |p| {
+        if p.ends_with('/') {
+            ROUTES.contains_key(format!("{}index.html", p).as_str())
+        } else {
+            ROUTES.contains_key(p)
+        }
+    })?;
+
+    let location = match req.uri().query() {
+        Some(query) => format!("{}?{}", canonical, query),
+        None => canonical,
+    };
+    let status = if matches!(*req.method(), Method::GET | Method::HEAD) {
+        StatusCode::MOVED_PERMANENTLY
+    } else {
+        StatusCode::PERMANENT_REDIRECT
+    };
+    Some(
+        Response::builder()
+            .status(status)
+            .header(header::LOCATION, location)
+            .body(Body::empty())
+            .unwrap(),
+    )
+}
+
+/// Answer an ACME HTTP-01 validation request, if `path` is one we're
+/// expecting.
+pub fn acme_challenge(path: &str) -> Option<Response<Body>> {
+    let token = path.strip_prefix("/.well-known/acme-challenge/")?;
+    let key_authorization = acme::http01_key_authorization(token)?;
+    Some(
         Response::builder()
+            .status(StatusCode::OK)
+            .header(header::CONTENT_TYPE, "application/octet-stream")
+            .body(Body::from(key_authorization))
+            .unwrap(),
+    )
+}
+
+/// Turn the GET response for a path into the one for `method`. A path that
+/// doesn't exist is a 404 whatever the method; otherwise OPTIONS lists what
+/// the path allows and anything but GET or HEAD is refused.
+fn for_method(method: &Method, response: Response<Body>) -> Response<Body> {
+    match *method {
+        Method::GET | Method::HEAD => response,
+        _ if response.status() == StatusCode::NOT_FOUND => response,
+        Method::OPTIONS => Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .header(header::ALLOW, ALLOWED_METHODS)
+            .body(Body::empty())
+            .unwrap(),
+        _ => Response::builder()
+            .status(StatusCode::METHOD_NOT_ALLOWED)
+            .header(header::ALLOW, ALLOWED_METHODS)
+            .header(header::CONTENT_TYPE, "text/plain; charset=utf-8")
+            .body(Body::from("405 Method Not Allowed"))
+            .unwrap(),
+    }
+}
+
+fn strip_body(response: Response<Body>) -> Response<Body> {
+    let (mut parts, body) = response.into_parts();
+    let has_length = !matches!(parts.status, StatusCode::NO_CONTENT | StatusCode::NOT_MODIFIED);
+    if let (true, Some(len)) = (has_length, body.size_hint().exact()) {
+        parts.headers.insert(header::CONTENT_LENGTH, header::HeaderValue::from(len));
+    }
+    Response::from_parts(parts, Body::empty())
+}
+
+/// 404 in the language of the path (`/de/404.html` for `/de/...`, falling
+/// back to `/404.html`), listing the closest existing pages.
+fn serve_404(path: &str) -> Response<Body> {
+    let lang = suggest::language_prefix(path);
+    let localized = lang.and_then(|l| ROUTES.get(format!("/{}/404.html", l).as_str()));
+
+    if let Some(not_found_asset) = localized.or_else(|| ROUTES.get("/404.html")) {
+        let suggestions = suggest::closest(path, PAGES.iter().copied(), MAX_SUGGESTIONS);
+        let body = if suggestions.is_empty() {
+            Body::from(not_found_asset.content_raw)
+        } else {
+            let lang = not_found_asset.content_language.unwrap_or("");
+            let lang = lang.split('-').next().unwrap_or("");
+            Body::from(with_suggestions(not_found_asset.content_raw, lang, &suggestions))
+        };
+        let mut response = Response::builder()
             .status(StatusCode::NOT_FOUND)
             .header(header::CONTENT_TYPE, not_found_asset.content_type)
-            .body(Body::from(not_found_asset.content_raw))
-            .unwrap()
+            .body(body)
+            .unwrap();
+        if let Some(csp) = not_found_asset.csp {
+            response.extensions_mut().insert(PageCsp(csp));
+        }
+        if let Some(lang) = not_found_asset.content_language {
+            response
+                .headers_mut()
+                .insert(header::CONTENT_LANGUAGE, header::HeaderValue::from_static(lang));
+        }
+        response
     } else {
         // Default 404
         Response::builder()
@@ -96,43 +377,97 @@ fn serve_404() -> Response<Body> {
     }
 }
 
+/// Insert a suggestion list into a 404 page, at the end of `<main>` if it
+/// has one, else at the end of `<body>`.
+fn with_suggestions(page: &[u8], lang: &str, suggestions: &[&str]) -> Vec<u8> {
+    let mut list = format!(
+        "<nav class=\"not-found-suggestions\"><p>{}</p><ul>",
+        suggest::heading(lang)
+    );
+    for path in suggestions {
+        // Paths come from our own route table; escape anyway.
+        let path = path.replace('&', "&amp;").replace('<', "&lt;").replace('"', "&quot;");
+        list.push_str(&format!("<li><a href=\"{0}\">{0}</a></li>", path));
+    }
+    list.push_str("</ul></nav>");
+
+    let html = String::from_utf8_lossy(page);
+    let lower = html.to_ascii_lowercase();
+    let at = lower
+        .rfind("</main>")
+        .or_else(|| lower.rfind("</body>"))
+        .unwrap_or(html.len());
+    let mut out = String::with_capacity(html.len() + list.len());
+    out.push_str(&html[..at]);
+    out.push_str(&list);
+    out.push_str(&html[at..]);
+    out.into_bytes()
+}
+
 fn serve_asset(asset: &Asset, req: &Request<Body>, path: &str) -> Response<Body> {
-    // Format ETag with quotes (HTTP spec requires it)
-    let etag_value = format!("\"{}\"", asset.etag);
-
-    // Check If-None-Match for 304 Not Modified
-    if let Some(client_etag) = req.headers().get(header::IF_NONE_MATCH) {
-        if let Ok(etag_str) = client_etag.to_str() {
-            if etag_str == etag_value {
-                return Response::builder()
-                    .status(StatusCode::NOT_MODIFIED)
-                    .header(header::ETAG, &etag_value)
-                    .body(Body::empty())
-                    .unwrap();
+    // Pages with a themed hero have a body per theme that only loads the
+    // matching image; the toggle's cookie picks it. Without the cookie the
+    // page carries both and CSS follows the OS preference.
+    let themed = !asset.theme_variants.is_empty();
+    let asset = cookie(req, THEME_COOKIE)
+        .and_then(|theme| asset.theme_variants.iter().find(|(t, _)| *t == theme))
+        .map_or(asset, |(_, variant)| *variant);
+
+    let mut response = negotiate_asset(asset, req, path);
+    if themed {
+        response
+            .headers_mut()
+            .append(header::VARY, header::HeaderValue::from_static("Cookie"));
+    }
+
+    // Every response for a compressible asset depends on Accept-Encoding,
+    // including 304s and ranges, so shared caches must key on it.
+    if asset.is_compressible {
+        response
+            .headers_mut()
+            .append(header::VARY, header::HeaderValue::from_static("Accept-Encoding"));
+    }
+    if let Some(csp) = asset.csp {
+        response.extensions_mut().insert(PageCsp(csp));
+    }
+    if let Some(modified) = asset.last_modified {
+        if matches!(response.status(), StatusCode::OK | StatusCode::PARTIAL_CONTENT | StatusCode::NOT_MODIFIED) {
+            if let Ok(value) = header::HeaderValue::from_str(&date::http_date(modified)) {
+                response.headers_mut().insert(header::LAST_MODIFIED, value);
             }
         }
     }
 
-    // Content negotiation based on Accept-Encoding
-    let accept_encoding = req.headers()
-        .get(header::ACCEPT_ENCODING)
-        .and_then(|v| v.to_str().ok())
-        .unwrap_or("");
-
-    let (content, encoding) = if asset.is_compressible {
-        // Only negotiate compression for compressible content
-        if accept_encoding.contains("br") {
-            (asset.content_brotli, "br")
-        } else if accept_encoding.contains("gzip") {
-            (asset.content_gzip, "gzip")
-        } else {
-            (asset.content_raw, "identity")
+    // What the page needs first goes out in the response head, so the
+    // browser can fetch it before it has parsed the HTML.
+    if let (Some(preload), StatusCode::OK) = (asset.preload, response.status()) {
+        response
+            .headers_mut()
+            .append(header::LINK, header::HeaderValue::from_static(preload));
+        response.extensions_mut().insert(Preload(preload));
+    }
+
+    if let Some(lang) = asset.content_language {
+        response
+            .headers_mut()
+            .insert(header::CONTENT_LANGUAGE, header::HeaderValue::from_static(lang));
+    }
+    if let Some((_, default_href)) = asset.alternates.first() {
+        let links = asset
+            .alternates
+            .iter()
+            .chain([&("x-default", *default_href)])
+            .map(|(lang, href)| format!("<{}>; rel=\"alternate\"; hreflang=\"{}\"", href, lang))
+            .collect::<Vec<_>>()
+            .join(", ");
+        if let Ok(value) = header::HeaderValue::from_str(&links) {
+            response.headers_mut().append(header::LINK, value);
         }
-    } else {
-        // Serve raw for non-compressible content (images, etc.)
-        (asset.content_raw, "identity")
-    };
+    }
+    response
+}
 
+fn negotiate_asset(asset: &Asset, req: &Request<Body>, path: &str) -> Response<Body> {
     // Determine cache-control header
     // Hugo fingerprints assets with hashes (e.g., style.min.39e30de...css)
     // These can be cached forever since content changes = new hash = new URL.
@@ -146,15 +481,109 @@ fn serve_asset(asset: &Asset, req: &Request<Body>, path: &str) -> Response<Body>
         "public, max-age=300, must-revalidate"
     };
 
+    // Content negotiation based on Accept-Encoding. Only compressible
+    // content has variants; images etc. are always served raw. Offering the
+    // smallest body first means q-value ties go to the best compression.
+    // Every stored body has its own ETag.
+    let accept_encoding = req.headers()
+        .get(header::ACCEPT_ENCODING)
+        .and_then(|v| v.to_str().ok());
+
+    let mut variants = [
+        ("br", asset.content_brotli, asset.etag_brotli),
+        ("zstd", asset.content_zstd, asset.etag_zstd),
+        ("gzip", asset.content_gzip, asset.etag_gzip),
+        ("identity", Some(asset.content_raw), Some(asset.etag)),
+    ];
+    variants.sort_by_key(|(_, body, _)| body.map_or(usize::MAX, <[u8]>::len));
+    let available: Vec<&str> = variants
+        .iter()
+        .filter(|(_, body, _)| body.is_some())
+        .map(|(name, _, _)| *name)
+        .collect();
+
+    let encoding = match negotiate::choose_encoding(accept_encoding, &available) {
+        Some(e) => e,
+        None if !asset.is_compressible => "identity",
+        None => {
+            return Response::builder()
+                .status(StatusCode::NOT_ACCEPTABLE)
+                .header(header::CONTENT_TYPE, "text/plain; charset=utf-8")
+                .body(Body::from("406 Not Acceptable"))
+                .unwrap();
+        }
+    };
+
+    let (content, etag) = variants
+        .iter()
+        .find(|(name, _, _)| *name == encoding)
+        .and_then(|(_, body, etag)| Some(((*body)?, (*etag)?)))
+        .unwrap_or((asset.content_raw, asset.etag));
+
+    // Format ETags with quotes (HTTP spec requires it)
+    let etag_value = format!("\"{}\"", etag);
+    let raw_etag_value = format!("\"{}\"", asset.etag);
+
+    // If-Match / If-None-Match / dates, in RFC 9110 order. A validator
+    // the client got with any encoding of this asset still counts.
+    let all_etags: Vec<String> = variants
+        .iter()
+        .filter_map(|(_, _, etag)| etag.map(|e| format!("\"{}\"", e)))
+        .collect();
+    match conditional::evaluate(req.method(), req.headers(), &all_etags, asset.last_modified) {
+        Outcome::Proceed => {}
+        Outcome::NotModified => {
+            return Response::builder()
+                .status(StatusCode::NOT_MODIFIED)
+                .header(header::CACHE_CONTROL, cache_control)
+                .header(header::ETAG, &etag_value)
+                .body(Body::empty())
+                .unwrap();
+        }
+        Outcome::PreconditionFailed => {
+            return Response::builder()
+                .status(StatusCode::PRECONDITION_FAILED)
+                .header(header::ETAG, &etag_value)
+                .body(Body::empty())
+                .unwrap();
+        }
+    }
+
+    // Range requests are answered from the raw bytes, never a compressed
+    // variant, so offsets always refer to the file the client knows about,
+    // and only the raw body's ETag can validate them.
+    if let Some(range_header) = req.headers().get(header::RANGE).and_then(|v| v.to_str().ok()) {
+        if conditional::if_range_matches(req.headers(), &raw_etag_value, asset.last_modified) {
+            match range::parse(range_header, asset.content_raw.len()) {
+                RangeRequest::Full => {}
+                RangeRequest::Partial(ranges) => {
+                    return serve_partial(asset, ranges, cache_control, &raw_etag_value);
+                }
+                RangeRequest::Unsatisfiable => {
+                    return Response::builder()
+                        .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                        .header(header::CONTENT_RANGE, range::unsatisfied_range(asset.content_raw.len()))
+                        .header(header::ACCEPT_RANGES, "bytes")
+                        .header(header::ETAG, &raw_etag_value)
+                        .body(Body::empty())
+                        .unwrap();
+                }
+            }
+        }
+    }
+
     let mut response = Response::builder()
         .status(StatusCode::OK)
         .header(header::CONTENT_TYPE, asset.content_type)
         .header(header::CACHE_CONTROL, cache_control)
         .header(header::ETAG, &etag_value);
 
-    // Only set content-encoding if we're actually compressing
+    // Only set content-encoding if we're actually compressing. Byte ranges
+    // are only offered on the identity body; see above.
     if encoding != "identity" {
         response = response.header(header::CONTENT_ENCODING, encoding);
+    } else {
+        response = response.header(header::ACCEPT_RANGES, "bytes");
     }
 
     response
@@ -162,6 +591,40 @@ fn serve_asset(asset: &Asset, req: &Request<Body>, path: &str) -> Response<Body>
         .unwrap()
 }
 
+fn serve_partial(
+    asset: &Asset,
+    ranges: Vec<std::ops::Range<usize>>,
+    cache_control: &str,
+    etag_value: &str,
+) -> Response<Body> {
+    let len = asset.content_raw.len();
+    let response = Response::builder()
+        .status(StatusCode::PARTIAL_CONTENT)
+        .header(header::CACHE_CONTROL, cache_control)
+        .header(header::ETAG, etag_value)
+        .header(header::ACCEPT_RANGES, "bytes");
+
+    if let [single] = ranges.as_slice() {
+        return response
+            .header(header::CONTENT_TYPE, asset.content_type)
+            .header(header::CONTENT_RANGE, range::content_range(single, len))
+            .body(Body::from(&asset.content_raw[single.clone()]))
+            .unwrap();
+    }
+
+    // Derive the boundary from the ETag: stable per asset, and 32 hex chars
+    // are vanishingly unlikely to occur inside the body.
+    let boundary = &asset.etag[..asset.etag.len().min(32)];
+    let body = range::multipart_body(asset.content_raw, &ranges, asset.content_type, boundary);
+    response
+        .header(
+            header::CONTENT_TYPE,
+            format!("multipart/byteranges; boundary={}", boundary),
+        )
+        .body(Body::from(body))
+        .unwrap()
+}
+
 fn is_fingerprinted(path: &str) -> bool {
     // Hugo fingerprints look like: file.min.HASH.ext
     // Check if path contains ".min." followed by a long hex-like string
@@ -183,3 +646,85 @@ fn is_long_lived_asset(path: &str) -> bool {
             | "svg" | "ico" | "pdf")
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn page() -> Response<Body> {
+        Response::builder()
+            .status(StatusCode::OK)
+            .header(header::CONTENT_TYPE, "text/html; charset=utf-8")
+            .header(header::ETAG, "\"abc\"")
+            .body(Body::from("<p>hello</p>"))
+            .unwrap()
+    }
+
+    fn not_found() -> Response<Body> {
+        Response::builder().status(StatusCode::NOT_FOUND).body(Body::from("404 Not Found")).unwrap()
+    }
+
+    async fn body(response: Response<Body>) -> Vec<u8> {
+        hyper::body::to_bytes(response.into_body()).await.unwrap().to_vec()
+    }
+
+    async fn request(method: Method, path: &str) -> Response<Body> {
+        let req = Request::builder().method(method).uri(path).body(Body::empty()).unwrap();
+        route(req, Metrics::new()).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn head_has_the_get_headers_and_no_body() {
+        let head = strip_body(page());
+        let get = page();
+        assert_eq!(head.status(), get.status());
+        assert_eq!(head.headers()[header::CONTENT_TYPE], get.headers()[header::CONTENT_TYPE]);
+        assert_eq!(head.headers()[header::ETAG], get.headers()[header::ETAG]);
+        assert_eq!(head.headers()[header::CONTENT_LENGTH], "12");
+        assert!(body(head).await.is_empty());
+
+        let not_modified = Response::builder().status(StatusCode::NOT_MODIFIED).body(Body::empty()).unwrap();
+        assert!(!strip_body(not_modified).headers().contains_key(header::CONTENT_LENGTH));
+    }
+
+    #[tokio::test]
+    async fn options_lists_allowed_methods() {
+        let response = for_method(&Method::OPTIONS, page());
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(response.headers()[header::ALLOW], ALLOWED_METHODS);
+        assert!(body(response).await.is_empty());
+    }
+
+    #[test]
+    fn other_methods_are_not_allowed() {
+        for method in [Method::POST, Method::PUT, Method::DELETE, Method::PATCH] {
+            let response = for_method(&method, page());
+            assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED, "{}", method);
+            assert_eq!(response.headers()[header::ALLOW], ALLOWED_METHODS);
+        }
+        assert_eq!(for_method(&Method::GET, page()).status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn unknown_paths_are_404_for_any_method() {
+        for method in [Method::OPTIONS, Method::POST, Method::DELETE] {
+            assert_eq!(for_method(&method, not_found()).status(), StatusCode::NOT_FOUND, "{}", method);
+        }
+    }
+
+    #[tokio::test]
+    async fn routed_requests_to_a_missing_path() {
+        let path = "/no/such/page-9f3a";
+        let get = request(Method::GET, path).await;
+        let head = request(Method::HEAD, path).await;
+        assert_eq!(get.status(), StatusCode::NOT_FOUND);
+        assert_eq!(head.status(), StatusCode::NOT_FOUND);
+        let mut head_headers = head.headers().clone();
+        let length = head_headers.remove(header::CONTENT_LENGTH).unwrap();
+        assert_eq!(&head_headers, get.headers());
+        assert!(body(head).await.is_empty());
+        assert_eq!(length, body(get).await.len().to_string().as_str());
+        assert_eq!(request(Method::OPTIONS, path).await.status(), StatusCode::NOT_FOUND);
+        assert_eq!(request(Method::POST, path).await.status(), StatusCode::NOT_FOUND);
+    }
+}
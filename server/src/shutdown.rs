@@ -0,0 +1,108 @@
+//! Coordinated shutdown on SIGTERM/SIGINT
+//!
+//! `trigger` flips a process-wide flag that every accept loop and
+//! long-lived connection watches via `triggered`. Listeners stop accepting,
+//! HTTP connections finish their current request, WebSocket clients get a
+//! Close frame and Gemini transfers get a deadline. Each of those holds a
+//! `Connection` guard, so `drain` knows when the last one is gone.
+
+use std::time::Duration;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+
+lazy_static::lazy_static! {
+    static ref STOPPING: watch::Sender<bool> = watch::channel(false).0;
+    static ref ACTIVE: watch::Sender<usize> = watch::channel(0).0;
+}
+
+/// Keeps `drain` waiting while alive.
+pub struct Connection(());
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        ACTIVE.send_modify(|n| *n -= 1);
+    }
+}
+
+/// Register a connection (or connection-like task) that shutdown waits for.
+pub fn track() -> Connection {
+    ACTIVE.send_modify(|n| *n += 1);
+    Connection(())
+}
+
+pub fn trigger() {
+    STOPPING.send_replace(true);
+}
+
+/// Resolves once shutdown has been triggered (immediately if it already
+/// has been).
+pub async fn triggered() {
+    let mut stopping = STOPPING.subscribe();
+    // The sender lives in a static, so this can't fail.
+    let _ = stopping.wait_for(|stopping| *stopping).await;
+}
+
+/// Wait for SIGTERM (systemd stop) or SIGINT (Ctrl-C), then trigger
+/// shutdown.
+pub async fn on_signal() {
+    let mut sigterm = signal(SignalKind::terminate()).expect("Failed to install SIGTERM handler");
+    let mut sigint = signal(SignalKind::interrupt()).expect("Failed to install SIGINT handler");
+    let name = tokio::select! {
+        _ = sigterm.recv() => "SIGTERM",
+        _ = sigint.recv() => "SIGINT",
+    };
+    println!("Received {}, shutting down", name);
+    trigger();
+}
+
+/// Wait up to `budget` for every tracked connection to finish. Returns how
+/// many were still open when it ran out.
+pub async fn drain(budget: Duration) -> usize {
+    let mut active = ACTIVE.subscribe();
+    let drained = tokio::time::timeout(budget, active.wait_for(|n| *n == 0)).await.is_ok();
+    if drained {
+        0
+    } else {
+        *active.borrow()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    // The connection count is process-wide, so every drain case runs in
+    // this one test.
+    #[tokio::test]
+    async fn drain_waits_for_connections_until_the_budget_runs_out() {
+        assert_eq!(drain(Duration::from_secs(5)).await, 0);
+
+        let connection = track();
+        let start = Instant::now();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            drop(connection);
+        });
+        assert_eq!(drain(Duration::from_secs(5)).await, 0);
+        assert!(start.elapsed() >= Duration::from_millis(50));
+
+        let (first, second) = (track(), track());
+        let start = Instant::now();
+        assert_eq!(drain(Duration::from_millis(50)).await, 2);
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        drop(first);
+        assert_eq!(drain(Duration::from_millis(10)).await, 1);
+        drop(second);
+        assert_eq!(drain(Duration::from_millis(10)).await, 0);
+    }
+
+    #[tokio::test]
+    async fn triggered_resolves_for_current_and_later_waiters() {
+        let waiter = tokio::spawn(triggered());
+        assert!(tokio::time::timeout(Duration::from_millis(20), triggered()).await.is_err());
+        trigger();
+        tokio::time::timeout(Duration::from_secs(5), waiter).await.unwrap().unwrap();
+        tokio::time::timeout(Duration::from_millis(20), triggered()).await.unwrap();
+    }
+}
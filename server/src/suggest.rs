@@ -0,0 +1,102 @@
+//! "Did you mean" suggestions for 404s
+//!
+//! Ranks existing page paths by edit distance to the one that wasn't found.
+//! Typos and half-remembered slugs are the common case, so plain Levenshtein
+//! over the path is enough.
+
+// Longer paths are bots probing for files, not people mistyping.
+const MAX_PATH_LEN: usize = 200;
+
+/// Up to `limit` of `candidates` closest to `path`, nearest first. A
+/// candidate only qualifies within a third of the path's length, so short
+/// paths don't match everything.
+pub fn closest<'a>(path: &str, candidates: impl IntoIterator<Item = &'a str>, limit: usize) -> Vec<&'a str> {
+    if path.len() > MAX_PATH_LEN {
+        return Vec::new();
+    }
+    let max_distance = (path.len() / 3).max(2);
+
+    let mut scored: Vec<(usize, &str)> = candidates
+        .into_iter()
+        .filter(|c| *c != path)
+        .map(|c| (levenshtein(path.as_bytes(), c.as_bytes()), c))
+        .filter(|(d, _)| *d <= max_distance)
+        .collect();
+    scored.sort();
+    scored.into_iter().take(limit).map(|(_, c)| c).collect()
+}
+
+/// Localised lead-in for the suggestion list.
+pub fn heading(lang: &str) -> &'static str {
+    match lang {
+        "de" => "Meinten Sie vielleicht:",
+        _ => "Did you mean:",
+    }
+}
+
+/// Language prefix of `path` (`/de/...` → `de`), if it has one.
+pub fn language_prefix(path: &str) -> Option<&str> {
+    let rest = path.strip_prefix('/')?;
+    let (first, _) = rest.split_once('/')?;
+    (first.len() == 2 && first.bytes().all(|b| b.is_ascii_lowercase())).then_some(first)
+}
+
+fn levenshtein(a: &[u8], b: &[u8]) -> usize {
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, &ca) in a.iter().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, &cb) in b.iter().enumerate() {
+            let substitution = diagonal + usize::from(ca != cb);
+            diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(row[j + 1] + 1);
+        }
+    }
+    row[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PAGES: &[&str] = &["/", "/blog/", "/about/", "/posts/hello-world/", "/posts/hello-there/"];
+
+    fn suggest(path: &str) -> Vec<&'static str> {
+        closest(path, PAGES.iter().copied(), 3)
+    }
+
+    #[test]
+    fn levenshtein_distance() {
+        assert_eq!(levenshtein(b"", b"abc"), 3);
+        assert_eq!(levenshtein(b"kitten", b"sitting"), 3);
+        assert_eq!(levenshtein(b"blog", b"blgo"), 2);
+        assert_eq!(levenshtein(b"same", b"same"), 0);
+    }
+
+    #[test]
+    fn typos_find_the_page() {
+        assert_eq!(suggest("/blgo/"), ["/blog/"]);
+        assert_eq!(suggest("/about"), ["/about/"]);
+    }
+
+    #[test]
+    fn nearest_first_and_limited() {
+        assert_eq!(suggest("/posts/hello-word/"), ["/posts/hello-world/", "/posts/hello-there/"]);
+        assert_eq!(closest("/posts/hello-word/", PAGES.iter().copied(), 1), ["/posts/hello-world/"]);
+    }
+
+    #[test]
+    fn distant_and_exact_paths_are_not_suggested() {
+        assert!(suggest("/contact/").is_empty());
+        assert!(suggest("/blog/").is_empty());
+        assert!(suggest(&format!("/{}", "a".repeat(MAX_PATH_LEN))).is_empty());
+    }
+
+    #[test]
+    fn language_prefixes() {
+        assert_eq!(language_prefix("/de/blog/"), Some("de"));
+        assert_eq!(language_prefix("/de"), None);
+        assert_eq!(language_prefix("/blog/"), None);
+        assert_eq!(language_prefix("/DE/blog/"), None);
+    }
+}
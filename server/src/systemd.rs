@@ -0,0 +1,337 @@
+//! systemd integration: socket activation and sd_notify
+//!
+//! With a `.socket` unit, systemd owns the listening sockets and hands them
+//! over as fds 3.. (`LISTEN_FDS`), named by `FileDescriptorName=` in
+//! `LISTEN_FDNAMES`. Restarting the service then never closes them:
+//! connections queue in the kernel until the new process accepts. The
+//! names used here are `http`, `https`, `http-redirect` and `gemini`; a
+//! name may cover several sockets (one `ListenStream=` each). Any listener
+//! without a passed socket binds the addresses from the config instead.
+//!
+//! With `Type=notify`, `NOTIFY_SOCKET` names the datagram socket for state
+//! messages (sd_notify(3)): READY/RELOADING/STOPPING/STATUS, and
+//! `WATCHDOG=1` pings while the health check passes. Outside systemd both
+//! are absent and this is a no-op. To watch the messages locally, listen
+//! on a datagram socket, e.g. `socat -u UNIX-RECV:/tmp/notify -`, and start
+//! the server with `NOTIFY_SOCKET=/tmp/notify WATCHDOG_USEC=2000000`.
+
+use std::collections::HashMap;
+use std::env;
+use std::io;
+use std::net::SocketAddr;
+use std::os::fd::{AsRawFd, FromRawFd, RawFd};
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::{self, UnixDatagram};
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpSocket};
+use tokio::task::JoinHandle;
+use tokio::time::timeout;
+
+use crate::router;
+use crate::shutdown;
+
+// sd_listen_fds(3): passed sockets start right after stdio.
+const LISTEN_FDS_START: RawFd = 3;
+// The runtime must pick up and finish the health check within this.
+const HEALTH_CHECK_DEADLINE: Duration = Duration::from_secs(2);
+// Same backlog as `TcpListener::bind`.
+const LISTEN_BACKLOG: u32 = 1024;
+
+lazy_static::lazy_static! {
+    static ref NOTIFY: Option<Notifier> =
+        env::var("NOTIFY_SOCKET").ok().and_then(|path| Notifier::open(&path));
+}
+
+/// Sockets passed in by systemd, by name, until a listener claims them.
+pub struct Activated(HashMap<String, Vec<std::net::TcpListener>>);
+
+/// Take over the sockets systemd passed to this process, if any. This
+/// clears their variables from the environment, so it has to run before
+/// the runtime starts any threads.
+pub fn listen_fds() -> Activated {
+    let pid = env::var("LISTEN_PID").ok();
+    let count = env::var("LISTEN_FDS").ok();
+    let names = env::var("LISTEN_FDNAMES").ok();
+    // Meant for this process only, not anything it starts.
+    for var in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        env::remove_var(var);
+    }
+
+    let mut sockets: HashMap<String, Vec<_>> = HashMap::new();
+    for (fd, name) in passed_fds(pid.as_deref(), count.as_deref(), names.as_deref(), std::process::id()) {
+        // Stale variables could otherwise hand us some other open fd.
+        if !fd_is(fd, libc::S_IFSOCK) {
+            eprintln!("Socket activation: fd {} ({}) is not a socket; ignoring it", fd, name);
+            continue;
+        }
+        // SAFETY: systemd passed `fd` to us and nothing else owns it.
+        unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+        let socket = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+        sockets.entry(name.to_string()).or_default().push(socket);
+    }
+    Activated(sockets)
+}
+
+/// The fds and names that `LISTEN_PID`, `LISTEN_FDS` and `LISTEN_FDNAMES`
+/// describe, if they're meant for process `our_pid`. An fd without a name
+/// is called `unknown`.
+fn passed_fds<'a>(
+    pid: Option<&str>,
+    count: Option<&str>,
+    names: Option<&'a str>,
+    our_pid: u32,
+) -> Vec<(RawFd, &'a str)> {
+    if pid.and_then(|pid| pid.parse::<u32>().ok()) != Some(our_pid) {
+        return Vec::new();
+    }
+    let count = count.and_then(|n| n.parse::<RawFd>().ok()).unwrap_or(0);
+    let mut names = names.unwrap_or_default().split(':');
+    (LISTEN_FDS_START..LISTEN_FDS_START + count)
+        .map(|fd| (fd, names.next().filter(|n| !n.is_empty()).unwrap_or("unknown")))
+        .collect()
+}
+
+/// Whether `fd` is open and of file type `kind` (`S_IFSOCK`, `S_IFIFO`, …).
+pub fn fd_is(fd: RawFd, kind: libc::mode_t) -> bool {
+    // SAFETY: fstat only writes to the buffer it's given.
+    let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+    unsafe { libc::fstat(fd, &mut stat) == 0 && stat.st_mode & libc::S_IFMT == kind }
+}
+
+impl Activated {
+    /// The passed sockets named `name`, else new ones bound to `addrs`.
+    pub fn listeners(&mut self, name: &str, addrs: &[SocketAddr]) -> io::Result<Vec<TcpListener>> {
+        match self.0.remove(name) {
+            Some(sockets) => sockets
+                .into_iter()
+                .map(|socket| {
+                    socket.set_nonblocking(true)?;
+                    TcpListener::from_std(socket)
+                })
+                .collect(),
+            None => addrs
+                .iter()
+                .map(|&addr| bind(addr).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", addr, e))))
+                .collect(),
+        }
+    }
+
+    /// Log the passed sockets no listener asked for, usually a typo in the
+    /// socket unit. Dropping our copy doesn't close the port: systemd holds
+    /// its own, so connections keep queueing there unanswered.
+    pub fn report_unused(self) {
+        for (name, sockets) in self.0 {
+            for socket in sockets {
+                let addr = socket.local_addr().map_or_else(|_| "?".to_string(), |a| a.to_string());
+                eprintln!(
+                    "Socket activation: no listener for the fd named {} ({}), nothing will answer it",
+                    name, addr
+                );
+            }
+        }
+    }
+}
+
+/// Like `TcpListener::bind`, but IPv6 sockets are IPv6 only, so `[::]:80`
+/// and `0.0.0.0:80` can both be listed without the second failing.
+fn bind(addr: SocketAddr) -> io::Result<TcpListener> {
+    let socket = match addr {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => {
+            let socket = TcpSocket::new_v6()?;
+            let on: libc::c_int = 1;
+            // SAFETY: setsockopt reads an int from a valid pointer.
+            let result = unsafe {
+                libc::setsockopt(
+                    socket.as_raw_fd(),
+                    libc::IPPROTO_IPV6,
+                    libc::IPV6_V6ONLY,
+                    &on as *const libc::c_int as *const libc::c_void,
+                    std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+                )
+            };
+            if result != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            socket
+        }
+    };
+    socket.set_reuseaddr(true)?;
+    socket.bind(addr)?;
+    socket.listen(LISTEN_BACKLOG)
+}
+
+/// Where state messages go: the socket `NOTIFY_SOCKET` names.
+struct Notifier {
+    socket: UnixDatagram,
+    addr: net::SocketAddr,
+}
+
+impl Notifier {
+    /// A leading @ in `path` means the abstract namespace.
+    fn open(path: &str) -> Option<Self> {
+        if path.is_empty() {
+            return None;
+        }
+        let addr = match path.strip_prefix('@') {
+            Some(name) => net::SocketAddr::from_abstract_name(name),
+            None => net::SocketAddr::from_pathname(path),
+        };
+        let result = addr.and_then(|addr| Ok(Self { socket: UnixDatagram::unbound()?, addr }));
+        if let Err(e) = &result {
+            eprintln!("NOTIFY_SOCKET {} unusable: {}", path, e);
+        }
+        result.ok()
+    }
+
+    fn send(&self, state: &str) {
+        if let Err(e) = self.socket.send_to_addr(state.as_bytes(), &self.addr) {
+            eprintln!("sd_notify failed: {}", e);
+        }
+    }
+}
+
+/// Send newline-separated `KEY=value` assignments to the service manager.
+pub fn notify(state: &str) {
+    if let Some(notifier) = NOTIFY.as_ref() {
+        notifier.send(state);
+    }
+}
+
+/// CLOCK_MONOTONIC in microseconds, which `RELOADING=1` has to come with.
+pub fn monotonic_usec() -> u64 {
+    let mut now = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+    // SAFETY: clock_gettime only writes to the timespec it's given.
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut now) };
+    now.tv_sec as u64 * 1_000_000 + now.tv_nsec as u64 / 1_000
+}
+
+/// Ping interval for `WatchdogSec=`: half the timeout systemd enforces, so
+/// one late ping doesn't get the service killed.
+pub fn watchdog_interval() -> Option<Duration> {
+    let pid = env::var("WATCHDOG_PID").ok();
+    if pid.is_some_and(|pid| pid.parse::<u32>().ok() != Some(std::process::id())) {
+        return None;
+    }
+    let usec = env::var("WATCHDOG_USEC").ok()?.parse::<u64>().ok().filter(|&u| u > 0)?;
+    Some(Duration::from_micros(usec / 2))
+}
+
+/// Ping the watchdog every `interval` while healthy, until shutdown.
+/// `listeners` are the accept loops spawned next to the main HTTP one;
+/// `status` is restored once a failing check passes again.
+pub async fn run_watchdog(
+    interval: Duration,
+    status: String,
+    listeners: Vec<(&'static str, JoinHandle<()>)>,
+) {
+    let mut ticks = tokio::time::interval(interval);
+    let mut failing = false;
+    loop {
+        tokio::select! {
+            _ = ticks.tick() => {}
+            // systemd stops the watchdog once the service is stopping.
+            _ = shutdown::triggered() => return,
+        }
+        match health_check(&listeners).await {
+            Ok(()) => {
+                if failing {
+                    println!("Health check passing again");
+                    notify(&format!("STATUS={}", status));
+                    failing = false;
+                }
+                notify("WATCHDOG=1");
+            }
+            // No ping: systemd restarts us once WatchdogSec runs out.
+            Err(reason) => {
+                eprintln!("Health check failed: {}", reason);
+                notify(&format!("STATUS=Unhealthy: {}", reason));
+                failing = true;
+            }
+        }
+    }
+}
+
+/// The runtime has to schedule a fresh task and serve `/` from it in time,
+/// and no listener may have died.
+async fn health_check(listeners: &[(&'static str, JoinHandle<()>)]) -> Result<(), String> {
+    if let Some((name, _)) = listeners.iter().find(|(_, task)| task.is_finished()) {
+        return Err(format!("{} listener stopped", name));
+    }
+    match timeout(HEALTH_CHECK_DEADLINE, tokio::spawn(async { router::self_check() })).await {
+        Ok(Ok(true)) => Ok(()),
+        Ok(Ok(false)) => Err("serving / returned a server error".to_string()),
+        Ok(Err(e)) => Err(format!("health check task failed: {}", e)),
+        Err(_) => Err(format!(
+            "runtime didn't run the health check within {}s",
+            HEALTH_CHECK_DEADLINE.as_secs()
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn passed_fds_are_only_taken_by_the_process_they_were_meant_for() {
+        let ours = std::process::id();
+        assert_eq!(passed_fds(Some("1"), Some("1"), Some("http"), ours), []);
+        assert_eq!(passed_fds(None, Some("1"), Some("http"), ours), []);
+        assert_eq!(passed_fds(Some("x"), Some("1"), Some("http"), ours), []);
+        let pid = ours.to_string();
+        assert_eq!(passed_fds(Some(&pid), Some("1"), Some("http"), ours), [(3, "http")]);
+        assert_eq!(passed_fds(Some(&pid), None, Some("http"), ours), []);
+        assert_eq!(passed_fds(Some(&pid), Some("two"), Some("http"), ours), []);
+    }
+
+    #[test]
+    fn fds_without_a_name_are_unknown() {
+        assert_eq!(passed_fds(Some("7"), Some("2"), None, 7), [(3, "unknown"), (4, "unknown")]);
+        assert_eq!(passed_fds(Some("7"), Some("2"), Some("http"), 7), [(3, "http"), (4, "unknown")]);
+        assert_eq!(
+            passed_fds(Some("7"), Some("3"), Some(":https:"), 7),
+            [(3, "unknown"), (4, "https"), (5, "unknown")]
+        );
+    }
+
+    #[test]
+    fn several_fds_can_share_a_name() {
+        assert_eq!(
+            passed_fds(Some("7"), Some("4"), Some("http:http:gemini:http"), 7),
+            [(3, "http"), (4, "http"), (5, "gemini"), (6, "http")]
+        );
+    }
+
+    fn receive(socket: &UnixDatagram) -> String {
+        let mut buf = [0; 256];
+        let n = socket.recv(&mut buf).unwrap();
+        String::from_utf8(buf[..n].to_vec()).unwrap()
+    }
+
+    #[test]
+    fn notifier_sends_each_state_as_one_datagram() {
+        let path = env::temp_dir().join(format!("systemd-test-{}-notify", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let manager = UnixDatagram::bind(&path).unwrap();
+        manager.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        let notifier = Notifier::open(path.to_str().unwrap()).unwrap();
+        notifier.send("READY=1\nSTATUS=Serving 3 routes");
+        notifier.send("WATCHDOG=1");
+        assert_eq!(receive(&manager), "READY=1\nSTATUS=Serving 3 routes");
+        assert_eq!(receive(&manager), "WATCHDOG=1");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn notifier_reaches_abstract_sockets() {
+        let name = format!("systemd-test-{}-notify", std::process::id());
+        let manager = UnixDatagram::bind_addr(&net::SocketAddr::from_abstract_name(&name).unwrap()).unwrap();
+        manager.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        Notifier::open(&format!("@{}", name)).unwrap().send("STOPPING=1");
+        assert_eq!(receive(&manager), "STOPPING=1");
+        assert!(Notifier::open("").is_none());
+    }
+}
@@ -0,0 +1,379 @@
+//! Zero-downtime binary upgrade by listener handoff
+//!
+//! On SIGUSR2 the running process starts the binary at its own path, which a
+//! deploy has just replaced, and passes it every listening socket the way
+//! systemd would: `LISTEN_FDS`, named after the listeners, so the new
+//! process takes them over in `systemd::listen_fds`. Once all its listeners
+//! run, the new process says so over a pipe (`UPGRADE_READY_FD`). Only then
+//! does the old one hand the service over (`MAINPID=` to systemd) and shut
+//! down, draining its connections. A new binary that doesn't come up in
+//! time is killed and the old one carries on.
+//!
+//! Both processes accept on the same sockets for a moment, so no connection
+//! is refused at any point.
+
+use parking_lot::Mutex;
+use std::ffi::{CString, OsString};
+use std::fs::File;
+use std::io::{self, Write};
+use std::os::fd::{AsFd, AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::raw::c_char;
+use std::os::unix::ffi::OsStrExt;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tokio::io::AsyncReadExt;
+use tokio::net::unix::pipe;
+use tokio::net::TcpListener;
+use tokio::signal::unix::{signal, SignalKind};
+
+use crate::shutdown;
+use crate::systemd;
+
+// The new process has this long to get all its listeners up.
+const UPGRADE_TIMEOUT: Duration = Duration::from_secs(30);
+// Variables describing this process's own hand-over, not for the next one.
+const HANDOFF_VARS: [&str; 5] =
+    ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES", "UPGRADE_READY_FD", "WATCHDOG_PID"];
+
+lazy_static::lazy_static! {
+    static ref STATE: Mutex<State> = Mutex::new(State::default());
+}
+
+static HANDED_OVER: AtomicBool = AtomicBool::new(false);
+
+#[derive(Default)]
+struct State {
+    /// Resolved at startup, before a deploy can move another file there.
+    exe: Option<PathBuf>,
+    /// Duplicates of the listening sockets, by `systemd::listen_fds` name.
+    listeners: Vec<(&'static str, OwnedFd)>,
+    /// Write end of the pipe the process that started us waits on.
+    parent: Option<OwnedFd>,
+}
+
+/// Record the binary path and, if an upgrade started us, the pipe to
+/// report readiness on. Like `systemd::listen_fds`, this clears its
+/// variable from the environment and so has to run before any threads.
+pub fn init() {
+    let mut state = STATE.lock();
+    state.exe = std::env::current_exe().ok();
+    let ready_fd = std::env::var("UPGRADE_READY_FD").ok().and_then(|fd| fd.parse::<RawFd>().ok());
+    std::env::remove_var("UPGRADE_READY_FD");
+    if let Some(fd) = ready_fd.filter(|&fd| systemd::fd_is(fd, libc::S_IFIFO)) {
+        // SAFETY: the old process passed `fd` to us and nothing else owns it.
+        unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+        state.parent = Some(unsafe { OwnedFd::from_raw_fd(fd) });
+    }
+}
+
+/// Keep `listener` for handing over under `name`.
+pub fn register(name: &'static str, listener: &TcpListener) {
+    match listener.as_fd().try_clone_to_owned() {
+        Ok(fd) => STATE.lock().listeners.push((name, fd)),
+        Err(e) => eprintln!("Upgrade: can't keep the {} listener for handoff: {}", name, e),
+    }
+}
+
+/// Close the kept listeners once this process stops accepting, so a
+/// plain shutdown doesn't leave them queueing connections.
+pub fn release_listeners() {
+    STATE.lock().listeners.clear();
+}
+
+/// Tell the process that started us, if any, that we're serving.
+pub fn notify_parent() {
+    let Some(fd) = STATE.lock().parent.take() else {
+        return;
+    };
+    if let Err(e) = File::from(fd).write_all(b"1") {
+        eprintln!("Upgrade: failed to report readiness: {}", e);
+    }
+}
+
+/// Whether this process gave its listeners to a newer one.
+pub fn handed_over() -> bool {
+    HANDED_OVER.load(Ordering::Relaxed)
+}
+
+/// Upgrade on every SIGUSR2 until one succeeds or shutdown starts.
+pub async fn on_signal() {
+    let mut usr2 = signal(SignalKind::user_defined2()).expect("Failed to install SIGUSR2 handler");
+    loop {
+        tokio::select! {
+            _ = usr2.recv() => {}
+            _ = shutdown::triggered() => return,
+        }
+        println!("Received SIGUSR2, starting the new binary");
+        match upgrade().await {
+            Ok(pid) => {
+                println!("Upgrade: process {} is serving, handing over", pid);
+                systemd::notify(&format!("MAINPID={}", pid));
+                HANDED_OVER.store(true, Ordering::Relaxed);
+                shutdown::trigger();
+                return;
+            }
+            Err(e) => eprintln!("Upgrade failed, carrying on: {}", e),
+        }
+    }
+}
+
+async fn upgrade() -> io::Result<libc::pid_t> {
+    let (read, write) = ready_pipe()?;
+    let pid = {
+        let state = STATE.lock();
+        let exe = state
+            .exe
+            .as_ref()
+            .ok_or_else(|| io::Error::other("path of the running binary unknown"))?;
+        let args: Vec<OsString> = std::env::args_os().collect();
+        spawn(exe, &args, &state.listeners, write.as_raw_fd())?
+    };
+    // Ours closed, a child that dies before it's ready shows up as EOF.
+    drop(write);
+
+    let mut ready = pipe::Receiver::from_owned_fd(read)?;
+    let mut byte = [0u8; 1];
+    let error = match tokio::time::timeout(UPGRADE_TIMEOUT, ready.read(&mut byte)).await {
+        Ok(Ok(1)) => return Ok(pid),
+        Ok(Ok(_)) => io::Error::other(format!("process {} exited before it was ready", pid)),
+        Ok(Err(e)) => e,
+        Err(_) => io::Error::other(format!(
+            "process {} not ready within {}s",
+            pid,
+            UPGRADE_TIMEOUT.as_secs()
+        )),
+    };
+    // SAFETY: plain syscalls on our own child.
+    unsafe { libc::kill(pid, libc::SIGKILL) };
+    let _ = tokio::task::spawn_blocking(move || unsafe {
+        libc::waitpid(pid, std::ptr::null_mut(), 0)
+    })
+    .await;
+    Err(error)
+}
+
+fn ready_pipe() -> io::Result<(OwnedFd, OwnedFd)> {
+    let mut fds = [0; 2];
+    // SAFETY: pipe2 fills `fds` with two new descriptors we then own.
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) })
+}
+
+/// The environment for the new binary: ours without the variables about
+/// our own hand-over, plus the ones describing its own for listeners named
+/// `names`. `LISTEN_PID` is left to the child, once it knows its pid.
+fn child_env(vars: impl Iterator<Item = (OsString, OsString)>, names: &[&str]) -> Vec<Vec<u8>> {
+    let mut env: Vec<Vec<u8>> = vars
+        .filter(|(key, _)| !HANDOFF_VARS.iter().any(|var| key == *var))
+        .map(|(key, value)| [key.as_bytes(), b"=", value.as_bytes()].concat())
+        .collect();
+    env.push(format!("LISTEN_FDS={}", names.len()).into_bytes());
+    env.push(format!("LISTEN_FDNAMES={}", names.join(":")).into_bytes());
+    env.push(format!("UPGRADE_READY_FD={}", 3 + names.len()).into_bytes());
+    env
+}
+
+/// Fork and exec `exe` with `args`, our environment, the listeners as fds
+/// 3.. and the ready pipe right after them.
+///
+/// Everything is allocated before the fork: in the child of a
+/// multi-threaded process only async-signal-safe calls are allowed, so it
+/// can't even format its own pid with `format!`.
+fn spawn(
+    exe: &std::path::Path,
+    args: &[OsString],
+    listeners: &[(&'static str, OwnedFd)],
+    ready: RawFd,
+) -> io::Result<libc::pid_t> {
+    let cstring = |bytes: &[u8]| CString::new(bytes).map_err(io::Error::other);
+    let path = cstring(exe.as_os_str().as_bytes())?;
+    let args = args.iter().map(|arg| cstring(arg.as_bytes())).collect::<io::Result<Vec<_>>>()?;
+
+    let names: Vec<&str> = listeners.iter().map(|(name, _)| *name).collect();
+    let env = child_env(std::env::vars_os(), &names)
+        .iter()
+        .map(|pair| cstring(pair))
+        .collect::<io::Result<Vec<_>>>()?;
+    // The child writes its pid after `LISTEN_PID=`; 10 digits fit any pid_t.
+    let mut listen_pid_var = [0u8; 22];
+    listen_pid_var[..11].copy_from_slice(b"LISTEN_PID=");
+    let listen_pid = listen_pid_var.as_mut_ptr();
+
+    let argv: Vec<*const c_char> =
+        args.iter().map(|a| a.as_ptr()).chain(std::iter::once(std::ptr::null())).collect();
+    let envp: Vec<*const c_char> = env
+        .iter()
+        .map(|e| e.as_ptr())
+        .chain([listen_pid as *const c_char, std::ptr::null()])
+        .collect();
+    let mut fds: Vec<RawFd> = listeners.iter().map(|(_, fd)| fd.as_raw_fd()).collect();
+    fds.push(ready);
+
+    // SAFETY: the child only calls async-signal-safe functions and never
+    // returns; everything it touches was set up above.
+    match unsafe { libc::fork() } {
+        -1 => Err(io::Error::last_os_error()),
+        0 => unsafe { exec_child(&path, &argv, &envp, listen_pid.add(11), &mut fds) },
+        pid => Ok(pid),
+    }
+}
+
+/// Runs in the forked child: async-signal-safe calls only.
+unsafe fn exec_child(
+    path: &CString,
+    argv: &[*const c_char],
+    envp: &[*const c_char],
+    pid_digits: *mut u8,
+    fds: &mut [RawFd],
+) -> ! {
+    let mut pid = libc::getpid() as u32;
+    let mut digits = [0u8; 10];
+    let mut len = 0;
+    loop {
+        digits[len] = b'0' + (pid % 10) as u8;
+        pid /= 10;
+        len += 1;
+        if pid == 0 {
+            break;
+        }
+    }
+    for i in 0..len {
+        *pid_digits.add(i) = digits[len - 1 - i];
+    }
+
+    // Move everything above the target range first, so placing one fd
+    // can't overwrite another that's still to be moved. dup2 clears
+    // close-on-exec on the copies that end up at 3..
+    let first_free = 3 + fds.len() as libc::c_int;
+    for fd in fds.iter_mut() {
+        *fd = libc::fcntl(*fd, libc::F_DUPFD_CLOEXEC, first_free);
+        if *fd < 0 {
+            libc::_exit(126);
+        }
+    }
+    for (i, fd) in fds.iter().enumerate() {
+        if libc::dup2(*fd, 3 + i as libc::c_int) < 0 {
+            libc::_exit(126);
+        }
+    }
+    libc::execve(path.as_ptr(), argv.as_ptr(), envp.as_ptr());
+    libc::_exit(127)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    // Run in the re-executed test binary: only the child of
+    // `new_binary_gets_the_listeners_and_the_ready_pipe` has the variable.
+    const CHILD_TEST: &str = "upgrade::tests::report_what_was_handed_over";
+
+    fn vars(pairs: &[(&str, &str)]) -> impl Iterator<Item = (OsString, OsString)> {
+        pairs.iter().map(|(k, v)| (k.into(), v.into())).collect::<Vec<_>>().into_iter()
+    }
+
+    fn strings(env: Vec<Vec<u8>>) -> Vec<String> {
+        env.into_iter().map(|pair| String::from_utf8(pair).unwrap()).collect()
+    }
+
+    #[test]
+    fn child_env_replaces_our_handoff_variables_with_its_own() {
+        let ours = vars(&[
+            ("PATH", "/usr/bin"),
+            ("LISTEN_PID", "41"),
+            ("LISTEN_FDS", "1"),
+            ("LISTEN_FDNAMES", "http"),
+            ("UPGRADE_READY_FD", "4"),
+            ("WATCHDOG_PID", "41"),
+            ("WATCHDOG_USEC", "2000000"),
+            ("CONFIG_FILE", "/etc/site.toml"),
+        ]);
+        assert_eq!(
+            strings(child_env(ours, &["http", "http", "gemini"])),
+            [
+                "PATH=/usr/bin",
+                "WATCHDOG_USEC=2000000",
+                "CONFIG_FILE=/etc/site.toml",
+                "LISTEN_FDS=3",
+                "LISTEN_FDNAMES=http:http:gemini",
+                "UPGRADE_READY_FD=6",
+            ]
+        );
+    }
+
+    #[test]
+    fn child_env_without_listeners_still_gets_the_ready_pipe() {
+        assert_eq!(
+            strings(child_env(vars(&[("LISTEN_FDS", "2")]), &[])),
+            ["LISTEN_FDS=0", "LISTEN_FDNAMES=", "UPGRADE_READY_FD=3"]
+        );
+    }
+
+    #[test]
+    fn new_binary_gets_the_listeners_and_the_ready_pipe() {
+        let bound = [
+            std::net::TcpListener::bind("127.0.0.1:0").unwrap(),
+            std::net::TcpListener::bind("127.0.0.1:0").unwrap(),
+        ];
+        // Kept the way `register` keeps them.
+        let listeners: Vec<(&'static str, OwnedFd)> = ["http", "gemini"]
+            .into_iter()
+            .zip(&bound)
+            .map(|(name, socket)| (name, socket.as_fd().try_clone_to_owned().unwrap()))
+            .collect();
+        let args: Vec<OsString> = ["test", CHILD_TEST, "--exact", "--quiet", "--test-threads=1"]
+            .iter()
+            .map(OsString::from)
+            .collect();
+        let exe = std::env::current_exe().unwrap();
+
+        let (read, write) = ready_pipe().unwrap();
+        let pid = spawn(&exe, &args, &listeners, write.as_raw_fd()).unwrap();
+        drop(write);
+        let mut report = String::new();
+        File::from(read).read_to_string(&mut report).unwrap();
+        let mut status = 0;
+        // SAFETY: waits for our own child.
+        assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+        assert!(libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0, "child failed: {}", status);
+
+        let expected: Vec<String> = listeners
+            .iter()
+            .zip(&bound)
+            .map(|((name, _), socket)| format!("{}={}", name, socket.local_addr().unwrap()))
+            .collect();
+        assert_eq!(report, expected.join("\n"));
+    }
+
+    /// Checks what `spawn` passed and reports the listeners' addresses
+    /// over the ready pipe. A no-op in a normal test run.
+    #[test]
+    fn report_what_was_handed_over() {
+        let Ok(ready) = std::env::var("UPGRADE_READY_FD") else {
+            return;
+        };
+        let var = |name| std::env::var(name).unwrap();
+        assert_eq!(var("LISTEN_PID"), std::process::id().to_string());
+        let count: RawFd = var("LISTEN_FDS").parse().unwrap();
+        assert_eq!(ready, (3 + count).to_string());
+        let names = var("LISTEN_FDNAMES");
+
+        let report: Vec<String> = (3..3 + count)
+            .zip(names.split(':'))
+            .map(|(fd, name)| {
+                assert!(systemd::fd_is(fd, libc::S_IFSOCK), "fd {} is not a socket", fd);
+                // SAFETY: the parent test passed `fd` to us and nothing else owns it.
+                let socket = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+                format!("{}={}", name, socket.local_addr().unwrap())
+            })
+            .collect();
+        assert!(systemd::fd_is(3 + count, libc::S_IFIFO));
+        // SAFETY: as above, for the write end of the ready pipe.
+        let mut parent = unsafe { File::from_raw_fd(3 + count) };
+        parent.write_all(report.join("\n").as_bytes()).unwrap();
+    }
+}
@@ -1,25 +1,26 @@
 use hyper::{Body, Request, Response, StatusCode, header, upgrade::Upgraded};
 use tokio_tungstenite::{
     WebSocketStream,
-    tungstenite::{Message, protocol::WebSocketConfig},
+    tungstenite::{
+        Message,
+        protocol::{CloseFrame, WebSocketConfig, frame::coding::CloseCode},
+    },
 };
 use futures_util::{StreamExt, SinkExt};
 use std::sync::Arc;
 use std::time::{Duration, Instant};
-use tokio::sync::Semaphore;
 use tokio::time::timeout;
+use crate::config;
 use crate::metrics::Metrics;
+use crate::reload;
+use crate::shutdown;
 
 // A metrics feed has no reason to receive anything bigger than control frames.
 const WS_MAX_MESSAGE_SIZE: usize = 16 * 1024;
 const WS_MAX_FRAME_SIZE: usize = 16 * 1024;
-const WS_MAX_CLIENTS: usize = 64;
-const WS_PING_INTERVAL: Duration = Duration::from_secs(30);
-const WS_PONG_DEADLINE: Duration = Duration::from_secs(60);
-const WS_SEND_TIMEOUT: Duration = Duration::from_secs(10);
 
 lazy_static::lazy_static! {
-    static ref WS_CLIENTS: Arc<Semaphore> = Arc::new(Semaphore::new(WS_MAX_CLIENTS));
+    static ref WS_CLIENTS: reload::Limit = reload::Limit::new(|c| c.websocket.max_clients);
 }
 
 pub async fn handle_websocket(
@@ -69,9 +70,9 @@ pub async fn handle_websocket(
     };
 
     // Cap concurrent clients. Refuse rather than queue.
-    let permit = match Arc::clone(&WS_CLIENTS).try_acquire_owned() {
-        Ok(p) => p,
-        Err(_) => {
+    let permit = match WS_CLIENTS.try_acquire() {
+        Some(p) => p,
+        None => {
             return Response::builder()
                 .status(StatusCode::SERVICE_UNAVAILABLE)
                 .header(header::RETRY_AFTER, "30")
@@ -79,8 +80,10 @@ pub async fn handle_websocket(
         }
     };
 
+    let connection = shutdown::track();
     tokio::spawn(async move {
         let _permit = permit;
+        let _connection = connection;
         match hyper::upgrade::on(req).await {
             Ok(upgraded) => {
                 if let Err(e) = websocket_loop(upgraded, metrics).await {
@@ -104,6 +107,8 @@ async fn websocket_loop(
     metrics: Arc<Metrics>,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     metrics.increment_ws_clients();
+    let config::WebSocket { ping_interval, pong_deadline, send_timeout, close_timeout, .. } =
+        config::current().websocket;
 
     let config = WebSocketConfig {
         max_message_size: Some(WS_MAX_MESSAGE_SIZE),
@@ -119,7 +124,7 @@ async fn websocket_loop(
 
     let (mut tx, mut rx) = ws_stream.split();
     let mut broadcast = tokio::time::interval(Duration::from_secs(1));
-    let mut ping = tokio::time::interval(WS_PING_INTERVAL);
+    let mut ping = tokio::time::interval(ping_interval);
     ping.tick().await; // skip the immediate first tick
     let mut last_pong = Instant::now();
 
@@ -132,7 +137,7 @@ async fn websocket_loop(
 
                     // If the client stops reading, the socket buffer fills,
                     // send() blocks — bail out instead of growing memory forever.
-                    match timeout(WS_SEND_TIMEOUT, tx.send(Message::Text(json))).await {
+                    match timeout(send_timeout, tx.send(Message::Text(json))).await {
                         Ok(Ok(())) => {}
                         Ok(Err(e)) => {
                             eprintln!("Failed to send metrics: {}", e);
@@ -146,19 +151,41 @@ async fn websocket_loop(
                 }
 
                 _ = ping.tick() => {
-                    if last_pong.elapsed() > WS_PONG_DEADLINE {
+                    if last_pong.elapsed() > pong_deadline {
                         break;
                     }
-                    match timeout(WS_SEND_TIMEOUT, tx.send(Message::Ping(Vec::new()))).await {
+                    match timeout(send_timeout, tx.send(Message::Ping(Vec::new()))).await {
                         Ok(Ok(())) => {}
                         Ok(Err(_)) | Err(_) => break,
                     }
                 }
 
+                _ = shutdown::triggered() => {
+                    // 1001 tells the dashboard to reconnect rather than
+                    // treat it as an error.
+                    let close = Message::Close(Some(CloseFrame {
+                        code: CloseCode::Away,
+                        reason: "server shutting down".into(),
+                    }));
+                    if let Ok(Ok(())) = timeout(send_timeout, tx.send(close)).await {
+                        // Wait for the client's Close to end the handshake.
+                        let _ = timeout(close_timeout, async {
+                            while let Some(Ok(msg)) = rx.next().await {
+                                if msg.is_close() {
+                                    break;
+                                }
+                            }
+                        })
+                        .await;
+                    }
+                    break;
+                }
+
                 msg = rx.next() => {
                     match msg {
                         Some(Ok(Message::Ping(data))) => {
-                            if tx.send(Message::Pong(data)).await.is_err() {
+                            let sent = tx.send(Message::Pong(data)).await;
+                            if sent.is_err() {
                                 break;
                             }
                         }
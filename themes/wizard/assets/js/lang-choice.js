@@ -0,0 +1,12 @@
+// Remember an explicit language choice.
+//
+// The server sends first-time visitors on / to their Accept-Language match;
+// once someone picks a language in the switcher, this cookie wins instead,
+// so they are never bounced back to the other language.
+
+document.addEventListener('click', function(event) {
+    var link = event.target.closest && event.target.closest('.nav-lang-link');
+    if (!link) return;
+    document.cookie = 'lang=' + link.getAttribute('hreflang') +
+        '; path=/; max-age=31536000; samesite=lax';
+});
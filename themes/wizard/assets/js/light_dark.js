@@ -3,7 +3,8 @@
 // No explicit choice: leave data-theme off so CSS media queries drive the scheme,
 // which means OS changes take effect live.
 // Explicit choice (via toggle): data-theme is set and persisted to sessionStorage,
-// overriding the OS preference for the current tab only.
+// overriding the OS preference for the current tab only. The choice is also sent
+// as a session cookie, so the server can render pages with just that theme's hero.
 
 var darkQuery = window.matchMedia('(prefers-color-scheme: dark)');
 
@@ -24,10 +25,26 @@ function toggleTheme() {
     var newTheme = effectiveTheme() === 'dark' ? 'light' : 'dark';
     document.documentElement.setAttribute('data-theme', newTheme);
     sessionStorage.setItem('theme', newTheme);
+    // The server reads this to send pages with only this theme's hero.
+    document.cookie = 'theme=' + newTheme + '; path=/; samesite=lax';
+    loadDeferredImages();
     updateThemeIcons(newTheme);
     document.dispatchEvent(new CustomEvent('themechange', { detail: { theme: newTheme } }));
 }
 
+// Pages served for one theme keep the other theme's hero in data-src so it
+// isn't downloaded; fetch it once the visitor actually switches.
+function loadDeferredImages() {
+    var images = document.querySelectorAll('img[data-src]');
+    for (var i = 0; i < images.length; i++) {
+        var img = images[i];
+        if (img.dataset.srcset) img.srcset = img.dataset.srcset;
+        img.src = img.dataset.src;
+        img.removeAttribute('data-src');
+        img.removeAttribute('data-srcset');
+    }
+}
+
 function updateThemeIcons(theme) {
     var moon = document.querySelector('.moon');
     var sun = document.querySelector('.sun');